@@ -0,0 +1,290 @@
+//! Persistent page allocator for a backing file.
+//!
+//! A bitmap with one bit per page of the file is stored in the pages right after the
+//! [`Superblock`], a set bit meaning the page is in use. The bitmap is kept in memory and the
+//! changed bitmap pages are written back by [`PageAllocator::sync`].
+//!
+//! ```text
+//! +------------+----------------------+--------------------
+//! | superblock | bitmap pages 1..=n   | data pages ...
+//! +------------+----------------------+--------------------
+//! ```
+
+use std::{
+    collections::BTreeSet,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use rustix::{
+    fd::{AsFd, BorrowedFd},
+    fs, io,
+};
+
+use crate::{fileio, superblock::Superblock};
+
+struct State {
+    bitmap: Vec<u8>,
+    /// Bitmap pages changed since the last sync, as page ids of the file
+    dirty: BTreeSet<u64>,
+    /// Where the next search for a free page starts
+    hint: u64,
+    free: u64,
+}
+
+impl State {
+    fn is_set(&self, pid: u64) -> bool {
+        self.bitmap[(pid / 8) as usize] & (1 << (pid % 8)) != 0
+    }
+
+    fn set(&mut self, pid: u64, used: bool, page_bits: u64) {
+        let byte = &mut self.bitmap[(pid / 8) as usize];
+        if used {
+            *byte |= 1 << (pid % 8);
+        } else {
+            *byte &= !(1 << (pid % 8));
+        }
+        self.dirty.insert(1 + pid / page_bits);
+    }
+}
+
+pub struct PageAllocator<'f, const P: usize> {
+    fd: BorrowedFd<'f>,
+    page_count: u64,
+    state: Mutex<State>,
+}
+
+impl<'f, const P: usize> PageAllocator<'f, P> {
+    const PAGE_BITS: u64 = P as u64 * 8;
+
+    /// Number of bitmap pages needed for a file of `page_count` pages
+    pub fn bitmap_pages(page_count: u64) -> u64 {
+        (page_count + Self::PAGE_BITS - 1) / Self::PAGE_BITS
+    }
+
+    /// First page id that can be handed out
+    pub fn first_data_page(&self) -> u64 {
+        1 + Self::bitmap_pages(self.page_count)
+    }
+
+    /// Write an empty bitmap for a file of `page_count` pages. The superblock and bitmap pages
+    /// are marked as in use.
+    pub fn format(fd: BorrowedFd<'f>, page_count: u64) -> io::Result<Self> {
+        let reserved = 1 + Self::bitmap_pages(page_count);
+        if reserved >= page_count {
+            return Err(io::Errno::NOSPC);
+        }
+
+        let mut state = State {
+            bitmap: vec![0; (Self::bitmap_pages(page_count) * P as u64) as usize],
+            dirty: (1..reserved).collect(),
+            hint: reserved,
+            free: page_count - reserved,
+        };
+        for pid in 0..reserved {
+            state.set(pid, true, Self::PAGE_BITS);
+        }
+
+        let allocator = Self {
+            fd,
+            page_count,
+            state: Mutex::new(state),
+        };
+        allocator.sync()?;
+        Ok(allocator)
+    }
+
+    /// Load the bitmap of a file of `page_count` pages, usually [`Superblock::page_count`]
+    pub fn open(fd: BorrowedFd<'f>, page_count: u64) -> io::Result<Self> {
+        let mut bitmap = vec![0; (Self::bitmap_pages(page_count) * P as u64) as usize];
+        fileio::pread_exact(fd, &mut bitmap, P as u64)?;
+
+        let used: u64 = bitmap.iter().map(|b| u64::from(b.count_ones())).sum();
+        let reserved = 1 + Self::bitmap_pages(page_count);
+        let state = State {
+            bitmap,
+            dirty: BTreeSet::new(),
+            hint: reserved,
+            free: page_count.saturating_sub(used),
+        };
+
+        if (0..reserved).any(|pid| !state.is_set(pid)) {
+            return Err(io::Errno::INVAL);
+        }
+
+        Ok(Self {
+            fd,
+            page_count,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Open the allocator of a file with a superblock
+    pub fn from_superblock(fd: BorrowedFd<'f>, sb: &Superblock) -> io::Result<Self> {
+        if sb.page_size as usize != P {
+            return Err(io::Errno::INVAL);
+        }
+        Self::open(fd, sb.page_count)
+    }
+
+    pub fn page_count(&self) -> u64 {
+        self.page_count
+    }
+
+    pub fn free_pages(&self) -> u64 {
+        self.lock().free
+    }
+
+    pub fn is_allocated(&self, pid: u64) -> bool {
+        pid < self.page_count && self.lock().is_set(pid)
+    }
+
+    pub fn allocate(&self) -> io::Result<u64> {
+        self.allocate_run(1)
+    }
+
+    /// Allocate `len` contiguous pages, returning the first
+    pub fn allocate_run(&self, len: u64) -> io::Result<u64> {
+        if len == 0 {
+            return Err(io::Errno::INVAL);
+        }
+
+        let mut state = self.lock();
+        if state.free < len {
+            return Err(io::Errno::NOSPC);
+        }
+
+        // First fit, starting at the hint and wrapping around once
+        let first = self.first_data_page();
+        let hint = state.hint.clamp(first, self.page_count);
+        let start = self
+            .find_run(&state, hint, self.page_count, len)
+            .or_else(|| self.find_run(&state, first, hint + len - 1, len))
+            .ok_or(io::Errno::NOSPC)?;
+
+        for pid in start..start + len {
+            state.set(pid, true, Self::PAGE_BITS);
+        }
+        state.free -= len;
+        state.hint = start + len;
+        Ok(start)
+    }
+
+    fn find_run(&self, state: &State, from: u64, to: u64, len: u64) -> Option<u64> {
+        let to = to.min(self.page_count);
+        let mut run_start = from;
+        let mut pid = from;
+        while pid < to {
+            if state.is_set(pid) {
+                run_start = pid + 1;
+            } else if pid + 1 - run_start == len {
+                return Some(run_start);
+            }
+            pid += 1;
+        }
+        None
+    }
+
+    pub fn free(&self, pid: u64) -> io::Result<()> {
+        self.free_run(pid, 1)
+    }
+
+    /// Free `len` pages starting at `pid`. Fails with `EINVAL` if any of them is reserved or
+    /// not allocated.
+    pub fn free_run(&self, pid: u64, len: u64) -> io::Result<()> {
+        let end = pid.checked_add(len).ok_or(io::Errno::INVAL)?;
+        if pid < self.first_data_page() || end > self.page_count {
+            return Err(io::Errno::INVAL);
+        }
+
+        let mut state = self.lock();
+        if (pid..end).any(|p| !state.is_set(p)) {
+            return Err(io::Errno::INVAL);
+        }
+
+        for p in pid..end {
+            state.set(p, false, Self::PAGE_BITS);
+        }
+        state.free += len;
+        state.hint = state.hint.min(pid);
+        Ok(())
+    }
+
+    /// Write the changed bitmap pages and sync them
+    pub fn sync(&self) -> io::Result<()> {
+        let mut state = self.lock();
+        if state.dirty.is_empty() {
+            return Ok(());
+        }
+
+        for &bitmap_pid in &state.dirty {
+            let start = ((bitmap_pid - 1) * P as u64) as usize;
+            fileio::pwrite_all(
+                self.fd,
+                &state.bitmap[start..start + P],
+                bitmap_pid * P as u64,
+            )?;
+        }
+        fs::fdatasync(self.fd.as_fd())?;
+        state.dirty.clear();
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempFile;
+
+    #[test]
+    fn survives_reopen() {
+        let path = TempFile::new("alloc-reopen");
+        let fd = path.create();
+        let fd = fd.as_fd();
+
+        // 512 bits per page so 1000 pages need two bitmap pages
+        let allocator = PageAllocator::<64>::format(fd, 1000).unwrap();
+        assert_eq!(allocator.first_data_page(), 3);
+        assert_eq!(allocator.free_pages(), 997);
+
+        let a = allocator.allocate().unwrap();
+        let b = allocator.allocate().unwrap();
+        let run = allocator.allocate_run(600).unwrap();
+        assert_eq!((a, b, run), (3, 4, 5));
+        allocator.free(a).unwrap();
+        assert_eq!(allocator.free(a), Err(io::Errno::INVAL));
+        assert_eq!(allocator.free(0), Err(io::Errno::INVAL));
+        allocator.sync().unwrap();
+
+        let reopened = PageAllocator::<64>::open(fd, 1000).unwrap();
+        assert!(!reopened.is_allocated(a));
+        assert!(reopened.is_allocated(b));
+        assert!(reopened.is_allocated(run + 599));
+        assert_eq!(reopened.free_pages(), 997 - 601);
+        // The freed page is reused
+        assert_eq!(reopened.allocate().unwrap(), a);
+    }
+
+    #[test]
+    fn contiguous_runs() {
+        let path = TempFile::new("alloc-runs");
+        let fd = path.create();
+        let fd = fd.as_fd();
+
+        let allocator = PageAllocator::<64>::format(fd, 20).unwrap();
+        let first = allocator.first_data_page();
+        let pages: Vec<u64> = (0..18).map(|_| allocator.allocate().unwrap()).collect();
+        assert_eq!(allocator.allocate(), Err(io::Errno::NOSPC));
+
+        // Free a hole of three and a hole of two
+        allocator.free_run(first + 2, 3).unwrap();
+        allocator.free_run(first + 10, 2).unwrap();
+        assert_eq!(allocator.allocate_run(4), Err(io::Errno::NOSPC));
+        assert_eq!(allocator.allocate_run(3).unwrap(), first + 2);
+        assert_eq!(allocator.allocate_run(2).unwrap(), first + 10);
+        assert_eq!(pages.len(), 18);
+    }
+}
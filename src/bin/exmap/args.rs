@@ -0,0 +1,242 @@
+//! Command line flags. Every flag takes a value, given as `--name value` or `--name=value`.
+
+use std::{collections::HashMap, error::Error, fmt, path::PathBuf, str::FromStr};
+
+use exmap::config::{parse_size, ExmapConfig};
+
+pub type CliResult<T> = Result<T, Box<dyn Error>>;
+
+#[derive(Debug)]
+pub struct UsageError(pub String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for UsageError {}
+
+pub fn usage(msg: impl Into<String>) -> Box<dyn Error> {
+    Box::new(UsageError(msg.into()))
+}
+
+pub struct Flags {
+    values: HashMap<String, String>,
+    positional: Vec<String>,
+}
+
+impl Flags {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> CliResult<Self> {
+        let mut values = HashMap::new();
+        let mut positional = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                positional.push(arg);
+                continue;
+            };
+
+            let (name, value) = match name.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| usage(format!("--{name} needs a value")))?;
+                    (name.to_string(), value)
+                }
+            };
+            if values.insert(name.clone(), value).is_some() {
+                return Err(usage(format!("--{name} given twice")));
+            }
+        }
+
+        Ok(Self { values, positional })
+    }
+
+    pub fn take_opt<T>(&mut self, name: &str) -> CliResult<Option<T>>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.values
+            .remove(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|e| usage(format!("--{name} {value}: {e}")))
+            })
+            .transpose()
+    }
+
+    pub fn take<T>(&mut self, name: &str, default: T) -> CliResult<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        Ok(self.take_opt(name)?.unwrap_or(default))
+    }
+
+    /// The positional arguments, failing on flags nobody took
+    pub fn finish(self) -> CliResult<Vec<String>> {
+        let mut unknown: Vec<_> = self.values.into_keys().collect();
+        unknown.sort();
+        match unknown.first() {
+            Some(name) => Err(usage(format!("unknown flag --{name}"))),
+            None => Ok(self.positional),
+        }
+    }
+}
+
+/// A byte count with an optional binary `K`, `M`, `G` or `T` suffix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size(pub u64);
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_size(s).map(Size)
+    }
+}
+
+/// Pages `page..page + len`, written `PAGE` or `PAGE:LEN`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageRange {
+    pub page: u64,
+    pub len: u64,
+}
+
+impl FromStr for PageRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (page, len) = s.split_once(':').unwrap_or((s, "1"));
+        let page = page.parse().map_err(|e| format!("{s}: {e}"))?;
+        let len = len.parse().map_err(|e| format!("{s}: {e}"))?;
+        if len == 0 {
+            return Err(format!("{s}: empty range"));
+        }
+        Ok(Self { page, len })
+    }
+}
+
+/// Fails with a usage error naming the first range that does not fit into a VMA of `pages`
+pub fn check_ranges(ranges: &[PageRange], pages: u64) -> CliResult<()> {
+    let outside = |r: &&PageRange| r.page.checked_add(r.len).map_or(true, |end| end > pages);
+    match ranges.iter().find(outside) {
+        Some(r) => Err(usage(format!(
+            "{}:{} is outside the {pages} page VMA",
+            r.page, r.len
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// How to set up the exmap, shared by every subcommand. Flags override `--config` and the
+/// environment, see [`ExmapConfig`].
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Size of the exmap VMA in bytes
+    pub vma_size: usize,
+    pub interfaces: u16,
+    /// Physical memory of the exmap in pages
+    pub buffer_size: usize,
+    pub backing: Option<PathBuf>,
+    /// Record every exmap action to this file
+    pub trace: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn take(flags: &mut Flags, page_size: usize) -> CliResult<Self> {
+        let path: Option<PathBuf> = flags.take_opt("config")?;
+        let mut exmap = match path {
+            Some(path) => ExmapConfig::from_file(path)?,
+            None => ExmapConfig::default(),
+        };
+        exmap.apply_env()?;
+
+        if let Some(Size(vma_size)) = flags.take_opt("vma-size")? {
+            exmap.vma_size = vma_size;
+        }
+        if let Some(interfaces) = flags.take_opt("interfaces")? {
+            exmap.interfaces = interfaces;
+        }
+        if let Some(Size(buffer_size)) = flags.take_opt("buffer-size")? {
+            exmap.buffer_size = buffer_size;
+        }
+        if let Some(backing) = flags.take_opt("backing")? {
+            exmap.backing = Some(backing);
+        }
+        if let Some(trace) = flags.take_opt("trace")? {
+            exmap.trace = Some(trace);
+        }
+        exmap
+            .validate(page_size)
+            .map_err(|e| usage(e.to_string()))?;
+
+        Ok(Self {
+            vma_size: exmap.vma_size as usize,
+            interfaces: exmap.interfaces,
+            buffer_size: exmap.buffer_pages(page_size),
+            backing: exmap.backing,
+            trace: exmap.trace,
+        })
+    }
+
+    pub fn pages(&self, page_size: usize) -> u64 {
+        (self.vma_size / page_size) as u64
+    }
+
+    pub fn exmap(&self, page_size: usize) -> ExmapConfig {
+        ExmapConfig {
+            vma_size: self.vma_size as u64,
+            interfaces: self.interfaces,
+            buffer_size: (self.buffer_size * page_size) as u64,
+            backing: self.backing.clone(),
+            trace: self.trace.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(args: &[&str]) -> Flags {
+        Flags::parse(args.iter().map(|s| s.to_string())).unwrap()
+    }
+
+    #[test]
+    fn parse_flags() {
+        let mut f = flags(&["--vma-size", "1G", "10:4", "--interfaces=2", "7"]);
+        let config = Config::take(&mut f, 4096).unwrap();
+        assert_eq!(config.vma_size, 1 << 30);
+        assert_eq!(config.interfaces, 2);
+        assert_eq!(config.buffer_size, 2048);
+        assert_eq!(config.backing, None);
+        assert_eq!(config.trace, None);
+        assert_eq!(f.finish().unwrap(), ["10:4", "7"]);
+
+        let mut f = flags(&["--bogus", "1"]);
+        assert!(f.take::<u64>("seed", 0).is_ok());
+        assert!(f.finish().is_err());
+        assert!(Flags::parse(["--seed".to_string()]).is_err());
+    }
+
+    #[test]
+    fn parse_values() {
+        assert_eq!("4k".parse(), Ok(Size(4096)));
+        assert_eq!("12".parse(), Ok(Size(12)));
+        assert!("1Q".parse::<Size>().is_err());
+        assert_eq!("10:4".parse(), Ok(PageRange { page: 10, len: 4 }));
+        assert_eq!("10".parse(), Ok(PageRange { page: 10, len: 1 }));
+        assert!("10:0".parse::<PageRange>().is_err());
+
+        let range = |page, len| PageRange { page, len };
+        assert!(check_ranges(&[range(0, 4), range(6, 4)], 10).is_ok());
+        assert!(check_ranges(&[range(0, 4), range(8, 4)], 10).is_err());
+        assert!(check_ranges(&[range(u64::MAX, 2)], 10).is_err());
+    }
+}
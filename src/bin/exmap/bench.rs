@@ -0,0 +1,415 @@
+//! `bench`: how fast exmap allocates and frees pages, or how the buffer manager does on random
+//! reads from a backing file larger than its memory, compared to a pread buffer pool.
+
+use std::{
+    hint::black_box,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use exmap::{
+    bm::{BufferManager, BufferPool},
+    pool::PreadPool,
+    workload::{Rng, Zipf},
+    ExmapConfig,
+};
+use rustix::{
+    fd::AsFd,
+    fs::{self, Mode, OFlags},
+};
+use serde::Serialize;
+
+use crate::{
+    args::{usage, CliResult, Config, Flags, Size},
+    hist::Histogram,
+    open_backing,
+    ops::{submit, Op},
+    output::{self, Format, Latency},
+    with_exmap, PAGE_SIZE,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Workload {
+    /// Alloc and free cycles through one interface
+    Churn,
+    Uniform,
+    Zipf,
+}
+
+impl FromStr for Workload {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "churn" => Ok(Self::Churn),
+            "uniform" => Ok(Self::Uniform),
+            "zipf" => Ok(Self::Zipf),
+            _ => Err("expected churn, uniform or zipf".to_string()),
+        }
+    }
+}
+
+impl Workload {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Churn => "churn",
+            Self::Uniform => "uniform",
+            Self::Zipf => "zipf",
+        }
+    }
+}
+
+pub fn run(config: Config, mut flags: Flags, format: Format) -> CliResult<()> {
+    match flags.take("workload", Workload::Churn)? {
+        Workload::Churn => churn(config, flags, format),
+        workload => reads(config, flags, workload, format),
+    }
+}
+
+#[derive(Serialize)]
+struct ChurnRecord {
+    command: &'static str,
+    workload: &'static str,
+    config: ExmapConfig,
+    iterations: u64,
+    batch: u64,
+    failed: usize,
+    alloc: ChurnTiming,
+    free: ChurnTiming,
+}
+
+#[derive(Serialize)]
+struct ChurnTiming {
+    elapsed_ns: u64,
+    pages_per_sec: f64,
+    us_per_cycle: f64,
+}
+
+fn churn(config: Config, mut flags: Flags, format: Format) -> CliResult<()> {
+    let iterations: u64 = flags.take("iterations", 1000)?;
+    let batch: u64 = flags.take("batch", 512)?;
+    if !flags.finish()?.is_empty() {
+        return Err(usage("bench takes no arguments"));
+    }
+    if batch == 0 || batch > config.buffer_size as u64 || batch > config.pages(PAGE_SIZE) {
+        return Err(usage("--batch must fit into --buffer-size and --vma-size"));
+    }
+
+    let iovs: Vec<_> = (0..batch).map(|page| (page, 1)).collect();
+    let (alloc, free, failed) = with_exmap(&config, |exmap_fd, mem| {
+        let mut interface = unsafe { exmap_fd.mmap_interface(0)? };
+        let (mut alloc, mut free, mut failed) = (Duration::ZERO, Duration::ZERO, 0);
+        for _ in 0..iterations {
+            for (op, time) in [(Op::Alloc, &mut alloc), (Op::Free, &mut free)] {
+                let start = Instant::now();
+                let (done, results) = submit(interface, op, &iovs)?;
+                *time += start.elapsed();
+                interface = done;
+                failed += results.iter().filter(|&&(res, _)| res != 0).count();
+            }
+        }
+        mem.unmap()?;
+        interface.unmap()?;
+        Ok((alloc, free, failed))
+    })?;
+
+    let pages = (iterations * batch) as f64;
+    let timing = |time: Duration| ChurnTiming {
+        elapsed_ns: output::nanos(time),
+        pages_per_sec: pages / time.as_secs_f64(),
+        us_per_cycle: time.as_secs_f64() * 1e6 / iterations as f64,
+    };
+    match format {
+        Format::Text => {
+            println!("{iterations} cycles of {batch} single page iovs, {failed} iovs failed");
+            for (op, time) in [("alloc", alloc), ("free", free)] {
+                let t = timing(time);
+                println!(
+                    "{op:5} {:>12.0} pages/s {:>10.2} us/cycle",
+                    t.pages_per_sec, t.us_per_cycle
+                );
+            }
+        }
+        Format::Json => output::json(&ChurnRecord {
+            command: "bench",
+            workload: Workload::Churn.name(),
+            config: config.exmap(PAGE_SIZE),
+            iterations,
+            batch,
+            failed,
+            alloc: timing(alloc),
+            free: timing(free),
+        }),
+    }
+    if failed > 0 {
+        return Err(format!("{failed} iovs failed").into());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+enum Dist {
+    Uniform(u64),
+    Zipf(Zipf),
+}
+
+impl Dist {
+    fn sample(&self, rng: &mut Rng) -> u64 {
+        match self {
+            Self::Uniform(n) => rng.below(*n),
+            Self::Zipf(zipf) => zipf.sample(rng),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pool {
+    Exmap,
+    Pread,
+    Both,
+}
+
+impl FromStr for Pool {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exmap" => Ok(Self::Exmap),
+            "pread" => Ok(Self::Pread),
+            "both" => Ok(Self::Both),
+            _ => Err("expected exmap, pread or both".to_string()),
+        }
+    }
+}
+
+struct ReadRun {
+    hist: Histogram,
+    misses: u64,
+    elapsed: Duration,
+}
+
+/// Random page reads from the backing file through the exmap buffer manager, with one
+/// interface per thread, or through the pread baseline
+#[derive(Serialize)]
+struct ReadsRecord {
+    command: &'static str,
+    workload: &'static str,
+    config: ExmapConfig,
+    pages: u64,
+    threads: u16,
+    seconds: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    theta: Option<f64>,
+    seed: u64,
+    runs: Vec<PoolRun>,
+    /// exmap throughput over pread, when both ran
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relative: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct PoolRun {
+    pool: &'static str,
+    reads: u64,
+    elapsed_ns: u64,
+    reads_per_sec: f64,
+    miss_ratio: f64,
+    latency: Latency,
+}
+
+fn reads(config: Config, mut flags: Flags, workload: Workload, format: Format) -> CliResult<()> {
+    let pool: Pool = flags.take("pool", Pool::Exmap)?;
+    let threads: u16 = flags.take("threads", config.interfaces)?;
+    let seconds: u64 = flags.take("seconds", 10)?;
+    let theta: f64 = flags.take("theta", 0.99)?;
+    let file_size: Option<Size> = flags.take_opt("file-size")?;
+    let seed = flags.take("seed", Rng::clock_seed())?;
+    if !flags.finish()?.is_empty() {
+        return Err(usage("bench takes no arguments"));
+    }
+    if threads == 0 || threads > config.interfaces {
+        return Err(usage("--threads must be between 1 and --interfaces"));
+    }
+    if !(theta > 0.0 && theta < 1.0) {
+        return Err(usage("--theta must be between 0 and 1, exclusive"));
+    }
+    let Some(path) = &config.backing else {
+        return Err(usage("reads need --backing"));
+    };
+
+    if let Some(Size(size)) = file_size {
+        fill(path, size)?;
+    }
+    let file_pages = std::fs::metadata(path)?.len() / PAGE_SIZE as u64;
+    let pages = file_pages.min(config.pages(PAGE_SIZE));
+    if pages == 0 {
+        return Err(usage(
+            "the backing file is empty, pass --file-size to fill it",
+        ));
+    }
+    if pages <= config.buffer_size as u64 {
+        eprintln!("warning: {pages} pages fit into the buffer, nothing will be evicted");
+    }
+    let dist = match workload {
+        Workload::Zipf => Dist::Zipf(Zipf::new(pages, theta)),
+        _ => Dist::Uniform(pages),
+    };
+
+    let mut runs = Vec::new();
+    if pool != Pool::Pread {
+        let run = with_exmap(&config, |exmap_fd, mem| {
+            let bm = unsafe {
+                BufferManager::new(exmap_fd, mem, config.interfaces, config.buffer_size)?
+            };
+            let run = read_pages(&bm, threads, seconds, &dist, seed)?;
+            bm.close()?;
+            Ok(run)
+        })?;
+        runs.push(("exmap", run));
+    }
+    if pool != Pool::Exmap {
+        let fd = open_backing(&config)?.expect("checked above");
+        let pool = PreadPool::<PAGE_SIZE>::new(fd.as_fd(), config.buffer_size);
+        let run = read_pages(&pool, threads, seconds, &dist, seed)?;
+        runs.push(("pread", run));
+    }
+
+    let runs: Vec<_> = runs
+        .into_iter()
+        .map(|(pool, run)| {
+            let reads = run.hist.count();
+            PoolRun {
+                pool,
+                reads,
+                elapsed_ns: output::nanos(run.elapsed),
+                reads_per_sec: reads as f64 / run.elapsed.as_secs_f64(),
+                miss_ratio: run.misses as f64 / reads.max(1) as f64,
+                latency: Latency::from(&run.hist),
+            }
+        })
+        .collect();
+    let relative = match &runs[..] {
+        [exmap, pread] => Some(exmap.reads_per_sec / pread.reads_per_sec),
+        _ => None,
+    };
+
+    if format == Format::Json {
+        output::json(&ReadsRecord {
+            command: "bench",
+            workload: workload.name(),
+            config: config.exmap(PAGE_SIZE),
+            pages,
+            threads,
+            seconds,
+            theta: (workload == Workload::Zipf).then_some(theta),
+            seed,
+            runs,
+            relative,
+        });
+        return Ok(());
+    }
+
+    println!(
+        "{workload:?} reads of {pages} pages through {} buffer pages, {threads} threads, seed {seed}",
+        config.buffer_size
+    );
+    for run in &runs {
+        let (name, l) = (run.pool, &run.latency);
+        println!(
+            "{name}: {} reads in {:.2}s: {:.0} reads/s, miss ratio {:.4}",
+            run.reads,
+            run.elapsed_ns as f64 / 1e9,
+            run.reads_per_sec,
+            run.miss_ratio
+        );
+        println!(
+            "{name}: latency us: p50 {:.2} p90 {:.2} p99 {:.2} p99.9 {:.2} max {:.2}",
+            l.p50_us, l.p90_us, l.p99_us, l.p999_us, l.max_us
+        );
+    }
+    if let Some(relative) = relative {
+        println!("exmap throughput relative to pread: {relative:.2}x");
+    }
+    Ok(())
+}
+
+/// `threads` threads reading pages picked from `dist` for `seconds`
+fn read_pages<B: BufferPool<PAGE_SIZE>>(
+    pool: &B,
+    threads: u16,
+    seconds: u64,
+    dist: &Dist,
+    seed: u64,
+) -> exmap::Result<ReadRun> {
+    let stop = AtomicBool::new(false);
+    let start = Instant::now();
+    let hists = thread::scope(|s| {
+        let workers: Vec<_> = (0..threads)
+            .map(|t| {
+                let (stop, dist) = (&stop, dist.clone());
+                let mut rng = Rng::new(seed.wrapping_add(t.into()));
+                s.spawn(move || -> exmap::Result<Histogram> {
+                    let mut hist = Histogram::default();
+                    while !stop.load(Ordering::Relaxed) {
+                        let pid = dist.sample(&mut rng);
+                        let start = Instant::now();
+                        let page = pool.fix_s(pid)?;
+                        black_box(page[PAGE_SIZE - 1]);
+                        drop(page);
+                        hist.record(start.elapsed().as_nanos() as u64);
+                    }
+                    Ok(hist)
+                })
+            })
+            .collect();
+
+        thread::sleep(Duration::from_secs(seconds));
+        stop.store(true, Ordering::Relaxed);
+        workers
+            .into_iter()
+            .map(|w| w.join().expect("reader panicked"))
+            .collect::<exmap::Result<Vec<_>>>()
+    })?;
+
+    let mut hist = Histogram::default();
+    hists.iter().for_each(|h| hist.merge(h));
+    Ok(ReadRun {
+        hist,
+        misses: pool.faults(),
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Grow the file at `path` to `size` bytes, stamping each new page with its page id
+fn fill(path: &std::path::Path, size: u64) -> CliResult<()> {
+    const CHUNK_PAGES: u64 = 256;
+
+    let fd = fs::openat(
+        fs::cwd(),
+        path,
+        OFlags::RDWR | OFlags::CREATE | OFlags::CLOEXEC,
+        Mode::from_raw_mode(0o600),
+    )?;
+    let pages = size / PAGE_SIZE as u64;
+    let mut pid = fs::fstat(&fd)?.st_size as u64 / PAGE_SIZE as u64;
+
+    let mut buf = vec![0; CHUNK_PAGES as usize * PAGE_SIZE];
+    while pid < pages {
+        let n = CHUNK_PAGES.min(pages - pid);
+        for (i, page) in buf.chunks_exact_mut(PAGE_SIZE).take(n as usize).enumerate() {
+            page[..8].copy_from_slice(&(pid + i as u64).to_le_bytes());
+        }
+        let mut chunk = &buf[..n as usize * PAGE_SIZE];
+        let mut offset = pid * PAGE_SIZE as u64;
+        while !chunk.is_empty() {
+            let written = rustix::io::pwrite(&fd, chunk, offset)?;
+            chunk = &chunk[written..];
+            offset += written as u64;
+        }
+        pid += n;
+    }
+    fs::fdatasync(&fd)?;
+    Ok(())
+}
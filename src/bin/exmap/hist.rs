@@ -0,0 +1,112 @@
+//! Latency histogram with buckets a sixteenth of a power of two wide, so percentiles are off
+//! by at most about 6%.
+
+const SUB_BITS: u32 = 4;
+const SUB: u64 = 1 << SUB_BITS;
+const BUCKETS: usize = ((64 - SUB_BITS) as usize + 1) * SUB as usize;
+
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; BUCKETS],
+            total: 0,
+            max: 0,
+        }
+    }
+}
+
+fn bucket(v: u64) -> usize {
+    if v < SUB {
+        return v as usize;
+    }
+    let exp = 63 - v.leading_zeros();
+    let sub = (v >> (exp - SUB_BITS)) & (SUB - 1);
+    ((exp - SUB_BITS + 1) as u64 * SUB + sub) as usize
+}
+
+/// Largest value that lands in bucket `b`
+fn upper(b: usize) -> u64 {
+    let b = b as u64;
+    if b < SUB {
+        return b;
+    }
+    let exp = b / SUB + u64::from(SUB_BITS) - 1;
+    let width = 1 << (exp - u64::from(SUB_BITS));
+    ((SUB + b % SUB) << (exp - u64::from(SUB_BITS))) + (width - 1)
+}
+
+impl Histogram {
+    pub fn record(&mut self, v: u64) {
+        self.counts[bucket(v)] += 1;
+        self.total += 1;
+        self.max = self.max.max(v);
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (a, b) in self.counts.iter_mut().zip(&other.counts) {
+            *a += b;
+        }
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Smallest bucket bound at or above a fraction `p` of the values, 0 when empty
+    pub fn percentile(&self, p: f64) -> u64 {
+        let rank = ((p * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (b, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return upper(b).min(self.max);
+            }
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        for v in [0, 15, 16, 17, 31, 32, 33, 1000, 123_456_789, u64::MAX] {
+            let b = bucket(v);
+            assert!(v <= upper(b), "{v}");
+            assert!(b == 0 || v > upper(b - 1), "{v}");
+        }
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut h = Histogram::default();
+        assert_eq!(h.percentile(0.5), 0);
+        for v in 1..=1000 {
+            h.record(v);
+        }
+        let p50 = h.percentile(0.5);
+        assert!((500..=532).contains(&p50), "{p50}");
+        assert_eq!(h.percentile(1.0), 1000);
+
+        let mut other = Histogram::default();
+        other.record(5000);
+        h.merge(&other);
+        assert_eq!((h.count(), h.max()), (1001, 5000));
+    }
+}
@@ -0,0 +1,203 @@
+//! Command line tool to check and exercise exmap on a host.
+
+mod args;
+mod bench;
+mod hist;
+mod ops;
+mod output;
+mod replay;
+mod residency;
+mod sim;
+mod stress;
+mod ycsb;
+
+use std::process::ExitCode;
+
+use exmap::{trace::Tracer, OwnedExmapFd, VirtMem};
+use rustix::{
+    fd::OwnedFd,
+    fs::{self, Mode, OFlags},
+};
+
+use args::{CliResult, Config, Flags, UsageError};
+use output::Format;
+
+pub const PAGE_SIZE: usize = 4096;
+
+const USAGE: &str = "\
+usage: exmap <command> [flags] [args]
+
+commands:
+  probe                 check that /dev/exmap works and speaks our ABI
+  alloc RANGE...        allocate pages, RANGE is PAGE or PAGE:LEN
+  free RANGE...         free pages
+  read RANGE...         allocate pages and read them from --backing
+  bench                 time alloc and free cycles or random page reads
+  stress                random alloc, free and read on many threads, checking results
+                        and page contents against a model
+  replay TRACE          re-issue a --trace recording and report differing results
+  residency RANGE...    map pages, reading them if --backing is given, then show which
+                        pages of the VMA are resident
+  sim TRACE             miss ratios of the eviction policies over a page access trace
+                        of THREAD PAGE lines, no exmap needed; of the flags for every
+                        command only --format applies
+  ycsb                  YCSB workload on a key value store in --backing, as CSV or JSON;
+                        a new file gets --vma-size bytes
+
+flags for every command:
+  --vma-size SIZE       exmap virtual memory, default 16M
+  --interfaces N        exmap interfaces, default 4
+  --buffer-size SIZE    exmap physical memory, default 8M
+  --backing FILE        backing file for reads and write back
+  --trace FILE          record every exmap action and its results to FILE
+  --config FILE         TOML with vma_size, interfaces, buffer_size, backing and trace,
+                        overridden by EXMAP_VMA_SIZE etc. and then by the flags
+  --format F            text, or json for one object per line with the configuration,
+                        per iov requests and results and timings, default text
+
+bench flags:
+  --workload W          churn to alloc and free, uniform or zipf for random reads
+                        from --backing through the buffer manager, default churn
+  --iterations N        churn: alloc and free cycles, default 1000
+  --batch N             churn: single page iovs per cycle, default 512
+  --pool P              reads: exmap, the pread baseline or both, default exmap
+  --threads N           reads: threads, one interface each, default --interfaces
+  --seconds N           reads: how long to run, default 10
+  --theta X             zipf: skew in (0, 1), default 0.99
+  --file-size SIZE      reads: grow --backing to SIZE first
+  --seed N              reads: random seed, default from the clock
+
+stress flags:
+  --ops N               operations per thread, default 10000
+  --threads N           threads, one interface each, default --interfaces
+  --batch N             operations between checks of every mapped page, default 8
+  --seed N              random seed, default from the clock
+
+residency flags:
+  --source S            mincore, or bm to fix the pages through the buffer manager and
+                        show its page table next to mincore, default mincore
+  --view V              runs of resident pages or an ASCII heat strip, default runs
+  --width N             strip: characters, default 64
+
+sim flags:
+  --sizes SIZE,...      buffer sizes, default doubling from 64K until all pages fit
+  --policies P,...      of bm-clock, pool-clock, lru and opt, default all
+
+ycsb flags:
+  --workload W          A to F, default A
+  --distribution D      uniform, zipfian or latest instead of the workload's own
+  --records N           records loaded first, default 100000
+  --operations N        operations after loading, default 100000
+  --threads N           threads, default 1
+  --value-size N        bytes per value, default 100
+  --theta X             zipfian skew in (0, 1), default 0.99
+  --seed N              random seed, default from the clock
+
+SIZE takes a K, M, G or T suffix.";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let Some(command) = args.next() else {
+        eprintln!("{USAGE}");
+        return ExitCode::from(2);
+    };
+
+    let mut format = Format::Text;
+    let res = Flags::parse(args).and_then(|mut flags| {
+        format = flags.take("format", Format::Text)?;
+        run(&command, flags, format)
+    });
+    if let (Err(e), Format::Json) = (&res, format) {
+        output::json(&serde_json::json!({ "command": command, "error": e.to_string() }));
+    }
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) if e.is::<UsageError>() => {
+            eprintln!("exmap {command}: {e}\n\n{USAGE}");
+            ExitCode::from(2)
+        }
+        Err(e) => {
+            eprintln!("exmap {command}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(command: &str, mut flags: Flags, format: Format) -> CliResult<()> {
+    let f = format;
+    match command {
+        "probe" => ops::probe(Config::take(&mut flags, PAGE_SIZE)?, flags, f),
+        "alloc" => ops::run(
+            ops::Op::Alloc,
+            Config::take(&mut flags, PAGE_SIZE)?,
+            flags,
+            f,
+        ),
+        "free" => ops::run(
+            ops::Op::Free,
+            Config::take(&mut flags, PAGE_SIZE)?,
+            flags,
+            f,
+        ),
+        "read" => ops::run(
+            ops::Op::Read,
+            Config::take(&mut flags, PAGE_SIZE)?,
+            flags,
+            f,
+        ),
+        "bench" => bench::run(Config::take(&mut flags, PAGE_SIZE)?, flags, f),
+        "stress" => stress::run(Config::take(&mut flags, PAGE_SIZE)?, flags, f),
+        "ycsb" => ycsb::run(Config::take(&mut flags, PAGE_SIZE)?, flags, f),
+        "replay" => replay::run(Config::take(&mut flags, PAGE_SIZE)?, flags, f),
+        "residency" => residency::run(Config::take(&mut flags, PAGE_SIZE)?, flags, f),
+        "sim" => sim::run(flags, f),
+        "help" | "--help" | "-h" => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        _ => Err(args::usage(format!("unknown command {command}"))),
+    }
+}
+
+/// Set up the exmap `config` describes and run `f` on it
+pub fn with_exmap<T>(
+    config: &Config,
+    f: impl FnOnce(&OwnedExmapFd<PAGE_SIZE>, VirtMem<'_, '_, PAGE_SIZE>) -> CliResult<T>,
+) -> CliResult<T> {
+    config.exmap(PAGE_SIZE).with_exmap(f)
+}
+
+/// Open /dev/exmap, tracing to `--trace` if given
+pub fn open_exmap(config: &Config) -> CliResult<OwnedExmapFd<PAGE_SIZE>> {
+    let exmap_fd = OwnedExmapFd::<PAGE_SIZE>::open()
+        .map_err(|e| format!("open /dev/exmap: {e}, is the module loaded?"))?;
+    let Some(path) = &config.trace else {
+        return Ok(exmap_fd);
+    };
+    let tracer = Tracer::create(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    Ok(exmap_fd.with_tracer(tracer))
+}
+
+/// Flush the trace of `exmap_fd`, if it has one
+pub fn finish_trace(exmap_fd: &OwnedExmapFd<PAGE_SIZE>) -> CliResult<()> {
+    match exmap_fd.tracer() {
+        Some(tracer) => Ok(tracer.finish().map_err(|e| format!("trace: {e}"))?),
+        None => Ok(()),
+    }
+}
+
+/// Open the backing file if one was given
+pub fn open_backing(config: &Config) -> CliResult<Option<OwnedFd>> {
+    let Some(path) = &config.backing else {
+        return Ok(None);
+    };
+    let fd = fs::openat(
+        fs::cwd(),
+        path,
+        OFlags::RDWR | OFlags::CLOEXEC,
+        Mode::empty(),
+    )
+    .map_err(|e| format!("{}: {e}", path.display()))?;
+    Ok(Some(fd))
+}
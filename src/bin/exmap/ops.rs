@@ -0,0 +1,287 @@
+//! `probe` and the single operations `alloc`, `free` and `read`.
+
+use std::time::Instant;
+
+use rustix::fd::AsFd;
+use serde::Serialize;
+
+use exmap::{ExmapConfig, InterfaceIov, InterfaceWrapper};
+
+use crate::{
+    args::{check_ranges, usage, CliResult, Config, Flags, PageRange},
+    finish_trace, open_backing, open_exmap,
+    output::{self, Format, Iov},
+    with_exmap, PAGE_SIZE,
+};
+
+type Interface<'a> = InterfaceWrapper<'a, InterfaceIov>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Alloc,
+    Free,
+    Read,
+}
+
+impl Op {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Alloc => "alloc",
+            Self::Free => "free",
+            Self::Read => "read",
+        }
+    }
+}
+
+/// Split ranges into iovs exmap accepts
+pub fn iovs(ranges: &[PageRange]) -> Vec<(u64, u64)> {
+    let mut iovs = Vec::new();
+    for range in ranges {
+        let end = range.page + range.len;
+        let mut page = range.page;
+        while page < end {
+            let len = (end - page).min(Interface::MAX_PAGES);
+            iovs.push((page, len));
+            page += len;
+        }
+    }
+    iovs
+}
+
+/// Run `op` on `iovs`, at most one interface full at a time. Returns the interface and the
+/// per iov `(res, pages)` results.
+pub fn submit<'a>(
+    mut interface: Interface<'a>,
+    op: Op,
+    iovs: &[(u64, u64)],
+) -> CliResult<(Interface<'a>, Vec<(i32, i16)>)> {
+    let mut results = Vec::with_capacity(iovs.len());
+    for batch in iovs.chunks(Interface::MAX_COUNT) {
+        for &(page, len) in batch {
+            interface.push(page, len).expect("batch fits the interface");
+        }
+        let res = match op {
+            Op::Alloc => interface.alloc(),
+            Op::Free => interface.free(),
+            Op::Read => interface.read(),
+        };
+        let (done, _) = match res {
+            Ok(done) => done,
+            Err((interface, e)) => {
+                interface.unmap()?;
+                return Err(format!("{} ioctl: {e}", op.name()).into());
+            }
+        };
+        results.extend(done.iter().map(|r| (r.res, r.pages)));
+        interface = done.into_iov();
+    }
+    Ok((interface, results))
+}
+
+#[derive(Serialize)]
+struct OpRecord<'a> {
+    command: &'a str,
+    config: ExmapConfig,
+    elapsed_ns: u64,
+    failed: usize,
+    iovs: Vec<Iov>,
+}
+
+pub fn run(op: Op, config: Config, flags: Flags, format: Format) -> CliResult<()> {
+    let ranges = flags
+        .finish()?
+        .iter()
+        .map(|s| s.parse::<PageRange>().map_err(usage))
+        .collect::<CliResult<Vec<_>>>()?;
+    if ranges.is_empty() {
+        return Err(usage("no page ranges given"));
+    }
+    let pages = config.pages(PAGE_SIZE);
+    check_ranges(&ranges, pages)?;
+    if op == Op::Read && config.backing.is_none() {
+        return Err(usage("read needs --backing"));
+    }
+
+    let iovs = iovs(&ranges);
+    let (results, elapsed) = with_exmap(&config, |exmap_fd, mem| {
+        let interface = unsafe { exmap_fd.mmap_interface(0)? };
+        let start = Instant::now();
+        let (interface, results) = submit(interface, op, &iovs)?;
+        let elapsed = start.elapsed();
+        mem.unmap()?;
+        interface.unmap()?;
+        Ok((results, elapsed))
+    })?;
+
+    let failed = results.iter().filter(|&&(res, _)| res != 0).count();
+    match format {
+        Format::Text => {
+            for (&(page, len), &(res, done)) in iovs.iter().zip(&results) {
+                println!("{} {page}:{len} res={res} pages={done}", op.name());
+            }
+            println!("{failed} of {} iovs failed", iovs.len());
+        }
+        Format::Json => output::json(&OpRecord {
+            command: op.name(),
+            config: config.exmap(PAGE_SIZE),
+            elapsed_ns: output::nanos(elapsed),
+            failed,
+            iovs: Iov::zip(&iovs, &results),
+        }),
+    }
+    if failed > 0 {
+        return Err(format!("{failed} iovs failed").into());
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Step {
+    step: String,
+    elapsed_ns: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    iovs: Vec<Iov>,
+}
+
+#[derive(Serialize)]
+struct ProbeRecord {
+    command: &'static str,
+    config: ExmapConfig,
+    page_size: usize,
+    max_count: usize,
+    max_pages: u64,
+    steps: Vec<Step>,
+}
+
+impl ProbeRecord {
+    /// Note a step that worked, timed from `start`
+    fn ok(&mut self, format: Format, step: String, start: Instant, iovs: Vec<Iov>) {
+        if format == Format::Text {
+            println!("ok   {step}");
+        }
+        self.steps.push(Step {
+            step,
+            elapsed_ns: output::nanos(start.elapsed()),
+            iovs,
+        });
+    }
+}
+
+/// Open the device, set up every interface and run an alloc, write and free through it
+pub fn probe(config: Config, flags: Flags, format: Format) -> CliResult<()> {
+    if !flags.finish()?.is_empty() {
+        return Err(usage("probe takes no arguments"));
+    }
+
+    let mut record = ProbeRecord {
+        command: "probe",
+        config: config.exmap(PAGE_SIZE),
+        page_size: PAGE_SIZE,
+        max_count: Interface::MAX_COUNT,
+        max_pages: Interface::MAX_PAGES,
+        steps: Vec::new(),
+    };
+    if format == Format::Text {
+        println!(
+            "page size {PAGE_SIZE}, {} iovs per interface, {} pages per iov",
+            Interface::MAX_COUNT,
+            Interface::MAX_PAGES
+        );
+    }
+    let res = probe_steps(&config, format, &mut record);
+    if format == Format::Json {
+        // Also when a step failed, to show how far it got
+        output::json(&record);
+    }
+    res
+}
+
+fn probe_steps(config: &Config, format: Format, record: &mut ProbeRecord) -> CliResult<()> {
+    let start = Instant::now();
+    let exmap_fd = open_exmap(config)?;
+    record.ok(format, "open /dev/exmap".to_string(), start, Vec::new());
+
+    let start = Instant::now();
+    let backing = open_backing(config)?;
+    let mut mem = exmap_fd
+        .create(
+            config.vma_size,
+            config.interfaces,
+            config.buffer_size,
+            backing.as_ref().map(|fd| fd.as_fd()),
+        )
+        .map_err(|e| format!("setup: {e}"))?;
+    let step = format!(
+        "setup {} pages of VMA, {} interfaces, {} pages of memory",
+        mem.page_count(),
+        config.interfaces,
+        config.buffer_size
+    );
+    record.ok(format, step, start, Vec::new());
+
+    let start = Instant::now();
+    let mut interfaces = Vec::new();
+    for i in 0..config.interfaces {
+        let interface = unsafe { exmap_fd.mmap_interface(i) }
+            .map_err(|e| format!("mmap interface {i}: {e}"))?;
+        interfaces.push(interface);
+    }
+    let step = format!("mmap {} interfaces", interfaces.len());
+    record.ok(format, step, start, Vec::new());
+
+    let start = Instant::now();
+    let interface = interfaces.swap_remove(0);
+    let (interface, results) = submit(interface, Op::Alloc, &[(0, 1)])?;
+    if results.iter().any(|&(res, _)| res != 0) {
+        return Err(format!("alloc of page 0 returned {results:?}").into());
+    }
+    let iovs = Iov::zip(&[(0, 1)], &results);
+    record.ok(format, "alloc page 0".to_string(), start, iovs);
+
+    let start = Instant::now();
+    let page = mem.page_mut(0);
+    page.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+    if page.iter().enumerate().any(|(i, &b)| b != i as u8) {
+        return Err("page 0 did not keep what was written".into());
+    }
+    record.ok(format, "write page 0".to_string(), start, Vec::new());
+
+    let start = Instant::now();
+    let (interface, results) = submit(interface, Op::Free, &[(0, 1)])?;
+    if results.iter().any(|&(res, _)| res != 0) {
+        return Err(format!("free of page 0 returned {results:?}").into());
+    }
+    let iovs = Iov::zip(&[(0, 1)], &results);
+    record.ok(format, "free page 0".to_string(), start, iovs);
+
+    mem.unmap()?;
+    interface.unmap()?;
+    for interface in interfaces {
+        interface.unmap()?;
+    }
+    finish_trace(&exmap_fd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_iovs() {
+        let ranges = [
+            PageRange { page: 3, len: 2 },
+            PageRange {
+                page: 100,
+                len: Interface::MAX_PAGES + 1,
+            },
+        ];
+        assert_eq!(
+            iovs(&ranges),
+            [
+                (3, 2),
+                (100, Interface::MAX_PAGES),
+                (100 + Interface::MAX_PAGES, 1)
+            ]
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! `--format`: text for people, or one JSON object per line for scripts.
+
+use std::{str::FromStr, time::Duration};
+
+use serde::Serialize;
+
+use crate::hist::Histogram;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err("expected text or json".to_string()),
+        }
+    }
+}
+
+/// Print `record` as one line of JSON
+pub fn json(record: &impl Serialize) {
+    println!(
+        "{}",
+        serde_json::to_string(record).expect("records serialize")
+    );
+}
+
+/// The request and result of one iov
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Iov {
+    pub page: u64,
+    pub len: u64,
+    pub res: i32,
+    pub pages: i16,
+}
+
+impl Iov {
+    pub fn zip(iovs: &[(u64, u64)], results: &[(i32, i16)]) -> Vec<Self> {
+        iovs.iter()
+            .zip(results)
+            .map(|(&(page, len), &(res, pages))| Self {
+                page,
+                len,
+                res,
+                pages,
+            })
+            .collect()
+    }
+}
+
+/// Latency percentiles of a histogram of nanoseconds, in microseconds
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Latency {
+    pub count: u64,
+    pub p50_us: f64,
+    pub p90_us: f64,
+    pub p95_us: f64,
+    pub p99_us: f64,
+    pub p999_us: f64,
+    pub max_us: f64,
+}
+
+impl From<&Histogram> for Latency {
+    fn from(hist: &Histogram) -> Self {
+        let us = |ns: u64| ns as f64 / 1e3;
+        Self {
+            count: hist.count(),
+            p50_us: us(hist.percentile(0.5)),
+            p90_us: us(hist.percentile(0.9)),
+            p95_us: us(hist.percentile(0.95)),
+            p99_us: us(hist.percentile(0.99)),
+            p999_us: us(hist.percentile(0.999)),
+            max_us: us(hist.max()),
+        }
+    }
+}
+
+pub fn nanos(d: Duration) -> u64 {
+    d.as_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iov_records() {
+        assert_eq!("json".parse(), Ok(Format::Json));
+        assert!("yaml".parse::<Format>().is_err());
+
+        let iovs = Iov::zip(&[(0, 2), (8, 1)], &[(0, 2), (-12, 0)]);
+        assert_eq!(
+            serde_json::to_string(&iovs).unwrap(),
+            r#"[{"page":0,"len":2,"res":0,"pages":2},{"page":8,"len":1,"res":-12,"pages":0}]"#
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! `replay`: re-issue a trace recorded with `--trace` against a fresh exmap and report every
+//! action whose results differ from the recorded ones.
+
+use std::time::Instant;
+
+use exmap::{
+    trace::{Opcode, Record, TraceReader},
+    ExmapConfig, InterfaceIov, InterfaceWrapper,
+};
+use serde::Serialize;
+
+use crate::{
+    args::{usage, CliResult, Config, Flags},
+    output::{self, Format},
+    with_exmap, PAGE_SIZE,
+};
+
+type Interface<'a> = InterfaceWrapper<'a, InterfaceIov>;
+
+#[derive(Serialize)]
+struct ReplayRecord<'a> {
+    command: &'static str,
+    config: ExmapConfig,
+    trace: &'a str,
+    records: usize,
+    elapsed_ns: u64,
+    divergences: Vec<Divergence>,
+}
+
+/// A record whose replay gave different results
+#[derive(Serialize)]
+struct Divergence {
+    record: usize,
+    opcode: &'static str,
+    interface: u16,
+    recorded: Ret,
+    replayed: Ret,
+    /// Only the iovs whose results differ
+    iovs: Vec<IovDivergence>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Ret {
+    Failed(u16),
+    Errno(i32),
+}
+
+impl From<Result<u16, i32>> for Ret {
+    fn from(ret: Result<u16, i32>) -> Self {
+        match ret {
+            Ok(failed) => Self::Failed(failed),
+            Err(errno) => Self::Errno(errno),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct IovDivergence {
+    index: usize,
+    page: u64,
+    len: u64,
+    recorded_res: i32,
+    recorded_pages: i16,
+    res: i32,
+    pages: i16,
+}
+
+pub fn run(config: Config, flags: Flags, format: Format) -> CliResult<()> {
+    let [path] = &flags.finish()?[..] else {
+        return Err(usage("replay takes one trace file"));
+    };
+    let records = TraceReader::open(path)
+        .and_then(|reader| reader.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{path}: {e}"))?;
+
+    let needed = records.iter().map(|r| r.interface + 1).max().unwrap_or(0);
+    if needed > config.interfaces {
+        return Err(usage(format!(
+            "the trace uses {needed} interfaces, give --interfaces {needed}"
+        )));
+    }
+
+    let start = Instant::now();
+    let divergences = with_exmap(&config, |exmap_fd, mem| {
+        let mut interfaces: Vec<Option<Interface>> = (0..needed).map(|_| None).collect();
+        let mut divergences = Vec::new();
+
+        // Records are in the order they finished, which is the issue order within a thread
+        for (i, recorded) in records.iter().enumerate() {
+            let slot = &mut interfaces[usize::from(recorded.interface)];
+            let mut interface = match slot.take() {
+                Some(interface) => interface,
+                None => unsafe { exmap_fd.mmap_interface(recorded.interface)? },
+            };
+            for &(page, len) in &recorded.iovs {
+                interface
+                    .push(page, len)
+                    .map_err(|()| format!("record {i}: too many iovs"))?;
+            }
+
+            let done = match recorded.opcode {
+                Opcode::Read => interface.read(),
+                Opcode::Alloc => interface.alloc(),
+                Opcode::Free => interface.free(),
+                Opcode::Write => interface.write(),
+            };
+            let (ret, results) = match done {
+                Ok((done, failed)) => {
+                    let results = done.iter().map(|r| (r.res, r.pages)).collect();
+                    *slot = Some(done.into_iov());
+                    (Ok(failed), results)
+                }
+                Err((interface, e)) => {
+                    *slot = Some(interface);
+                    (Err(e.raw_os_error()), Vec::new())
+                }
+            };
+
+            if ret != recorded.ret || results != recorded.results {
+                let divergence = diverge(i, recorded, ret, &results);
+                if format == Format::Text {
+                    report(&divergence);
+                }
+                divergences.push(divergence);
+            }
+        }
+
+        mem.unmap()?;
+        for interface in interfaces.into_iter().flatten() {
+            interface.unmap()?;
+        }
+        Ok(divergences)
+    })?;
+
+    let diverged = divergences.len();
+    match format {
+        Format::Text => println!("replayed {} records, {diverged} diverged", records.len()),
+        Format::Json => output::json(&ReplayRecord {
+            command: "replay",
+            config: config.exmap(PAGE_SIZE),
+            trace: path,
+            records: records.len(),
+            elapsed_ns: output::nanos(start.elapsed()),
+            divergences,
+        }),
+    }
+    if diverged > 0 {
+        return Err(format!("{diverged} records diverged").into());
+    }
+    Ok(())
+}
+
+fn diverge(
+    i: usize,
+    recorded: &Record,
+    ret: Result<u16, i32>,
+    results: &[(i32, i16)],
+) -> Divergence {
+    let none = (0, 0);
+    let iovs = recorded
+        .iovs
+        .iter()
+        .enumerate()
+        .filter_map(|(j, &(page, len))| {
+            let want = recorded.results.get(j).unwrap_or(&none);
+            let got = results.get(j).unwrap_or(&none);
+            (want != got).then_some(IovDivergence {
+                index: j,
+                page,
+                len,
+                recorded_res: want.0,
+                recorded_pages: want.1,
+                res: got.0,
+                pages: got.1,
+            })
+        })
+        .collect();
+
+    Divergence {
+        record: i,
+        opcode: recorded.opcode.name(),
+        interface: recorded.interface,
+        recorded: recorded.ret.into(),
+        replayed: ret.into(),
+        iovs,
+    }
+}
+
+fn report(d: &Divergence) {
+    let show = |ret: &Ret| match ret {
+        Ret::Failed(failed) => format!("{failed} failed"),
+        Ret::Errno(errno) => format!("errno {errno}"),
+    };
+    println!(
+        "record {}: {} on interface {}: recorded {}, replayed {}",
+        d.record,
+        d.opcode,
+        d.interface,
+        show(&d.recorded),
+        show(&d.replayed)
+    );
+    for iov in &d.iovs {
+        println!(
+            "  iov {} {}:{}: recorded res={} pages={}, replayed res={} pages={}",
+            iov.index, iov.page, iov.len, iov.recorded_res, iov.recorded_pages, iov.res, iov.pages
+        );
+    }
+}
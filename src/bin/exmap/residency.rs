@@ -0,0 +1,165 @@
+//! `residency`: map some pages, then show which pages of the VMA are backed by memory.
+
+use std::str::FromStr;
+
+use exmap::{bm::BufferManager, residency::Residency, ExmapConfig};
+use serde::Serialize;
+
+use crate::{
+    args::{check_ranges, usage, CliResult, Config, Flags, PageRange},
+    ops::{iovs, submit, Op},
+    output::{self, Format},
+    with_exmap, PAGE_SIZE,
+};
+
+#[derive(Serialize)]
+struct ResidencyRecord {
+    command: &'static str,
+    config: ExmapConfig,
+    source: &'static str,
+    views: Vec<ViewRecord>,
+}
+
+#[derive(Serialize)]
+struct ViewRecord {
+    name: &'static str,
+    page_count: u64,
+    resident: u64,
+    /// Resident runs as `[start, end)` pages
+    runs: Vec<[u64; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strip: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    /// Ask the kernel with mincore
+    Mincore,
+    /// Fix the pages through the buffer manager and read its page table
+    Bm,
+}
+
+impl Source {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Mincore => "mincore",
+            Self::Bm => "bm",
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mincore" => Ok(Self::Mincore),
+            "bm" => Ok(Self::Bm),
+            _ => Err("expected mincore or bm".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum View {
+    Runs,
+    Strip,
+}
+
+impl FromStr for View {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "runs" => Ok(Self::Runs),
+            "strip" => Ok(Self::Strip),
+            _ => Err("expected runs or strip".to_string()),
+        }
+    }
+}
+
+pub fn run(config: Config, mut flags: Flags, format: Format) -> CliResult<()> {
+    let source = flags.take("source", Source::Mincore)?;
+    let view = flags.take("view", View::Runs)?;
+    let width: usize = flags.take("width", 64)?;
+    let ranges = flags
+        .finish()?
+        .iter()
+        .map(|s| s.parse::<PageRange>().map_err(usage))
+        .collect::<CliResult<Vec<_>>>()?;
+    let pages = config.pages(PAGE_SIZE);
+    check_ranges(&ranges, pages)?;
+    if width == 0 {
+        return Err(usage("--width must be at least 1"));
+    }
+
+    let op = match config.backing {
+        Some(_) => Op::Read,
+        None => Op::Alloc,
+    };
+    let views = with_exmap(&config, |exmap_fd, mem| match source {
+        Source::Mincore => {
+            let interface = unsafe { exmap_fd.mmap_interface(0)? };
+            let (interface, results) = submit(interface, op, &iovs(&ranges))?;
+            if let Some((res, _)) = results.iter().find(|&&(res, _)| res != 0) {
+                return Err(format!("{} failed with res={res}", op.name()).into());
+            }
+            let residency = mem.residency()?;
+            mem.unmap()?;
+            interface.unmap()?;
+            Ok(vec![("mincore", residency)])
+        }
+        Source::Bm => {
+            let bm = unsafe {
+                BufferManager::new(exmap_fd, mem, config.interfaces, config.buffer_size)?
+            };
+            for pid in ranges.iter().flat_map(|r| r.page..r.page + r.len) {
+                drop(bm.fix_s(pid)?);
+            }
+            let table = bm.residency();
+            let mem = bm.into_mem()?;
+            let kernel = mem.residency()?;
+            mem.unmap()?;
+            Ok(vec![("page table", table), ("mincore", kernel)])
+        }
+    })?;
+
+    if format == Format::Json {
+        let views = views
+            .iter()
+            .map(|&(name, ref residency)| ViewRecord {
+                name,
+                page_count: residency.page_count(),
+                resident: residency.resident(),
+                runs: residency.runs().iter().map(|r| [r.start, r.end]).collect(),
+                strip: (view == View::Strip).then(|| residency.strip(width)),
+            })
+            .collect();
+        output::json(&ResidencyRecord {
+            command: "residency",
+            config: config.exmap(PAGE_SIZE),
+            source: source.name(),
+            views,
+        });
+        return Ok(());
+    }
+
+    for (name, residency) in &views {
+        match view {
+            View::Runs => println!("{name}: {residency}"),
+            View::Strip => println!("{name}: |{}|", residency.strip(width)),
+        }
+        summary(name, residency, config.buffer_size);
+    }
+    Ok(())
+}
+
+fn summary(name: &str, residency: &Residency, buffer_size: usize) {
+    let resident = residency.resident();
+    println!(
+        "{name}: {resident} of {} pages resident in {} runs, {:.1}% of the {buffer_size} page buffer_size",
+        residency.page_count(),
+        residency.runs().len(),
+        100.0 * resident as f64 / buffer_size as f64
+    );
+}
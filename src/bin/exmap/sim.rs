@@ -0,0 +1,181 @@
+//! `sim`: miss ratio curves of the eviction policies over a page access trace, without exmap.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::BufReader,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use exmap::cachesim::{self, Point, Policy, Trace};
+use serde::Serialize;
+
+use crate::{
+    args::{usage, CliResult, Flags, Size},
+    output::{self, Format},
+    PAGE_SIZE,
+};
+
+/// Smallest buffer of the default curve
+const MIN_PAGES: usize = 16;
+
+#[derive(Serialize)]
+struct SimRecord<'a> {
+    command: &'static str,
+    trace: &'a str,
+    accesses: usize,
+    threads: usize,
+    distinct_pages: usize,
+    points: Vec<PointRecord>,
+}
+
+#[derive(Serialize)]
+struct PointRecord {
+    policy: &'static str,
+    pages: usize,
+    buffer_size: usize,
+    accesses: u64,
+    misses: u64,
+    miss_ratio: f64,
+}
+
+pub fn run(mut flags: Flags, format: Format) -> CliResult<()> {
+    let sizes: Option<String> = flags.take_opt("sizes")?;
+    let policies: Option<String> = flags.take_opt("policies")?;
+    let [path] = &flags.finish()?[..] else {
+        return Err(usage("sim takes one trace file"));
+    };
+
+    let policies = match policies {
+        Some(list) => list
+            .split(',')
+            .map(|p| p.parse().map_err(usage))
+            .collect::<CliResult<Vec<Policy>>>()?,
+        None => Policy::ALL.to_vec(),
+    };
+    let file = File::open(path).map_err(|e| format!("{path}: {e}"))?;
+    let accesses =
+        cachesim::read_accesses(BufReader::new(file)).map_err(|e| format!("{path}: {e}"))?;
+    let distinct = cachesim::distinct_pages(&accesses);
+
+    // Doubling up to where everything fits, by default
+    let pages = match sizes {
+        Some(list) => list
+            .split(',')
+            .map(
+                |s| match s.parse::<Size>().map_err(usage)?.0 as usize / PAGE_SIZE {
+                    0 => Err(usage(format!("--sizes {s} is less than a page"))),
+                    pages => Ok(pages),
+                },
+            )
+            .collect::<CliResult<Vec<_>>>()?,
+        None => {
+            let mut pages: Vec<_> = std::iter::successors(Some(MIN_PAGES), |p| Some(p * 2))
+                .take_while(|&p| p < distinct)
+                .collect();
+            pages.push(distinct.max(1));
+            pages
+        }
+    };
+
+    let threads = accesses
+        .iter()
+        .map(|a| a.thread)
+        .collect::<HashSet<_>>()
+        .len();
+    let curves = simulate(&Trace::new(&accesses), &pages, &policies);
+
+    if format == Format::Json {
+        let points = curves
+            .iter()
+            .flatten()
+            .map(|point| PointRecord {
+                policy: point.policy.name(),
+                pages: point.pages,
+                buffer_size: point.pages * PAGE_SIZE,
+                accesses: point.accesses,
+                misses: point.misses,
+                miss_ratio: point.miss_ratio(),
+            })
+            .collect();
+        output::json(&SimRecord {
+            command: "sim",
+            trace: path,
+            accesses: accesses.len(),
+            threads,
+            distinct_pages: distinct,
+            points,
+        });
+        return Ok(());
+    }
+
+    println!(
+        "{} accesses from {threads} threads to {distinct} pages",
+        accesses.len()
+    );
+    print!("{:>12} {:>10}", "buffer_size", "pages");
+    for policy in &policies {
+        print!(" {:>10}", policy.name());
+    }
+    println!();
+    for (&p, row) in pages.iter().zip(&curves) {
+        print!("{:>12} {p:>10}", human(p * PAGE_SIZE));
+        for point in row {
+            print!(" {:>10.4}", point.miss_ratio());
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// Simulate every buffer size under every policy, one row of points per size. The points are
+/// independent and handed out to a worker per CPU.
+fn simulate(trace: &Trace<'_>, pages: &[usize], policies: &[Policy]) -> Vec<Vec<Point>> {
+    let total = pages.len() * policies.len();
+    let workers = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(total);
+    let next = AtomicUsize::new(0);
+    let point = |i: usize| trace.simulate(policies[i % policies.len()], pages[i / policies.len()]);
+
+    let mut done: Vec<(usize, Point)> = thread::scope(|s| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        if i >= total {
+                            return done;
+                        }
+                        done.push((i, point(i)));
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    });
+    done.sort_unstable_by_key(|&(i, _)| i);
+
+    let mut points = done.into_iter().map(|(_, point)| point);
+    pages
+        .iter()
+        .map(|_| points.by_ref().take(policies.len()).collect())
+        .collect()
+}
+
+/// Bytes with the largest binary suffix that keeps them whole
+fn human(bytes: usize) -> String {
+    ["", "K", "M", "G", "T"]
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|&(i, _)| bytes % (1 << (10 * i)) == 0 && bytes >= 1 << (10 * i))
+        .map_or(bytes.to_string(), |(i, suffix)| {
+            format!("{}{suffix}", bytes >> (10 * i))
+        })
+}
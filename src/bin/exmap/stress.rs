@@ -0,0 +1,352 @@
+//! `stress`: random alloc, free and read on many threads at once, each through its own
+//! interface, checked after every batch against a model of which pages should be mapped and
+//! what they should hold.
+//!
+//! Every thread owns a slice of the VMA and of the buffer, so what it sees does not depend on
+//! how the threads interleave and the seed alone reproduces a run.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, PoisonError,
+    },
+    thread,
+    time::Instant,
+};
+
+use exmap::{workload::Rng, ExmapConfig, InterfaceIov, InterfaceWrapper};
+use rustix::fd::{AsFd, BorrowedFd};
+use serde::Serialize;
+
+use crate::{
+    args::{usage, CliResult, Config, Flags},
+    open_backing,
+    ops::{submit, Op},
+    output::{self, Format},
+    with_exmap, PAGE_SIZE,
+};
+
+type Interface<'a> = InterfaceWrapper<'a, InterfaceIov>;
+
+/// Longest range a single operation touches
+const MAX_RANGE: u64 = 16;
+
+/// What a page of the model holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Unmapped,
+    /// Allocated and not written since
+    Zero,
+    /// Read from the backing file and not written since
+    File,
+    /// Filled with [`pattern`] for this stamp
+    Stamp(u64),
+}
+
+/// The mapping, shared by the threads, each writing only its own pages
+#[derive(Clone, Copy)]
+struct Base(*mut u8);
+
+// SAFETY: the threads touch disjoint pages
+unsafe impl Send for Base {}
+unsafe impl Sync for Base {}
+
+#[derive(Serialize)]
+struct StressRecord {
+    command: &'static str,
+    config: ExmapConfig,
+    seed: u64,
+    threads: u16,
+    ops: u64,
+    batch: u64,
+    elapsed_ns: u64,
+    /// Pages mapped at the end, if no thread diverged
+    mapped: Option<u64>,
+    divergence: Option<String>,
+}
+
+pub fn run(config: Config, mut flags: Flags, format: Format) -> CliResult<()> {
+    let ops: u64 = flags.take("ops", 10_000)?;
+    let threads: u16 = flags.take("threads", config.interfaces)?;
+    let batch: u64 = flags.take("batch", 8)?;
+    let seed = flags.take("seed", Rng::clock_seed())?;
+    if !flags.finish()?.is_empty() {
+        return Err(usage("stress takes no arguments"));
+    }
+    if threads == 0 || threads > config.interfaces {
+        return Err(usage("--threads must be between 1 and --interfaces"));
+    }
+    if batch == 0 {
+        return Err(usage("--batch must be at least 1"));
+    }
+    let pages = config.pages(PAGE_SIZE) / u64::from(threads);
+    let capacity = config.buffer_size as u64 / u64::from(threads);
+    if pages == 0 || capacity == 0 {
+        return Err(usage(
+            "every thread needs at least a page of --vma-size and of --buffer-size",
+        ));
+    }
+    if format == Format::Text {
+        println!(
+            "seed {seed}, {threads} threads with {pages} pages and {capacity} buffer pages each"
+        );
+    }
+    let start = Instant::now();
+
+    let backing = open_backing(&config)?;
+    with_exmap(&config, |exmap_fd, mem| {
+        let interfaces = (0..threads)
+            .map(|t| unsafe { exmap_fd.mmap_interface(t) })
+            .collect::<Result<Vec<_>, _>>()?;
+        let base = Base(mem.as_mut_ptr());
+        let stop = AtomicBool::new(false);
+        let failure = Mutex::new(None);
+
+        let done: Vec<_> = thread::scope(|s| {
+            let handles: Vec<_> = interfaces
+                .into_iter()
+                .enumerate()
+                .map(|(t, interface)| {
+                    let mut worker = Worker {
+                        thread: t as u16,
+                        first: t as u64 * pages,
+                        model: vec![Slot::Unmapped; pages as usize],
+                        mapped: 0,
+                        capacity,
+                        base,
+                        backing: backing.as_ref().map(|fd| fd.as_fd()),
+                        rng: Rng::new(seed.wrapping_add(t as u64)),
+                    };
+                    let (stop, failure) = (&stop, &failure);
+                    s.spawn(move || {
+                        let res = worker.run(interface, ops, batch, stop);
+                        if let Err(e) = &res {
+                            // Only the first divergence is interesting, the rest may follow from it
+                            stop.store(true, Ordering::Relaxed);
+                            let mut failure =
+                                failure.lock().unwrap_or_else(PoisonError::into_inner);
+                            failure.get_or_insert_with(|| e.clone());
+                        }
+                        res.ok().map(|interface| (interface, worker.mapped))
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let failure = failure
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+            .map(|e| format!("{e}, rerun with --seed {seed} --threads {threads}"));
+        let mapped: u64 = done.iter().flatten().map(|&(_, mapped)| mapped).sum();
+        match format {
+            Format::Text if failure.is_none() => println!(
+                "{} operations ok, {mapped} pages mapped at the end",
+                ops * u64::from(threads)
+            ),
+            Format::Text => {}
+            Format::Json => output::json(&StressRecord {
+                command: "stress",
+                config: config.exmap(PAGE_SIZE),
+                seed,
+                threads,
+                ops,
+                batch,
+                elapsed_ns: output::nanos(start.elapsed()),
+                mapped: failure.is_none().then_some(mapped),
+                divergence: failure.clone(),
+            }),
+        }
+        if let Some(e) = failure {
+            return Err(e.into());
+        }
+        mem.unmap()?;
+        for (interface, _) in done.into_iter().flatten() {
+            interface.unmap()?;
+        }
+        Ok(())
+    })
+}
+
+struct Worker<'f> {
+    thread: u16,
+    /// First page of the thread's slice of the VMA
+    first: u64,
+    model: Vec<Slot>,
+    mapped: u64,
+    capacity: u64,
+    base: Base,
+    backing: Option<BorrowedFd<'f>>,
+    rng: Rng,
+}
+
+impl Worker<'_> {
+    /// Run `ops` operations, checking the model after every `batch` of them
+    fn run<'a>(
+        &mut self,
+        mut interface: Interface<'a>,
+        ops: u64,
+        batch: u64,
+        stop: &AtomicBool,
+    ) -> Result<Interface<'a>, String> {
+        let pages = self.model.len() as u64;
+        for i in 0..ops {
+            if i % batch == 0 {
+                if i > 0 {
+                    self.check(i / batch - 1)?;
+                }
+                if stop.load(Ordering::Relaxed) {
+                    return Ok(interface);
+                }
+            }
+
+            let len = 1 + self.rng.below(MAX_RANGE.min(pages));
+            let start = self.rng.below(pages - len + 1);
+            let mut op = match self.rng.below(if self.backing.is_some() { 4 } else { 3 }) {
+                0 => Op::Alloc,
+                1 => Op::Free,
+                2 => {
+                    self.scribble(start..start + len);
+                    continue;
+                }
+                _ => Op::Read,
+            };
+
+            // Map only what is unmapped and fits the buffer, free only what is mapped
+            let want = |model: &[Slot], op| {
+                runs(start..start + len, |p| {
+                    (model[p as usize] == Slot::Unmapped) != (op == Op::Free)
+                })
+            };
+            let mut iovs = want(&self.model, op);
+            let new: u64 = iovs.iter().map(|&(_, len)| len).sum();
+            if op != Op::Free && self.mapped + new > self.capacity {
+                op = Op::Free;
+                iovs = want(&self.model, op);
+            }
+            if iovs.is_empty() {
+                continue;
+            }
+
+            let global: Vec<_> = iovs.iter().map(|&(p, len)| (self.first + p, len)).collect();
+            let (done, results) = submit(interface, op, &global)
+                .map_err(|e| format!("thread {}: operation {i}: {e}", self.thread))?;
+            interface = done;
+            for (&(page, len), &(res, _)) in global.iter().zip(&results) {
+                if res != 0 {
+                    return Err(format!(
+                        "thread {}: operation {i}: {op:?} {page}:{len} failed with res={res}",
+                        self.thread
+                    ));
+                }
+            }
+
+            let slot = match op {
+                Op::Alloc => Slot::Zero,
+                Op::Read => Slot::File,
+                Op::Free => Slot::Unmapped,
+            };
+            for p in iovs.iter().flat_map(|&(p, len)| p..p + len) {
+                self.model[p as usize] = slot;
+            }
+            let count: u64 = iovs.iter().map(|&(_, len)| len).sum();
+            match op {
+                Op::Free => self.mapped -= count,
+                _ => self.mapped += count,
+            }
+        }
+
+        self.check((ops + batch - 1) / batch)?;
+        Ok(interface)
+    }
+
+    /// Write a fresh pattern into the mapped pages of `range`
+    fn scribble(&mut self, range: std::ops::Range<u64>) {
+        for p in range {
+            if self.model[p as usize] == Slot::Unmapped {
+                continue;
+            }
+            let stamp = self.rng.next_u64();
+            let pid = self.first + p;
+            self.page(pid).copy_from_slice(&pattern(pid, stamp));
+            self.model[p as usize] = Slot::Stamp(stamp);
+        }
+    }
+
+    /// Compare every mapped page of the thread with the model
+    fn check(&mut self, batch: u64) -> Result<(), String> {
+        for p in 0..self.model.len() as u64 {
+            let pid = self.first + p;
+            let expected = match self.model[p as usize] {
+                Slot::Unmapped => continue,
+                Slot::Zero => vec![0; PAGE_SIZE],
+                Slot::File => self.file_page(pid)?,
+                Slot::Stamp(stamp) => pattern(pid, stamp),
+            };
+            if self.page(pid)[..] != expected[..] {
+                return Err(format!(
+                    "thread {}: after batch {batch}: page {pid} should hold {:?}",
+                    self.thread, self.model[p as usize]
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn page(&mut self, pid: u64) -> &mut [u8] {
+        unsafe {
+            std::slice::from_raw_parts_mut(self.base.0.add(pid as usize * PAGE_SIZE), PAGE_SIZE)
+        }
+    }
+
+    /// The backing file's page, zeroes past its end
+    fn file_page(&self, pid: u64) -> Result<Vec<u8>, String> {
+        let mut page = vec![0; PAGE_SIZE];
+        let fd = self.backing.expect("reads need a backing file");
+        let mut filled = 0;
+        while filled < PAGE_SIZE {
+            let offset = pid * PAGE_SIZE as u64 + filled as u64;
+            match rustix::io::pread(fd, &mut page[filled..], offset) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) => return Err(format!("pread of page {pid}: {e}")),
+            }
+        }
+        Ok(page)
+    }
+}
+
+/// Page contents for a stamp, different for every page
+fn pattern(pid: u64, stamp: u64) -> Vec<u8> {
+    let mut rng = Rng::new(stamp ^ pid.rotate_left(32));
+    (0..PAGE_SIZE / 8)
+        .flat_map(|_| rng.next_u64().to_le_bytes())
+        .collect()
+}
+
+/// Maximal runs of pages in `pids` for which `f` holds
+fn runs(pids: std::ops::Range<u64>, f: impl Fn(u64) -> bool) -> Vec<(u64, u64)> {
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    for pid in pids.filter(|&pid| f(pid)) {
+        match runs.last_mut() {
+            Some((start, len)) if *start + *len == pid => *len += 1,
+            _ => runs.push((pid, 1)),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_runs() {
+        let mapped = [true, true, false, true, false, false, true];
+        assert_eq!(
+            runs(0..7, |pid| mapped[pid as usize]),
+            [(0, 2), (3, 1), (6, 1)]
+        );
+        assert_eq!(runs(2..6, |pid| !mapped[pid as usize]), [(2, 1), (4, 2)]);
+    }
+}
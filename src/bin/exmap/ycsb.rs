@@ -0,0 +1,239 @@
+//! `ycsb`: the YCSB core workloads against the key value store on a backing file, reported as
+//! CSV or JSON.
+
+use std::{
+    collections::HashMap,
+    ops::Bound,
+    sync::atomic::AtomicU64,
+    thread,
+    time::{Duration, Instant},
+};
+
+use exmap::{
+    kv::{Kv, KvOptions},
+    workload::{key, Distribution, Generator, Operation, Request, Rng, Workload},
+    ExmapConfig,
+};
+use serde::Serialize;
+
+use crate::{
+    args::{usage, CliResult, Config, Flags},
+    hist::Histogram,
+    output::{self, Format, Latency},
+    PAGE_SIZE,
+};
+
+type Stats = HashMap<Operation, Histogram>;
+
+pub fn run(config: Config, mut flags: Flags, format: Format) -> CliResult<()> {
+    let name: String = flags.take("workload", "A".to_string())?;
+    let mut workload =
+        Workload::ycsb(&name).ok_or_else(|| usage("--workload must be one of A to F"))?;
+    if let Some(distribution) = flags.take_opt::<Distribution>("distribution")? {
+        workload.distribution = distribution;
+    }
+    let records: u64 = flags.take("records", 100_000)?;
+    let operations: u64 = flags.take("operations", 100_000)?;
+    let threads: u16 = flags.take("threads", 1)?;
+    let value_size: usize = flags.take("value-size", 100)?;
+    let theta: f64 = flags.take("theta", 0.99)?;
+    let seed = flags.take("seed", Rng::clock_seed())?;
+    if !flags.finish()?.is_empty() {
+        return Err(usage("ycsb takes no arguments"));
+    }
+    if records == 0 {
+        return Err(usage("--records must be at least 1"));
+    }
+    if !(theta > 0.0 && theta < 1.0) {
+        return Err(usage("--theta must be between 0 and 1, exclusive"));
+    }
+    if threads == 0 || threads > config.interfaces {
+        return Err(usage("--threads must be between 1 and --interfaces"));
+    }
+    if key(0).len() + value_size > Kv::<PAGE_SIZE>::max_entry() {
+        return Err(usage(format!(
+            "--value-size must be at most {}",
+            Kv::<PAGE_SIZE>::max_entry() - key(0).len()
+        )));
+    }
+    let Some(path) = &config.backing else {
+        return Err(usage("ycsb needs --backing"));
+    };
+
+    let options = KvOptions {
+        page_count: config.pages(PAGE_SIZE),
+        buffer_size: config.buffer_size,
+        interfaces: config.interfaces,
+    };
+    let (load, run) = Kv::<PAGE_SIZE>::with_file(path, options, |kv| {
+        let start = Instant::now();
+        let mut load = Stats::new();
+        let value = vec![0; value_size];
+        for record in 0..records {
+            let start = Instant::now();
+            kv.put(&key(record), &value)?;
+            record_latency(&mut load, Operation::Insert, start);
+        }
+        let load = (load, start.elapsed());
+
+        let count = AtomicU64::new(records);
+        let start = Instant::now();
+        let stats = thread::scope(|s| {
+            let workers: Vec<_> = (0..threads)
+                .map(|t| {
+                    let per_thread = operations / u64::from(threads)
+                        + u64::from(u64::from(t) < operations % u64::from(threads));
+                    let mut gen = Generator::new(
+                        workload.clone(),
+                        &count,
+                        theta,
+                        seed.wrapping_add(t.into()),
+                    );
+                    s.spawn(move || -> exmap::Result<Stats> {
+                        let mut stats = Stats::new();
+                        let mut value = vec![0; value_size];
+                        for _ in 0..per_thread {
+                            let request = gen.next_request();
+                            let start = Instant::now();
+                            execute(kv, request, &mut value)?;
+                            record_latency(&mut stats, request.operation(), start);
+                        }
+                        Ok(stats)
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|w| w.join().expect("ycsb worker panicked"))
+                .collect::<exmap::Result<Vec<_>>>()
+        })?;
+
+        let mut run = Stats::new();
+        for stats in stats {
+            for (op, hist) in stats {
+                run.entry(op).or_default().merge(&hist);
+            }
+        }
+        Ok((load, (run, start.elapsed())))
+    })?;
+
+    let run_info = RunInfo {
+        command: "ycsb",
+        workload: workload.name,
+        distribution: format!("{:?}", workload.distribution),
+        threads,
+        seed,
+        config: config.exmap(PAGE_SIZE),
+    };
+    if format == Format::Text {
+        println!("workload,distribution,threads,seed,phase,operation,count,seconds,ops_per_sec,p50_us,p95_us,p99_us,max_us");
+    }
+    for (phase, (stats, elapsed)) in [("load", load), ("run", run)] {
+        report(&run_info, phase, &stats, elapsed, format);
+    }
+    Ok(())
+}
+
+/// What every row shares
+#[derive(Serialize)]
+struct RunInfo {
+    command: &'static str,
+    workload: &'static str,
+    distribution: String,
+    threads: u16,
+    seed: u64,
+    config: ExmapConfig,
+}
+
+#[derive(Serialize)]
+struct Row<'a> {
+    #[serde(flatten)]
+    run: &'a RunInfo,
+    phase: &'a str,
+    operation: &'a str,
+    seconds: f64,
+    ops_per_sec: f64,
+    latency: Latency,
+}
+
+fn record_latency(stats: &mut Stats, op: Operation, start: Instant) {
+    stats
+        .entry(op)
+        .or_default()
+        .record(start.elapsed().as_nanos() as u64);
+}
+
+fn execute(
+    kv: &Kv<'_, '_, '_, PAGE_SIZE>,
+    request: Request,
+    value: &mut [u8],
+) -> exmap::Result<()> {
+    match request {
+        Request::Read(record) => {
+            kv.get(&key(record))?;
+        }
+        Request::Update(record) | Request::Insert(record) => {
+            value.fill(record as u8);
+            kv.put(&key(record), value)?;
+        }
+        Request::Scan { record, len } => {
+            let start = key(record);
+            for entry in kv
+                .range(Bound::Included(&start[..]), Bound::Unbounded)
+                .take(len as usize)
+            {
+                entry?;
+            }
+        }
+        Request::ReadModifyWrite(record) => {
+            let mut current = kv.get(&key(record))?.unwrap_or_else(|| value.to_vec());
+            current.iter_mut().for_each(|b| *b = b.wrapping_add(1));
+            kv.put(&key(record), &current)?;
+        }
+    }
+    Ok(())
+}
+
+/// One row per operation and one for all of them
+fn report(run: &RunInfo, phase: &str, stats: &Stats, elapsed: Duration, format: Format) {
+    let mut all = Histogram::default();
+    let mut rows = Vec::new();
+    for op in Operation::ALL {
+        if let Some(hist) = stats.get(&op) {
+            all.merge(hist);
+            rows.push((op.name(), hist));
+        }
+    }
+    rows.push(("all", &all));
+
+    for (operation, hist) in rows {
+        let row = Row {
+            run,
+            phase,
+            operation,
+            seconds: elapsed.as_secs_f64(),
+            ops_per_sec: hist.count() as f64 / elapsed.as_secs_f64(),
+            latency: Latency::from(hist),
+        };
+        match format {
+            Format::Text => {
+                let l = &row.latency;
+                println!(
+                    "{},{},{},{},{phase},{operation},{},{:.3},{:.1},{:.2},{:.2},{:.2},{:.2}",
+                    run.workload,
+                    run.distribution,
+                    run.threads,
+                    run.seed,
+                    l.count,
+                    row.seconds,
+                    row.ops_per_sec,
+                    l.p50_us,
+                    l.p95_us,
+                    l.p99_us,
+                    l.max_us
+                );
+            }
+            Format::Json => output::json(&row),
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Large objects stored in runs of contiguous pages.
+//!
+//! A blob occupies `ceil(len / P)` consecutive page ids. Fixing it faults every evicted page of
+//! the run in with a single ioctl and hands out one slice spanning the whole object, so callers
+//! never reassemble it page by page.
+//!
+//! Blobs need a buffer manager without page checksums, the per-page header would break up the
+//! object.
+
+use std::ops::{Deref, DerefMut};
+
+use rustix::io;
+
+use crate::{
+    bm::{BufferManager, ExclusiveRunGuard, SharedRunGuard},
+    Result,
+};
+
+/// Where a blob lives. Stored by callers, e.g. in an index, to find the blob again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlobId {
+    pub pid: u64,
+    /// Length in bytes
+    pub len: u64,
+}
+
+impl BlobId {
+    pub const ENCODED_SIZE: usize = 16;
+
+    /// Number of pages the blob occupies
+    pub fn pages<const P: usize>(&self) -> u64 {
+        ((self.len + P as u64 - 1) / P as u64).max(1)
+    }
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_SIZE] {
+        let mut buf = [0; Self::ENCODED_SIZE];
+        buf[0..8].copy_from_slice(&self.pid.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.len.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8; Self::ENCODED_SIZE]) -> Self {
+        Self {
+            pid: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+            len: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+pub struct BlobStore<'bm, 'a, 'b, const P: usize> {
+    bm: &'bm BufferManager<'a, 'b, P>,
+}
+
+impl<'bm, 'a, 'b, const P: usize> BlobStore<'bm, 'a, 'b, P> {
+    /// Fails with `EINVAL` if the buffer manager checksums or logs its pages, since blobs use
+    /// the page headers
+    pub fn new(bm: &'bm BufferManager<'a, 'b, P>) -> io::Result<Self> {
+        if bm.checksums() || bm.wal().is_some() {
+            return Err(io::Errno::INVAL);
+        }
+        Ok(Self { bm })
+    }
+
+    /// Store `data` in a freshly allocated run. The tail of the last page is zeroed.
+    pub fn put(&self, data: &[u8]) -> io::Result<BlobId> {
+        let id = BlobId {
+            pid: 0,
+            len: data.len() as u64,
+        };
+        let mut run = self.bm.allocate_run(id.pages::<P>())?;
+        run[..data.len()].copy_from_slice(data);
+
+        Ok(BlobId {
+            pid: run.pid(),
+            ..id
+        })
+    }
+
+    /// Latch the blob shared and view it as one slice
+    pub fn get(&self, id: BlobId) -> Result<Blob<'bm, 'a, 'b, P>> {
+        Ok(Blob {
+            run: self.bm.fix_run_s(id.pid, id.pages::<P>())?,
+            len: id.len as usize,
+        })
+    }
+
+    /// Latch the blob exclusively to change it in place
+    pub fn get_mut(&self, id: BlobId) -> Result<BlobMut<'bm, 'a, 'b, P>> {
+        Ok(BlobMut {
+            run: self.bm.fix_run_x(id.pid, id.pages::<P>())?,
+            len: id.len as usize,
+        })
+    }
+
+    /// Drop the blob and return its pages to the allocator
+    pub fn delete(&self, id: BlobId) -> io::Result<()> {
+        self.bm.free_run(id.pid, id.pages::<P>())
+    }
+}
+
+pub struct Blob<'bm, 'a, 'b, const P: usize> {
+    run: SharedRunGuard<'bm, 'a, 'b, P>,
+    len: usize,
+}
+
+impl<'bm, 'a, 'b, const P: usize> Deref for Blob<'bm, 'a, 'b, P> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.run[..self.len]
+    }
+}
+
+pub struct BlobMut<'bm, 'a, 'b, const P: usize> {
+    run: ExclusiveRunGuard<'bm, 'a, 'b, P>,
+    len: usize,
+}
+
+impl<'bm, 'a, 'b, const P: usize> Deref for BlobMut<'bm, 'a, 'b, P> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.run[..self.len]
+    }
+}
+
+impl<'bm, 'a, 'b, const P: usize> DerefMut for BlobMut<'bm, 'a, 'b, P> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.run[..self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustix::fd::AsFd;
+
+    use super::*;
+    use crate::{allocator::PageAllocator, testutil::TempFile, OwnedExmapFd};
+
+    #[test]
+    fn id_roundtrip() {
+        let id = BlobId {
+            pid: 42,
+            len: 3 * 4096 + 1,
+        };
+        assert_eq!(BlobId::decode(&id.encode()), id);
+        assert_eq!(id.pages::<4096>(), 4);
+        assert_eq!(BlobId { pid: 1, len: 0 }.pages::<4096>(), 1);
+        assert_eq!(BlobId { pid: 1, len: 4096 }.pages::<4096>(), 1);
+    }
+
+    /// More blobs than fit into the buffer, so getting the early ones faults their runs back in
+    #[test]
+    fn put_get_delete() {
+        let path = TempFile::new("blob");
+        let backing = path.create();
+        let exmap_fd = OwnedExmapFd::<4096>::open().unwrap();
+        let mem = exmap_fd
+            .create(256 * 4096, 1, 64, Some(backing.as_fd()))
+            .unwrap();
+        let allocator = PageAllocator::format(backing.as_fd(), 256).unwrap();
+        let bm = unsafe { BufferManager::new(&exmap_fd, mem, 1, 64) }
+            .unwrap()
+            .with_allocator(allocator);
+        let blobs = BlobStore::new(&bm).unwrap();
+
+        let data = |i: usize| -> Vec<u8> { (0..4 * 4096 + i).map(|b| (b * 7 + i) as u8).collect() };
+        let ids: Vec<_> = (0..20).map(|i| blobs.put(&data(i)).unwrap()).collect();
+        assert!(ids.iter().all(|id| id.pages::<4096>() == 5));
+        for (i, &id) in ids.iter().enumerate() {
+            assert_eq!(&*blobs.get(id).unwrap(), &data(i)[..]);
+        }
+
+        blobs.get_mut(ids[3]).unwrap()[4096..4100].copy_from_slice(b"blob");
+        assert_eq!(&blobs.get(ids[3]).unwrap()[4096..4100], b"blob");
+        assert_eq!(&*blobs.get(ids[4]).unwrap(), &data(4)[..]);
+
+        let empty = blobs.put(b"").unwrap();
+        assert_eq!(blobs.get(empty).unwrap().len(), 0);
+
+        blobs.delete(ids[0]).unwrap();
+        let allocator = bm.allocator().unwrap();
+        assert!(!(ids[0].pid..ids[0].pid + 5).any(|p| allocator.is_allocated(p)));
+        assert_eq!(&*blobs.get(ids[1]).unwrap(), &data(1)[..]);
+
+        bm.close().unwrap();
+    }
+
+    /// A blob put into the run of a deleted one sees none of the old bytes
+    #[test]
+    fn reuses_freed_run() {
+        let path = TempFile::new("blob-reuse");
+        let backing = path.create();
+        let exmap_fd = OwnedExmapFd::<4096>::open().unwrap();
+        let mem = exmap_fd
+            .create(256 * 4096, 1, 64, Some(backing.as_fd()))
+            .unwrap();
+        let allocator = PageAllocator::format(backing.as_fd(), 256).unwrap();
+        let bm = unsafe { BufferManager::new(&exmap_fd, mem, 1, 64) }
+            .unwrap()
+            .with_allocator(allocator);
+        let blobs = BlobStore::new(&bm).unwrap();
+
+        let old = blobs.put(&[0xaa; 2 * 4096]).unwrap();
+        let next = blobs.put(&[0xbb; 4096]).unwrap();
+        blobs.delete(old).unwrap();
+
+        let new = blobs.put(&[0xcc; 4096 + 10]).unwrap();
+        assert_eq!(new.pid, old.pid);
+        assert_eq!(&*blobs.get(new).unwrap(), &[0xcc; 4096 + 10][..]);
+        // The tail of the last page was zeroed
+        let run = bm.fix_run_s(new.pid, 2).unwrap();
+        assert!(run[4096 + 10..].iter().all(|&b| b == 0));
+        drop(run);
+        assert_eq!(&*blobs.get(next).unwrap(), &[0xbb; 4096][..]);
+
+        bm.close().unwrap();
+    }
+}
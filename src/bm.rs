@@ -0,0 +1,1471 @@
+//! A vmcache style buffer manager on top of an exmap.
+//!
+//! Every page of the [`VirtMem`] has a [`PageState`] word holding a version and a latch. Pages
+//! are faulted in through an exmap interface and evicted with a clock over the resident pages.
+
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+};
+
+use rustix::{fs, io};
+
+use crate::{
+    allocator::PageAllocator,
+    checksum, fileio,
+    residency::Residency,
+    superblock::Superblock,
+    sys,
+    wal::{self, Wal},
+    Error, Evict, InterfaceIov, InterfaceWrapper, OpResult, OwnedExmapFd, Result, VirtMem,
+};
+
+/// How many pages the clock looks at per round
+pub(crate) const EVICT_BATCH: usize = 64;
+
+/// How many times a fault is retried after running out of exmap memory
+const FAULT_ATTEMPTS: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageStatus {
+    Unlocked,
+    LockedShared(u8),
+    Locked,
+    Marked,
+    Evicted,
+}
+
+impl PageStatus {
+    pub const MAX_SHARED: u8 = 252;
+}
+
+impl From<PageStatus> for u8 {
+    fn from(v: PageStatus) -> u8 {
+        match v {
+            PageStatus::Unlocked => 0,
+            PageStatus::Locked => 253,
+            PageStatus::Marked => 254,
+            PageStatus::Evicted => 255,
+            PageStatus::LockedShared(v) => v,
+        }
+    }
+}
+
+impl From<u8> for PageStatus {
+    fn from(v: u8) -> Self {
+        match v {
+            0 => Self::Unlocked,
+            253 => Self::Locked,
+            254 => Self::Marked,
+            255 => Self::Evicted,
+            v => Self::LockedShared(v),
+        }
+    }
+}
+
+/// The state word of a page: a 56 bit version followed by an 8 bit [`PageStatus`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageState {
+    data: [u8; 8],
+}
+
+impl PageState {
+    pub fn new(version: u64, status: PageStatus) -> Self {
+        let mut state = Self { data: [0; 8] };
+        state.set_version(version);
+        state.set_status(status);
+        state
+    }
+
+    pub fn version(&self) -> u64 {
+        u64::from_le_bytes([
+            self.data[0],
+            self.data[1],
+            self.data[2],
+            self.data[3],
+            self.data[4],
+            self.data[5],
+            self.data[6],
+            0,
+        ])
+    }
+
+    pub fn set_version(&mut self, new_val: u64) {
+        assert!(new_val < (0x01_u64 << 56));
+        let le_bytes = new_val.to_le_bytes();
+        self.data[..7].copy_from_slice(&le_bytes[..7])
+    }
+
+    pub fn status(&self) -> PageStatus {
+        self.data[7].into()
+    }
+
+    pub fn set_status(&mut self, new_val: PageStatus) {
+        self.data[7] = new_val.into();
+    }
+
+    /// Same version with a different status
+    pub fn with_status(mut self, status: PageStatus) -> Self {
+        self.set_status(status);
+        self
+    }
+
+    /// Next version with a different status, wraps at 56 bits
+    pub fn bump(self, status: PageStatus) -> Self {
+        Self::new((self.version() + 1) & ((0x01_u64 << 56) - 1), status)
+    }
+}
+
+impl From<u64> for PageState {
+    fn from(v: u64) -> Self {
+        Self {
+            data: v.to_le_bytes(),
+        }
+    }
+}
+
+impl From<PageState> for u64 {
+    fn from(v: PageState) -> u64 {
+        u64::from_le_bytes(v.data)
+    }
+}
+
+/// Lock free open addressing set of the resident page ids, doubling as the clock for eviction
+pub(crate) struct ResidentSet {
+    slots: Box<[AtomicU64]>,
+    mask: u64,
+    hand: AtomicU64,
+}
+
+impl ResidentSet {
+    const EMPTY: u64 = u64::MAX;
+    const TOMBSTONE: u64 = u64::MAX - 1;
+
+    pub(crate) fn new(max_pages: usize) -> Self {
+        let capacity = (max_pages + max_pages / 2).max(1).next_power_of_two();
+        let slots = (0..capacity).map(|_| AtomicU64::new(Self::EMPTY)).collect();
+
+        Self {
+            slots,
+            mask: capacity as u64 - 1,
+            hand: AtomicU64::new(0),
+        }
+    }
+
+    fn hash(pid: u64) -> u64 {
+        // murmur finalizer
+        let mut k = pid;
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xff51afd7ed558ccd);
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xc4ceb9fe1a85ec53);
+        k ^ (k >> 33)
+    }
+
+    pub(crate) fn insert(&self, pid: u64) {
+        let mut pos = Self::hash(pid) & self.mask;
+        loop {
+            let slot = &self.slots[pos as usize];
+            let curr = slot.load(Ordering::Acquire);
+            debug_assert_ne!(curr, pid);
+            if (curr == Self::EMPTY || curr == Self::TOMBSTONE)
+                && slot
+                    .compare_exchange(curr, pid, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return;
+            }
+            pos = (pos + 1) & self.mask;
+        }
+    }
+
+    pub(crate) fn remove(&self, pid: u64) -> bool {
+        let mut pos = Self::hash(pid) & self.mask;
+        loop {
+            let slot = &self.slots[pos as usize];
+            let curr = slot.load(Ordering::Acquire);
+            if curr == Self::EMPTY {
+                return false;
+            }
+            if curr == pid
+                && slot
+                    .compare_exchange(curr, Self::TOMBSTONE, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return true;
+            }
+            pos = (pos + 1) & self.mask;
+        }
+    }
+
+    /// Advance the clock hand by `batch` slots, calling `f` on each resident page
+    pub(crate) fn clock_batch(&self, batch: usize, mut f: impl FnMut(u64)) {
+        let start = self.hand.fetch_add(batch as u64, Ordering::Relaxed);
+        for i in 0..batch as u64 {
+            let curr = self.slots[((start + i) & self.mask) as usize].load(Ordering::Acquire);
+            if curr != Self::EMPTY && curr != Self::TOMBSTONE {
+                f(curr);
+            }
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+/// Where pages are read from and written back to, in place of the exmap backing fd
+pub trait PageStore: Send + Sync {
+    fn read_page(&self, pid: u64, page: &mut [u8]) -> io::Result<()>;
+
+    fn write_page(&self, pid: u64, page: &[u8]) -> io::Result<()>;
+
+    /// Make the written pages durable
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Fixing pages, implemented by [`BufferManager`] and the [`PreadPool`](crate::pool::PreadPool)
+/// baseline so benchmarks can run on either. Dropping a guard unfixes the page.
+pub trait BufferPool<const P: usize>: Sync {
+    type Exclusive<'g>: DerefMut<Target = [u8; P]>
+    where
+        Self: 'g;
+    type Shared<'g>: Deref<Target = [u8; P]>
+    where
+        Self: 'g;
+
+    fn fix_x(&self, pid: u64) -> Result<Self::Exclusive<'_>>;
+
+    fn fix_s(&self, pid: u64) -> Result<Self::Shared<'_>>;
+
+    /// Write back every dirty page and make it durable, returning how many were written
+    fn flush(&self) -> Result<usize>;
+
+    /// Pages read in so far
+    fn faults(&self) -> u64;
+}
+
+thread_local! {
+    static WORKER: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+static NEXT_WORKER: AtomicUsize = AtomicUsize::new(0);
+
+type InterfaceSlot<'a> = Mutex<Option<InterfaceWrapper<'a, InterfaceIov>>>;
+
+pub struct BufferManager<'a, 'b, const P: usize> {
+    mem: VirtMem<'a, 'b, P>,
+    states: Box<[AtomicU64]>,
+    dirty: Box<[AtomicBool]>,
+    resident: ResidentSet,
+    interfaces: Box<[InterfaceSlot<'a>]>,
+    /// Pages currently mapped by the buffer manager
+    physical: AtomicUsize,
+    /// Userspace limit on `physical`, never above `limit`
+    budget: AtomicUsize,
+    /// The `buffer_size` exmap was set up with
+    limit: usize,
+    alloc_count: AtomicU64,
+    /// Pages faulted in so far
+    faults: AtomicU64,
+    store: Option<Box<dyn PageStore + 'b>>,
+    allocator: Option<PageAllocator<'b, P>>,
+    /// Pages carry a [`checksum`] header
+    checksums: bool,
+    wal: Option<Wal>,
+}
+
+// SAFETY:
+// Concurrent access to the pages of the virtual memory is mediated by the page latches and the
+// interfaces are behind mutexes.
+unsafe impl<'a, 'b, const P: usize> Send for BufferManager<'a, 'b, P> {}
+unsafe impl<'a, 'b, const P: usize> Sync for BufferManager<'a, 'b, P> {}
+
+impl<'a, 'b, const P: usize> BufferManager<'a, 'b, P> {
+    /// Takes over `mem` and maps interfaces `0..max_interfaces` of `exmap_fd`. `buffer_size`
+    /// should be the value passed to [`OwnedExmapFd::create`]. On error `mem` is unmapped.
+    ///
+    /// # Safety
+    /// None of the interfaces may have been mapped already.
+    pub unsafe fn new(
+        exmap_fd: &'a OwnedExmapFd<P>,
+        mem: VirtMem<'a, 'b, P>,
+        max_interfaces: u16,
+        buffer_size: usize,
+    ) -> io::Result<Self> {
+        let mut interfaces = Vec::with_capacity(max_interfaces.into());
+        for i in 0..max_interfaces {
+            match unsafe { exmap_fd.mmap_interface(i) } {
+                Ok(interface) => interfaces.push(Mutex::new(Some(interface))),
+                Err(e) => {
+                    for slot in interfaces {
+                        let _ = take_slot(slot).map(InterfaceWrapper::unmap);
+                    }
+                    let _ = mem.unmap();
+                    return Err(e);
+                }
+            }
+        }
+
+        let page_count = mem.size() / P;
+        let evicted: u64 = PageState::new(0, PageStatus::Evicted).into();
+
+        Ok(Self {
+            states: (0..page_count).map(|_| AtomicU64::new(evicted)).collect(),
+            dirty: (0..page_count).map(|_| AtomicBool::new(false)).collect(),
+            resident: ResidentSet::new(buffer_size),
+            interfaces: interfaces.into(),
+            physical: AtomicUsize::new(0),
+            budget: AtomicUsize::new(buffer_size),
+            limit: buffer_size,
+            alloc_count: AtomicU64::new(0),
+            faults: AtomicU64::new(0),
+            store: None,
+            allocator: None,
+            checksums: false,
+            wal: None,
+            mem,
+        })
+    }
+
+    /// Stamp a [`checksum`] into the page header on write back and verify it when a page is
+    /// read back in. The first [`checksum::HEADER_SIZE`] bytes of every page belong to the header.
+    pub fn with_checksums(mut self) -> Self {
+        self.checksums = true;
+        self
+    }
+
+    /// Route page reads and write backs through `store` instead of the exmap backing fd.
+    /// Faults then allocate the page and fill it with a `pread` from the store.
+    pub fn with_store(mut self, store: impl PageStore + 'b) -> Self {
+        self.store = Some(Box::new(store));
+        self
+    }
+
+    /// The backing file starts with a [`Superblock`], so counting page ids up without an
+    /// allocator starts after it. A [`PageAllocator`] keeps it reserved on its own.
+    pub fn with_superblock(self) -> Self {
+        self.alloc_count
+            .store(Superblock::PID + 1, Ordering::Relaxed);
+        self
+    }
+
+    /// Hand out page ids from a persistent allocator instead of counting up from 0
+    pub fn with_allocator(mut self, allocator: PageAllocator<'b, P>) -> Self {
+        self.allocator = Some(allocator);
+        self
+    }
+
+    /// Log every change made through an exclusive guard to `wal`, which must have recovered
+    /// the backing file already. Pages carry their LSN in the [`checksum`] header, so the
+    /// first [`checksum::HEADER_SIZE`] bytes of every page belong to it as with checksums.
+    pub fn with_wal(mut self, wal: Wal) -> Self {
+        self.wal = Some(wal);
+        self
+    }
+
+    #[inline]
+    pub fn checksums(&self) -> bool {
+        self.checksums
+    }
+
+    pub fn allocator(&self) -> Option<&PageAllocator<'b, P>> {
+        self.allocator.as_ref()
+    }
+
+    pub fn wal(&self) -> Option<&Wal> {
+        self.wal.as_ref()
+    }
+
+    /// Pages faulted in since the buffer manager was created, to tell hits from misses
+    pub fn faults(&self) -> u64 {
+        self.faults.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn page_count(&self) -> u64 {
+        self.states.len() as u64
+    }
+
+    /// Number of pages currently faulted in
+    #[inline]
+    pub fn resident(&self) -> usize {
+        self.physical.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn budget(&self) -> usize {
+        self.budget.load(Ordering::Relaxed)
+    }
+
+    /// The kernel limit, `buffer_size`
+    #[inline]
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Which pages the page table holds as faulted in. Pages being faulted or evicted right now
+    /// may show either way.
+    pub fn residency(&self) -> Residency {
+        Residency::from_fn(self.page_count(), |pid| {
+            PageState::from(self.state(pid).load(Ordering::Acquire)).status() != PageStatus::Evicted
+        })
+    }
+
+    /// Change the number of pages the buffer manager may keep resident. The budget is capped at
+    /// the kernel `buffer_size`. Lowering it evicts pages until the new target is met.
+    ///
+    /// Returns the number of resident pages afterwards.
+    pub fn set_budget(&self, pages: usize) -> io::Result<usize> {
+        let pages = pages.min(self.limit);
+        self.budget.store(pages, Ordering::Relaxed);
+
+        loop {
+            let resident = self.resident();
+            if resident <= pages || self.evict(resident - pages)? == 0 {
+                return Ok(self.resident());
+            }
+        }
+    }
+
+    #[inline]
+    pub(crate) fn state(&self, pid: u64) -> &AtomicU64 {
+        &self.states[pid as usize]
+    }
+
+    #[inline]
+    pub(crate) fn page_ptr(&self, pid: u64) -> *mut u8 {
+        debug_assert!(pid < self.page_count());
+        unsafe { self.mem.as_mut_ptr().add(pid as usize * P) }
+    }
+
+    /// Latch the page exclusively, faulting it in if needed. Fails with `EINVAL` if `pid` is out
+    /// of range.
+    pub fn fix_x(&self, pid: u64) -> Result<ExclusiveGuard<'_, 'a, 'b, P>> {
+        self.lock_x(pid, 1)?;
+        Ok(ExclusiveGuard {
+            bm: self,
+            pid,
+            before: None,
+        })
+    }
+
+    /// Latch the page shared, faulting it in if needed
+    pub fn fix_s(&self, pid: u64) -> Result<SharedGuard<'_, 'a, 'b, P>> {
+        self.lock_s(pid, 1)?;
+        Ok(SharedGuard { bm: self, pid })
+    }
+
+    /// Read the page without latching it, faulting it in if needed. Optimistic readers never
+    /// block writers, instead they check afterwards that no writer got in between.
+    pub fn fix_o(&self, pid: u64) -> Result<OptimisticGuard<'_, 'a, 'b, P>> {
+        self.check_range(pid, 1)?;
+        loop {
+            let curr = self.state(pid).load(Ordering::Acquire);
+            let page_state = PageState::from(curr);
+            match page_state.status() {
+                PageStatus::Locked => thread::yield_now(),
+                PageStatus::Evicted => drop(self.fix_s(pid)?),
+                PageStatus::Marked => {
+                    // Accessed again, so not a victim. Losing the race is harmless.
+                    let next = page_state.with_status(PageStatus::Unlocked);
+                    self.try_transition(pid, curr, next);
+                }
+                _ => {
+                    return Ok(OptimisticGuard {
+                        bm: self,
+                        pid,
+                        version: page_state.version(),
+                    })
+                }
+            }
+        }
+    }
+
+    /// Latch `len` contiguous pages exclusively. The evicted pages of the run are faulted in
+    /// with a single ioctl, one iov per evicted stretch.
+    pub fn fix_run_x(&self, pid: u64, len: u64) -> Result<ExclusiveRunGuard<'_, 'a, 'b, P>> {
+        self.lock_x(pid, len)?;
+        Ok(ExclusiveRunGuard {
+            bm: self,
+            pid,
+            len,
+            before: None,
+        })
+    }
+
+    /// Latch `len` contiguous pages shared, faulting them in like [`fix_run_x`](Self::fix_run_x)
+    pub fn fix_run_s(&self, pid: u64, len: u64) -> Result<SharedRunGuard<'_, 'a, 'b, P>> {
+        self.lock_s(pid, len)?;
+        Ok(SharedRunGuard { bm: self, pid, len })
+    }
+
+    /// Hand out a fresh zeroed page, latched exclusively. Without an allocator page ids are
+    /// handed out from 0 upwards, or from 1 [`with_superblock`](Self::with_superblock).
+    pub fn allocate_page(&self) -> io::Result<ExclusiveGuard<'_, 'a, 'b, P>> {
+        let pid = self.allocate_pids(1)?;
+        self.lock_x_new(pid, 1)?;
+
+        let mut guard = ExclusiveGuard {
+            bm: self,
+            pid,
+            before: None,
+        };
+        guard.fill(0);
+        guard.before = self.log_new(pid, 1);
+        Ok(guard)
+    }
+
+    /// Hand out `len` fresh zeroed contiguous pages, latched exclusively
+    pub fn allocate_run(&self, len: u64) -> io::Result<ExclusiveRunGuard<'_, 'a, 'b, P>> {
+        let pid = self.allocate_pids(len)?;
+        self.lock_x_new(pid, len)?;
+
+        let mut guard = ExclusiveRunGuard {
+            bm: self,
+            pid,
+            len,
+            before: None,
+        };
+        guard.fill(0);
+        guard.before = self.log_new(pid, len);
+        Ok(guard)
+    }
+
+    fn allocate_pids(&self, len: u64) -> io::Result<u64> {
+        if let Some(allocator) = &self.allocator {
+            return allocator.allocate_run(len);
+        }
+
+        let pid = self.alloc_count.fetch_add(len, Ordering::Relaxed);
+        if pid + len > self.page_count() {
+            return Err(io::Errno::NOSPC);
+        }
+        Ok(pid)
+    }
+
+    /// Drop the contents of `len` pages without writing them back and return them to the
+    /// allocator
+    pub fn free_run(&self, pid: u64, len: u64) -> io::Result<()> {
+        let evicted = self.latch_x(pid, len)?;
+        let resident: Vec<u64> = (pid..pid + len)
+            .filter(|&p| !in_ranges(&evicted, p))
+            .collect();
+
+        for &p in &resident {
+            self.dirty[p as usize].store(false, Ordering::Relaxed);
+        }
+        if let Err(e) = self.free_pages(&resident) {
+            self.abort_x(pid, len, &evicted);
+            return Err(e);
+        }
+        self.release(&resident);
+        for p in pages(&evicted) {
+            let state = self.state(p);
+            let curr = PageState::from(state.load(Ordering::Acquire));
+            state.store(
+                curr.with_status(PageStatus::Evicted).into(),
+                Ordering::Release,
+            );
+        }
+
+        match &self.allocator {
+            Some(allocator) => allocator.free_run(pid, len),
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn try_transition(&self, pid: u64, curr: u64, next: PageState) -> bool {
+        self.state(pid)
+            .compare_exchange(curr, next.into(), Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Fails with `EINVAL` unless `pid..pid + len` is a non-empty run of existing pages
+    fn check_range(&self, pid: u64, len: u64) -> io::Result<()> {
+        match pid.checked_add(len) {
+            Some(end) if len > 0 && end <= self.page_count() => Ok(()),
+            _ => Err(io::Errno::INVAL),
+        }
+    }
+
+    /// Latch a run exclusively in ascending page order, so runs cannot deadlock each other.
+    /// Returns the evicted stretches of the run, which still need to be mapped.
+    fn latch_x(&self, pid: u64, len: u64) -> io::Result<Vec<(u64, u64)>> {
+        self.check_range(pid, len)?;
+
+        let mut evicted = Vec::new();
+        for p in pid..pid + len {
+            loop {
+                let curr = self.state(p).load(Ordering::Acquire);
+                let page_state = PageState::from(curr);
+                match page_state.status() {
+                    PageStatus::Evicted => {
+                        let next = page_state.with_status(PageStatus::Locked);
+                        if self.try_transition(p, curr, next) {
+                            push_page(&mut evicted, p);
+                            break;
+                        }
+                    }
+                    PageStatus::Unlocked | PageStatus::Marked => {
+                        let next = page_state.with_status(PageStatus::Locked);
+                        if self.try_transition(p, curr, next) {
+                            break;
+                        }
+                    }
+                    _ => thread::yield_now(),
+                }
+            }
+        }
+
+        Ok(evicted)
+    }
+
+    /// Undo [`latch_x`](Self::latch_x) when the evicted stretches could not be mapped
+    fn abort_x(&self, pid: u64, len: u64, evicted: &[(u64, u64)]) {
+        for p in pid..pid + len {
+            if in_ranges(evicted, p) {
+                let state = self.state(p);
+                let curr = PageState::from(state.load(Ordering::Acquire));
+                state.store(
+                    curr.with_status(PageStatus::Evicted).into(),
+                    Ordering::Release,
+                );
+            } else {
+                self.unfix_x(p);
+            }
+        }
+    }
+
+    fn lock_x(&self, pid: u64, len: u64) -> Result<()> {
+        let evicted = self.latch_x(pid, len)?;
+        if let Err(e) = self.fault(&evicted) {
+            self.abort_x(pid, len, &evicted);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Latch pages that are about to be overwritten, mapping them without reading
+    fn lock_x_new(&self, pid: u64, len: u64) -> io::Result<()> {
+        let evicted = self.latch_x(pid, len)?;
+        if let Err(e) = self.map(&evicted, false) {
+            self.abort_x(pid, len, &evicted);
+            return Err(e);
+        }
+
+        for p in pid..pid + len {
+            self.dirty[p as usize].store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn lock_s(&self, pid: u64, len: u64) -> Result<()> {
+        self.check_range(pid, len)?;
+
+        // Evicted pages are latched exclusively, faulted in, then downgraded
+        let mut evicted = Vec::new();
+        for p in pid..pid + len {
+            loop {
+                let curr = self.state(p).load(Ordering::Acquire);
+                let page_state = PageState::from(curr);
+                let next = match page_state.status() {
+                    PageStatus::Evicted => page_state.with_status(PageStatus::Locked),
+                    PageStatus::Unlocked | PageStatus::Marked => {
+                        page_state.with_status(PageStatus::LockedShared(1))
+                    }
+                    PageStatus::LockedShared(n) if n < PageStatus::MAX_SHARED => {
+                        page_state.with_status(PageStatus::LockedShared(n + 1))
+                    }
+                    _ => {
+                        thread::yield_now();
+                        continue;
+                    }
+                };
+
+                if self.try_transition(p, curr, next) {
+                    if next.status() == PageStatus::Locked {
+                        push_page(&mut evicted, p);
+                    }
+                    break;
+                }
+            }
+        }
+
+        if let Err(e) = self.fault(&evicted) {
+            for p in pid..pid + len {
+                if in_ranges(&evicted, p) {
+                    let state = self.state(p);
+                    let curr = PageState::from(state.load(Ordering::Acquire));
+                    state.store(
+                        curr.with_status(PageStatus::Evicted).into(),
+                        Ordering::Release,
+                    );
+                } else {
+                    self.unfix_s(p);
+                }
+            }
+            return Err(e);
+        }
+
+        for p in pages(&evicted) {
+            let state = self.state(p);
+            let curr = PageState::from(state.load(Ordering::Acquire));
+            state.store(
+                curr.with_status(PageStatus::LockedShared(1)).into(),
+                Ordering::Release,
+            );
+        }
+
+        Ok(())
+    }
+
+    fn unfix_x(&self, pid: u64) {
+        let state = self.state(pid);
+        let curr = PageState::from(state.load(Ordering::Acquire));
+        debug_assert_eq!(curr.status(), PageStatus::Locked);
+        state.store(curr.bump(PageStatus::Unlocked).into(), Ordering::Release);
+    }
+
+    fn unfix_s(&self, pid: u64) {
+        let state = self.state(pid);
+        loop {
+            let curr = state.load(Ordering::Acquire);
+            let page_state = PageState::from(curr);
+            let next = match page_state.status() {
+                PageStatus::LockedShared(1) => page_state.with_status(PageStatus::Unlocked),
+                PageStatus::LockedShared(n) => {
+                    page_state.with_status(PageStatus::LockedShared(n - 1))
+                }
+                s => unreachable!("unfix_s on page {pid} in state {s:?}"),
+            };
+            if self.try_transition(pid, curr, next) {
+                return;
+            }
+        }
+    }
+
+    #[inline]
+    fn page(&self, pid: u64) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.page_ptr(pid), P) }
+    }
+
+    /// Caller must hold the exclusive latch
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    fn page_mut(&self, pid: u64) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.page_ptr(pid), P) }
+    }
+
+    /// The stretches are latched exclusively and evicted
+    fn fault(&self, ranges: &[(u64, u64)]) -> Result<()> {
+        match &self.store {
+            Some(store) => {
+                self.map(ranges, false)?;
+                for pid in pages(ranges) {
+                    if let Err(e) = store.read_page(pid, self.page_mut(pid)) {
+                        self.unmap(ranges)?;
+                        return Err(e.into());
+                    }
+                }
+            }
+            None => self.map(ranges, self.mem.backing_fd.is_some())?,
+        }
+
+        // Without a backing fd nothing was read, so there is nothing to verify
+        let read = self.store.is_some() || self.mem.backing_fd.is_some();
+        if self.checksums && read {
+            for pid in pages(ranges) {
+                if let Err((stored, computed)) = checksum::verify(self.page(pid)) {
+                    self.unmap(ranges)?;
+                    return Err(Error::Corrupt {
+                        pid,
+                        stored,
+                        computed,
+                    });
+                }
+            }
+        }
+
+        let count: u64 = ranges.iter().map(|&(_, len)| len).sum();
+        self.faults.fetch_add(count, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Give pages back to exmap that never became visible
+    fn unmap(&self, ranges: &[(u64, u64)]) -> io::Result<()> {
+        self.free_pages(&pages(ranges).collect::<Vec<_>>())?;
+        for pid in pages(ranges) {
+            self.resident.remove(pid);
+        }
+        self.physical
+            .fetch_sub(pages(ranges).count(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn free_pages(&self, pids: &[u64]) -> io::Result<()> {
+        if pids.is_empty() {
+            return Ok(());
+        }
+        self.with_interface(|interface| interface.free_pages(pids).map(|i| (i, ())))
+    }
+
+    /// Map exclusively latched and evicted stretches, filling them from the backing fd if
+    /// `read`. All iovs go out in one ioctl unless there are more than an interface holds.
+    fn map(&self, ranges: &[(u64, u64)], read: bool) -> io::Result<()> {
+        let count = pages(ranges).count();
+        if count == 0 {
+            return Ok(());
+        }
+        self.ensure_free(count)?;
+
+        let max_count = InterfaceWrapper::<InterfaceIov>::MAX_COUNT;
+        for (i, chunk) in ranges.chunks(max_count).enumerate() {
+            let outcome = self.with_interface(|mut interface| {
+                for &(pid, len) in chunk {
+                    interface
+                        .push(pid, len)
+                        .expect("chunk fits in the interface");
+                }
+                let mut evictor = Evictor { bm: self };
+                if read {
+                    interface.read_retry(FAULT_ATTEMPTS, &mut evictor)
+                } else {
+                    interface.alloc_retry(FAULT_ATTEMPTS, &mut evictor)
+                }
+            })?;
+
+            if let Some(failure) = outcome.failed.first() {
+                // Give back the stretches that made it in
+                let mapped = &ranges[..i * max_count + chunk.len()];
+                self.free_pages(&pages(mapped).collect::<Vec<_>>())?;
+                return Err(failure.errno());
+            }
+        }
+
+        for pid in pages(ranges) {
+            self.resident.insert(pid);
+        }
+        self.physical.fetch_add(count, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Evict until there is room for `pages` more pages within the budget
+    fn ensure_free(&self, pages: usize) -> io::Result<()> {
+        while self.resident() + pages > self.budget() {
+            if self.evict(EVICT_BATCH.max(pages))? == 0 {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unmap the interfaces and give back the mapping, e.g. to [`unmap`](VirtMem::unmap) it.
+    /// Dirty pages that were not [`flush`](Self::flush)ed are lost. On error the mapping is
+    /// unmapped as well.
+    pub fn into_mem(self) -> io::Result<VirtMem<'a, 'b, P>> {
+        let mut res = Ok(());
+        for slot in self.interfaces.into_vec() {
+            if let Some(interface) = take_slot(slot) {
+                res = res.and(interface.unmap());
+            }
+        }
+
+        match res {
+            Ok(()) => Ok(self.mem),
+            Err(e) => {
+                let _ = self.mem.unmap();
+                Err(e)
+            }
+        }
+    }
+
+    /// Unmap the interfaces and the mapping. Dirty pages that were not
+    /// [`flush`](Self::flush)ed are lost.
+    pub fn close(self) -> io::Result<()> {
+        self.into_mem()?.unmap()
+    }
+
+    /// Write back every dirty page and make it durable, without evicting anything. Returns the
+    /// number of pages written. The caller must not hold an exclusive guard. With a [`Wal`],
+    /// pages with uncommitted changes stay dirty.
+    pub fn flush(&self) -> Result<usize> {
+        let mut written = 0;
+        for pid in 0..self.page_count() {
+            if !self.dirty[pid as usize].load(Ordering::Acquire) {
+                continue;
+            }
+            // Keeps writers out while the page is written
+            let _guard = self.fix_s(pid)?;
+            if !self.committed(pid) {
+                continue;
+            }
+            self.write_back(pid)?;
+            written += 1;
+        }
+
+        match (&self.store, self.mem.backing_fd) {
+            (Some(store), _) => store.sync()?,
+            (None, Some(fd)) => fs::fdatasync(fd)?,
+            (None, None) => {}
+        }
+        Ok(written)
+    }
+
+    /// Run an eviction round for up to `pages` pages. Returns the number of pages evicted.
+    pub fn evict(&self, pages: usize) -> io::Result<usize> {
+        let victims = self.select_victims(pages)?;
+        if victims.is_empty() {
+            return Ok(0);
+        }
+
+        if let Err(e) = self.free_pages(&victims) {
+            self.keep(&victims);
+            return Err(e);
+        }
+        self.release(&victims);
+        Ok(victims.len())
+    }
+
+    /// Second chance clock. Returns exclusively latched and written back pages.
+    fn select_victims(&self, pages: usize) -> io::Result<Vec<u64>> {
+        let mut victims = Vec::with_capacity(pages);
+
+        // Two passes over the clock are enough to see every page marked
+        let mut budget = 2 * self.resident.capacity();
+        while victims.len() < pages && budget > 0 {
+            let batch = EVICT_BATCH.min(budget);
+            budget -= batch;
+
+            self.resident.clock_batch(batch, |pid| {
+                if victims.len() >= pages {
+                    return;
+                }
+
+                let curr = self.state(pid).load(Ordering::Acquire);
+                let page_state = PageState::from(curr);
+                match page_state.status() {
+                    PageStatus::Marked => {
+                        let next = page_state.with_status(PageStatus::Locked);
+                        if !self.try_transition(pid, curr, next) {
+                            return;
+                        }
+                        if self.committed(pid) {
+                            victims.push(pid);
+                        } else {
+                            // No steal: the backing file only sees committed changes
+                            self.state(pid).store(curr, Ordering::Release);
+                        }
+                    }
+                    PageStatus::Unlocked => {
+                        let next = page_state.with_status(PageStatus::Marked);
+                        let _ = self.try_transition(pid, curr, next);
+                    }
+                    _ => {}
+                }
+            });
+        }
+
+        // Victims stay latched so nobody touches them between the write back and the free
+        for &pid in &victims {
+            if let Err(e) = self.write_back(pid) {
+                self.keep(&victims);
+                return Err(e);
+            }
+        }
+
+        Ok(victims)
+    }
+
+    /// Write the page back to the store or backing fd if it is dirty
+    fn write_back(&self, pid: u64) -> io::Result<()> {
+        if !self.dirty[pid as usize].swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        // Readers may hold the page shared, so the checksum only goes into the written copy
+        let mut page = Cow::Borrowed(self.page(pid));
+        if self.checksums {
+            checksum::stamp(page.to_mut());
+        }
+
+        let res = match (&self.store, self.mem.backing_fd) {
+            (Some(store), _) => store.write_page(pid, &page),
+            (None, Some(fd)) => fileio::pwrite_all(fd, &page, pid * P as u64),
+            (None, None) => Ok(()),
+        };
+
+        if res.is_err() {
+            self.dirty[pid as usize].store(true, Ordering::Release);
+        }
+        res
+    }
+
+    /// Commit the [`Wal`], write back every dirty page and start a new log. Nothing may log
+    /// changes while this runs, so no exclusive guard may be held or taken.
+    pub fn checkpoint(&self) -> Result<usize> {
+        let wal = self.wal.as_ref().ok_or(io::Errno::INVAL)?;
+        wal.commit()?;
+        let written = self.flush()?;
+        wal.reset()?;
+        Ok(written)
+    }
+
+    /// Whether the page may be written back, i.e. its changes are committed in the [`Wal`].
+    /// The page must be latched.
+    fn committed(&self, pid: u64) -> bool {
+        match &self.wal {
+            None => true,
+            Some(wal) => {
+                !self.dirty[pid as usize].load(Ordering::Acquire)
+                    || wal::page_lsn(self.page(pid)) <= wal.committed()
+            }
+        }
+    }
+
+    /// Copy of the pages before an exclusive guard changes them, if changes are logged
+    fn snapshot(&self, pid: u64, len: u64) -> Option<Box<[u8]>> {
+        self.wal.as_ref()?;
+        let pages = unsafe { std::slice::from_raw_parts(self.page_ptr(pid), len as usize * P) };
+        Some(pages.into())
+    }
+
+    /// Log the whole of freshly allocated pages, whatever the backing file held for them before
+    fn log_new(&self, pid: u64, len: u64) -> Option<Box<[u8]>> {
+        let wal = self.wal.as_ref()?;
+        for pid in pid..pid + len {
+            let page = self.page_mut(pid);
+            let lsn = wal.log_update(pid, checksum::HEADER_SIZE, &page[checksum::HEADER_SIZE..]);
+            wal::set_page_lsn(page, lsn);
+        }
+        self.snapshot(pid, len)
+    }
+
+    /// Log what an exclusive guard changed since `before` was taken and stamp the page LSNs
+    fn log_changes(&self, pid: u64, before: &[u8]) {
+        let Some(wal) = &self.wal else {
+            return;
+        };
+        for (i, before) in before.chunks_exact(P).enumerate() {
+            let pid = pid + i as u64;
+            let page = self.page_mut(pid);
+            if let Some(range) = wal::changed(before, page) {
+                let lsn = wal.log_update(pid, range.start, &page[range]);
+                wal::set_page_lsn(page, lsn);
+            }
+        }
+    }
+
+    /// Pages have been freed to exmap, mark them evicted
+    fn release(&self, pids: &[u64]) {
+        for &pid in pids {
+            self.resident.remove(pid);
+            let state = self.state(pid);
+            let curr = PageState::from(state.load(Ordering::Acquire));
+            state.store(curr.bump(PageStatus::Evicted).into(), Ordering::Release);
+        }
+        self.physical.fetch_sub(pids.len(), Ordering::Relaxed);
+    }
+
+    /// Victims that could not be written back or freed stay resident, unlatch them
+    fn keep(&self, pids: &[u64]) {
+        for &pid in pids {
+            self.unfix_x(pid);
+        }
+    }
+
+    /// Run `f` with this thread's interface. Threads are spread over the interfaces and fall back
+    /// to whichever one is free.
+    fn with_interface<T>(
+        &self,
+        f: impl FnOnce(
+            InterfaceWrapper<'a, InterfaceIov>,
+        ) -> OpResult<'a, (InterfaceWrapper<'a, InterfaceIov>, T)>,
+    ) -> io::Result<T> {
+        let mut slot = self.lock_interface();
+        // Only empty if `f` panicked while it had the interface
+        let interface = slot.take().ok_or(io::Errno::BADF)?;
+        let (interface, res) = match f(interface) {
+            Ok((interface, res)) => (interface, Ok(res)),
+            Err((interface, e)) => (interface, Err(e)),
+        };
+        *slot = Some(interface);
+        res
+    }
+
+    fn lock_interface(&self) -> MutexGuard<'_, Option<InterfaceWrapper<'a, InterfaceIov>>> {
+        let n = self.interfaces.len();
+        let start = WORKER.with(|w| match w.get() {
+            Some(i) => i,
+            None => {
+                let i = NEXT_WORKER.fetch_add(1, Ordering::Relaxed);
+                w.set(Some(i));
+                i
+            }
+        }) % n;
+
+        for i in 0..n {
+            if let Ok(slot) = self.interfaces[(start + i) % n].try_lock() {
+                return slot;
+            }
+        }
+
+        self.interfaces[start]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn take_slot(slot: InterfaceSlot<'_>) -> Option<InterfaceWrapper<'_, InterfaceIov>> {
+    slot.into_inner().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Largest stretch a single iov can describe
+const MAX_IOV_PAGES: u64 = sys::EXMAP_PAGE_MAX_PAGES as u64 - 1;
+
+/// Add `pid` to a list of stretches, extending the last one if it is adjacent
+fn push_page(ranges: &mut Vec<(u64, u64)>, pid: u64) {
+    match ranges.last_mut() {
+        Some((start, len)) if *start + *len == pid && *len < MAX_IOV_PAGES => *len += 1,
+        _ => ranges.push((pid, 1)),
+    }
+}
+
+fn in_ranges(ranges: &[(u64, u64)], pid: u64) -> bool {
+    ranges
+        .iter()
+        .any(|&(start, len)| (start..start + len).contains(&pid))
+}
+
+fn pages(ranges: &[(u64, u64)]) -> impl Iterator<Item = u64> + '_ {
+    ranges.iter().flat_map(|&(start, len)| start..start + len)
+}
+
+/// Evicts on behalf of a fault that ran out of exmap memory
+struct Evictor<'bm, 'a, 'b, const P: usize> {
+    bm: &'bm BufferManager<'a, 'b, P>,
+}
+
+impl<'bm, 'a, 'b, const P: usize> Evict for Evictor<'bm, 'a, 'b, P> {
+    fn select(&mut self, pages: usize) -> io::Result<Vec<u64>> {
+        self.bm.select_victims(pages.max(EVICT_BATCH))
+    }
+
+    fn released(&mut self, pages: &[u64]) {
+        self.bm.release(pages)
+    }
+
+    fn kept(&mut self, pages: &[u64]) {
+        self.bm.keep(pages)
+    }
+}
+
+impl<'a, 'b, const P: usize> BufferPool<P> for BufferManager<'a, 'b, P> {
+    type Exclusive<'g>
+        = ExclusiveGuard<'g, 'a, 'b, P>
+    where
+        Self: 'g;
+    type Shared<'g>
+        = SharedGuard<'g, 'a, 'b, P>
+    where
+        Self: 'g;
+
+    fn fix_x(&self, pid: u64) -> Result<Self::Exclusive<'_>> {
+        BufferManager::fix_x(self, pid)
+    }
+
+    fn fix_s(&self, pid: u64) -> Result<Self::Shared<'_>> {
+        BufferManager::fix_s(self, pid)
+    }
+
+    fn flush(&self) -> Result<usize> {
+        BufferManager::flush(self)
+    }
+
+    fn faults(&self) -> u64 {
+        BufferManager::faults(self)
+    }
+}
+
+/// An exclusively latched page. Mutable access marks the page dirty.
+pub struct ExclusiveGuard<'bm, 'a, 'b, const P: usize> {
+    bm: &'bm BufferManager<'a, 'b, P>,
+    pid: u64,
+    /// The page before the first mutable access, to log the changes on drop
+    before: Option<Box<[u8]>>,
+}
+
+impl<'bm, 'a, 'b, const P: usize> ExclusiveGuard<'bm, 'a, 'b, P> {
+    #[inline]
+    pub fn pid(&self) -> u64 {
+        self.pid
+    }
+}
+
+impl<'bm, 'a, 'b, const P: usize> Deref for ExclusiveGuard<'bm, 'a, 'b, P> {
+    type Target = [u8; P];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*(self.bm.page_ptr(self.pid) as *const [u8; P]) }
+    }
+}
+
+impl<'bm, 'a, 'b, const P: usize> DerefMut for ExclusiveGuard<'bm, 'a, 'b, P> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        if self.before.is_none() {
+            self.before = self.bm.snapshot(self.pid, 1);
+        }
+        self.bm.dirty[self.pid as usize].store(true, Ordering::Relaxed);
+        unsafe { &mut *(self.bm.page_ptr(self.pid) as *mut [u8; P]) }
+    }
+}
+
+impl<'bm, 'a, 'b, const P: usize> Drop for ExclusiveGuard<'bm, 'a, 'b, P> {
+    fn drop(&mut self) {
+        if let Some(before) = &self.before {
+            self.bm.log_changes(self.pid, before);
+        }
+        self.bm.unfix_x(self.pid)
+    }
+}
+
+/// A page latched in shared mode
+pub struct SharedGuard<'bm, 'a, 'b, const P: usize> {
+    bm: &'bm BufferManager<'a, 'b, P>,
+    pid: u64,
+}
+
+impl<'bm, 'a, 'b, const P: usize> SharedGuard<'bm, 'a, 'b, P> {
+    #[inline]
+    pub fn pid(&self) -> u64 {
+        self.pid
+    }
+}
+
+impl<'bm, 'a, 'b, const P: usize> Deref for SharedGuard<'bm, 'a, 'b, P> {
+    type Target = [u8; P];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*(self.bm.page_ptr(self.pid) as *const [u8; P]) }
+    }
+}
+
+impl<'bm, 'a, 'b, const P: usize> Drop for SharedGuard<'bm, 'a, 'b, P> {
+    fn drop(&mut self) {
+        self.bm.unfix_s(self.pid)
+    }
+}
+
+/// A page read without a latch. Anything read through it may be torn by a concurrent writer
+/// and only counts once [`validate`](Self::validate) succeeds afterwards.
+pub struct OptimisticGuard<'bm, 'a, 'b, const P: usize> {
+    bm: &'bm BufferManager<'a, 'b, P>,
+    pid: u64,
+    version: u64,
+}
+
+impl<'bm, 'a, 'b, const P: usize> OptimisticGuard<'bm, 'a, 'b, P> {
+    #[inline]
+    pub fn pid(&self) -> u64 {
+        self.pid
+    }
+
+    /// Copy the page out. The copy is consistent if [`validate`](Self::validate) succeeds.
+    pub fn copy_to(&self, buf: &mut [u8]) {
+        assert_eq!(buf.len(), P);
+        unsafe { std::ptr::copy_nonoverlapping(self.bm.page_ptr(self.pid), buf.as_mut_ptr(), P) }
+    }
+
+    /// Whether the page is unchanged since the guard was taken
+    pub fn validate(&self) -> bool {
+        fence(Ordering::Acquire);
+        let curr = PageState::from(self.bm.state(self.pid).load(Ordering::Acquire));
+        curr.version() == self.version && curr.status() != PageStatus::Locked
+    }
+
+    /// Latch the page exclusively if it is unchanged since the guard was taken
+    pub fn upgrade(self) -> Option<ExclusiveGuard<'bm, 'a, 'b, P>> {
+        let curr = self.bm.state(self.pid).load(Ordering::Acquire);
+        let page_state = PageState::from(curr);
+        if page_state.version() != self.version {
+            return None;
+        }
+
+        match page_state.status() {
+            PageStatus::Unlocked | PageStatus::Marked => {
+                let next = page_state.with_status(PageStatus::Locked);
+                if !self.bm.try_transition(self.pid, curr, next) {
+                    return None;
+                }
+                Some(ExclusiveGuard {
+                    bm: self.bm,
+                    pid: self.pid,
+                    before: None,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A run of contiguous pages latched exclusively, seen as one slice. Mutable access marks all
+/// of them dirty.
+pub struct ExclusiveRunGuard<'bm, 'a, 'b, const P: usize> {
+    bm: &'bm BufferManager<'a, 'b, P>,
+    pid: u64,
+    len: u64,
+    before: Option<Box<[u8]>>,
+}
+
+impl<'bm, 'a, 'b, const P: usize> ExclusiveRunGuard<'bm, 'a, 'b, P> {
+    #[inline]
+    pub fn pid(&self) -> u64 {
+        self.pid
+    }
+
+    /// Number of pages in the run
+    #[inline]
+    pub fn pages(&self) -> u64 {
+        self.len
+    }
+}
+
+impl<'bm, 'a, 'b, const P: usize> Deref for ExclusiveRunGuard<'bm, 'a, 'b, P> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { std::slice::from_raw_parts(self.bm.page_ptr(self.pid), self.len as usize * P) }
+    }
+}
+
+impl<'bm, 'a, 'b, const P: usize> DerefMut for ExclusiveRunGuard<'bm, 'a, 'b, P> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        if self.before.is_none() {
+            self.before = self.bm.snapshot(self.pid, self.len);
+        }
+        for pid in self.pid..self.pid + self.len {
+            self.bm.dirty[pid as usize].store(true, Ordering::Relaxed);
+        }
+        unsafe { std::slice::from_raw_parts_mut(self.bm.page_ptr(self.pid), self.len as usize * P) }
+    }
+}
+
+impl<'bm, 'a, 'b, const P: usize> Drop for ExclusiveRunGuard<'bm, 'a, 'b, P> {
+    fn drop(&mut self) {
+        if let Some(before) = &self.before {
+            self.bm.log_changes(self.pid, before);
+        }
+        for pid in self.pid..self.pid + self.len {
+            self.bm.unfix_x(pid)
+        }
+    }
+}
+
+/// A run of contiguous pages latched shared, seen as one slice
+pub struct SharedRunGuard<'bm, 'a, 'b, const P: usize> {
+    bm: &'bm BufferManager<'a, 'b, P>,
+    pid: u64,
+    len: u64,
+}
+
+impl<'bm, 'a, 'b, const P: usize> SharedRunGuard<'bm, 'a, 'b, P> {
+    #[inline]
+    pub fn pid(&self) -> u64 {
+        self.pid
+    }
+
+    /// Number of pages in the run
+    #[inline]
+    pub fn pages(&self) -> u64 {
+        self.len
+    }
+}
+
+impl<'bm, 'a, 'b, const P: usize> Deref for SharedRunGuard<'bm, 'a, 'b, P> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { std::slice::from_raw_parts(self.bm.page_ptr(self.pid), self.len as usize * P) }
+    }
+}
+
+impl<'bm, 'a, 'b, const P: usize> Drop for SharedRunGuard<'bm, 'a, 'b, P> {
+    fn drop(&mut self) {
+        for pid in self.pid..self.pid + self.len {
+            self.bm.unfix_s(pid)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_state_roundtrip() {
+        let mut state = PageState::new(0, PageStatus::Evicted);
+        assert_eq!(state.status(), PageStatus::Evicted);
+
+        state.set_version(0xAB_CDEF_0123);
+        state.set_status(PageStatus::LockedShared(3));
+        let raw: u64 = state.into();
+        let state = PageState::from(raw);
+        assert_eq!(state.version(), 0xAB_CDEF_0123);
+        assert_eq!(state.status(), PageStatus::LockedShared(3));
+
+        let max = PageState::new((1 << 56) - 1, PageStatus::Locked);
+        assert_eq!(max.bump(PageStatus::Unlocked).version(), 0);
+    }
+
+    #[test]
+    fn resident_set_clock() {
+        let set = ResidentSet::new(100);
+        for pid in 0..100 {
+            set.insert(pid);
+        }
+        assert!(set.remove(42));
+        assert!(!set.remove(42));
+        assert!(!set.remove(1000));
+
+        let mut seen = Vec::new();
+        set.clock_batch(set.capacity(), |pid| seen.push(pid));
+        seen.sort();
+        let expected: Vec<u64> = (0..100).filter(|&p| p != 42).collect();
+        assert_eq!(seen, expected);
+
+        // The tombstone is reused
+        set.insert(42);
+        let mut count = 0;
+        set.clock_batch(set.capacity(), |_| count += 1);
+        assert_eq!(count, 100);
+    }
+
+    #[test]
+    fn stretches() {
+        let mut ranges = Vec::new();
+        for pid in [1, 2, 3, 7, 8, 20] {
+            push_page(&mut ranges, pid);
+        }
+        assert_eq!(ranges, vec![(1, 3), (7, 2), (20, 1)]);
+        assert!(in_ranges(&ranges, 8));
+        assert!(!in_ranges(&ranges, 9));
+        assert_eq!(pages(&ranges).count(), 6);
+
+        let mut long = Vec::new();
+        for pid in 0..MAX_IOV_PAGES + 1 {
+            push_page(&mut long, pid);
+        }
+        assert_eq!(long, vec![(0, MAX_IOV_PAGES), (MAX_IOV_PAGES, 1)]);
+    }
+}
@@ -0,0 +1,608 @@
+//! B+tree with optimistic latch coupling over buffer manager pages.
+//!
+//! Every node is a page of the [`BufferManager`]. Readers descend without latching anything:
+//! they copy a node, check its version is unchanged and move on to the child. Writers upgrade
+//! the leaf they found to an exclusive latch, and on a full node split it top-down with the
+//! parent latched as well, restarting whenever a version changed underneath them.
+//!
+//! A meta page holds the page id of the root, so splitting the root only changes the meta page.
+//! Nodes are not merged when entries are removed.
+//!
+//! ```text
+//! 0          16      18      20     22     24       32
+//! +----------+-------+-------+------+------+--------+----------------+-------+--------------+
+//! | checksum | level | count | heap | dead | upper  | slots ...      | free  | records ...  |
+//! +----------+-------+-------+------+------+--------+----------------+-------+--------------+
+//! ```
+//!
+//! Slots are sorted by key and point at a record of key and value in the heap at the end of the
+//! page. Inner nodes store child page ids as values; `upper` is the child for keys greater than
+//! every key of the node. Leaves are level 0.
+
+use std::ops::Bound;
+
+use rustix::io;
+
+use crate::{
+    bm::{BufferManager, OptimisticGuard},
+    checksum, Result,
+};
+
+/// Node header, after the checksum header every page starts with
+const BASE: usize = checksum::HEADER_SIZE;
+const LEVEL: usize = BASE;
+const COUNT: usize = BASE + 2;
+const HEAP: usize = BASE + 4;
+const DEAD: usize = BASE + 6;
+const UPPER: usize = BASE + 8;
+const SLOTS: usize = BASE + 16;
+
+/// Record offset, key length and value length
+const SLOT_SIZE: usize = 6;
+
+/// Where the meta page keeps the root page id
+const ROOT: usize = BASE;
+
+pub struct BTree<'bm, 'a, 'b, const P: usize> {
+    bm: &'bm BufferManager<'a, 'b, P>,
+    meta: u64,
+}
+
+/// Result of an optimistic descent
+struct Descent<'bm, 'a, 'b, const P: usize> {
+    parent: OptimisticGuard<'bm, 'a, 'b, P>,
+    node: OptimisticGuard<'bm, 'a, 'b, P>,
+    /// Keys of the node are at most the fence. `None` for the rightmost node of a level.
+    fence: Option<Vec<u8>>,
+}
+
+impl<'bm, 'a, 'b, const P: usize> BTree<'bm, 'a, 'b, P> {
+    /// Largest key plus value that can be stored. A node always holds at least four entries.
+    pub const MAX_ENTRY: usize = (P - SLOTS) / 4 - SLOT_SIZE;
+
+    /// Allocate a meta page and an empty root
+    pub fn create(bm: &'bm BufferManager<'a, 'b, P>) -> Result<Self> {
+        assert!(P < u16::MAX as usize, "page offsets are 16 bit");
+
+        let mut meta = bm.allocate_page()?;
+        let mut root = bm.allocate_page()?;
+        node::init(&mut root[..], 0, 0);
+        write_u64(&mut meta[..], ROOT, root.pid());
+
+        Ok(Self {
+            bm,
+            meta: meta.pid(),
+        })
+    }
+
+    /// Open the tree whose meta page is `meta`, as returned by [`meta`](Self::meta)
+    pub fn open(bm: &'bm BufferManager<'a, 'b, P>, meta: u64) -> Self {
+        Self { bm, meta }
+    }
+
+    pub fn meta(&self) -> u64 {
+        self.meta
+    }
+
+    pub fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut buf = vec![0; P];
+        loop {
+            if self.descend(key, false, 0, &mut buf)?.is_some() {
+                return Ok(node::search(&buf, key)
+                    .ok()
+                    .map(|i| node::value(&buf, i).to_vec()));
+            }
+        }
+    }
+
+    /// Insert `key` or replace its value. Fails with `EINVAL` if the entry is larger than
+    /// [`MAX_ENTRY`](Self::MAX_ENTRY).
+    pub fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        // The key must also fit into inner nodes as a separator
+        if key.len() + value.len().max(8) > Self::MAX_ENTRY {
+            return Err(io::Errno::INVAL.into());
+        }
+
+        let mut buf = vec![0; P];
+        loop {
+            let Some(descent) = self.descend(key, false, 0, &mut buf)? else {
+                continue;
+            };
+            let Some(mut leaf) = descent.node.upgrade() else {
+                continue;
+            };
+            if node::upsert(&mut leaf[..], key, value) {
+                return Ok(());
+            }
+
+            drop(leaf);
+            self.split(key, 0, key.len() + value.len())?;
+        }
+    }
+
+    /// Remove `key`, returning whether it was present
+    pub fn remove(&self, key: &[u8]) -> Result<bool> {
+        let mut buf = vec![0; P];
+        loop {
+            let Some(descent) = self.descend(key, false, 0, &mut buf)? else {
+                continue;
+            };
+            let Ok(i) = node::search(&buf, key) else {
+                return Ok(false);
+            };
+            // Unchanged since the copy if the upgrade succeeds, so `i` still holds
+            if let Some(mut leaf) = descent.node.upgrade() {
+                node::remove(&mut leaf[..], i);
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Call `f` with the entries from `start` on in key order until it returns `false`. No
+    /// latch is held while `f` runs.
+    pub fn scan(&self, start: Bound<&[u8]>, mut f: impl FnMut(&[u8], &[u8]) -> bool) -> Result<()> {
+        let mut start = to_owned(start);
+        let mut buf = vec![0; P];
+        loop {
+            let (key, excluded) = match &start {
+                Bound::Included(key) => (key.as_slice(), false),
+                Bound::Excluded(key) => (key.as_slice(), true),
+                Bound::Unbounded => (&[][..], false),
+            };
+            let Some(descent) = self.descend(key, excluded, 0, &mut buf)? else {
+                continue;
+            };
+
+            let first = match node::search(&buf, key) {
+                Ok(i) if excluded => i + 1,
+                Ok(i) | Err(i) => i,
+            };
+            for i in first..node::count(&buf) {
+                if !f(node::key(&buf, i), node::value(&buf, i)) {
+                    return Ok(());
+                }
+            }
+
+            match descent.fence {
+                Some(fence) => start = Bound::Excluded(fence),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Descend to the node at `level` on the path of `key`, leaving a validated copy of it in
+    /// `buf`. With `excluded` the path leads to keys greater than `key`. `None` means a
+    /// version changed and the caller restarts.
+    fn descend(
+        &self,
+        key: &[u8],
+        excluded: bool,
+        level: u16,
+        buf: &mut [u8],
+    ) -> Result<Option<Descent<'bm, 'a, 'b, P>>> {
+        let mut parent = self.bm.fix_o(self.meta)?;
+        parent.copy_to(buf);
+        let root = read_u64(buf, ROOT);
+        if !parent.validate() {
+            return Ok(None);
+        }
+
+        let mut node = self.bm.fix_o(root)?;
+        let mut fence = None;
+        loop {
+            node.copy_to(buf);
+            if !node.validate() || !parent.validate() {
+                return Ok(None);
+            }
+
+            let node_level = node::level(buf);
+            if node_level == level {
+                return Ok(Some(Descent {
+                    parent,
+                    node,
+                    fence,
+                }));
+            } else if node_level < level {
+                return Ok(None);
+            }
+
+            let (child, slot) = node::route(buf, key, excluded);
+            if let Some(i) = slot {
+                fence = Some(node::key(buf, i).to_vec());
+            }
+            let next = self.bm.fix_o(child)?;
+            parent = node;
+            node = next;
+        }
+    }
+
+    /// Make room for an entry of `needed` bytes in the node at `level` on the path of `key`.
+    /// The lower half moves to a new left sibling, the node keeps the upper half.
+    fn split(&self, key: &[u8], level: u16, needed: usize) -> Result<()> {
+        let mut buf = vec![0; P];
+        loop {
+            let Some(descent) = self.descend(key, false, level, &mut buf)? else {
+                continue;
+            };
+            // Someone else split it in the meantime
+            if node::space(&buf) >= needed + SLOT_SIZE {
+                return Ok(());
+            }
+
+            let Some(mut parent) = descent.parent.upgrade() else {
+                continue;
+            };
+            let Some(mut node) = descent.node.upgrade() else {
+                continue;
+            };
+
+            if parent.pid() == self.meta {
+                let mut left = self.bm.allocate_page()?;
+                let mut root = self.bm.allocate_page()?;
+                let separator = node::split(&mut node[..], &mut left[..]);
+                node::init(&mut root[..], level + 1, node.pid());
+                node::insert(&mut root[..], 0, &separator, &left.pid().to_le_bytes());
+                write_u64(&mut parent[..], ROOT, root.pid());
+                return Ok(());
+            }
+
+            let separator_len = node::separator_len(&node[..]);
+            if node::space(&parent[..]) >= separator_len + 8 + SLOT_SIZE {
+                let mut left = self.bm.allocate_page()?;
+                let separator = node::split(&mut node[..], &mut left[..]);
+                let i = node::search(&parent[..], &separator).unwrap_or_else(|i| i);
+                node::insert(&mut parent[..], i, &separator, &left.pid().to_le_bytes());
+                return Ok(());
+            }
+
+            drop(node);
+            drop(parent);
+            self.split(key, level + 1, separator_len + 8)?;
+        }
+    }
+}
+
+/// Copy the key of `bound`
+pub(crate) fn to_owned(bound: Bound<&[u8]>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn read_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+fn write_u64(buf: &mut [u8], at: usize, v: u64) {
+    buf[at..at + 8].copy_from_slice(&v.to_le_bytes());
+}
+
+/// Node layout on a page buffer
+mod node {
+    use std::cmp::Ordering;
+
+    use super::*;
+
+    fn read_u16(buf: &[u8], at: usize) -> usize {
+        u16::from_le_bytes(buf[at..at + 2].try_into().unwrap()).into()
+    }
+
+    fn write_u16(buf: &mut [u8], at: usize, v: usize) {
+        buf[at..at + 2].copy_from_slice(&(v as u16).to_le_bytes());
+    }
+
+    pub fn init(buf: &mut [u8], level: u16, upper: u64) {
+        write_u16(buf, LEVEL, level.into());
+        write_u16(buf, COUNT, 0);
+        write_u16(buf, HEAP, buf.len());
+        write_u16(buf, DEAD, 0);
+        write_u64(buf, UPPER, upper);
+    }
+
+    pub fn level(buf: &[u8]) -> u16 {
+        read_u16(buf, LEVEL) as u16
+    }
+
+    pub fn count(buf: &[u8]) -> usize {
+        read_u16(buf, COUNT)
+    }
+
+    fn upper(buf: &[u8]) -> u64 {
+        read_u64(buf, UPPER)
+    }
+
+    fn slot(buf: &[u8], i: usize) -> (usize, usize, usize) {
+        let at = SLOTS + i * SLOT_SIZE;
+        (
+            read_u16(buf, at),
+            read_u16(buf, at + 2),
+            read_u16(buf, at + 4),
+        )
+    }
+
+    pub fn key(buf: &[u8], i: usize) -> &[u8] {
+        let (offset, key_len, _) = slot(buf, i);
+        &buf[offset..offset + key_len]
+    }
+
+    pub fn value(buf: &[u8], i: usize) -> &[u8] {
+        let (offset, key_len, value_len) = slot(buf, i);
+        &buf[offset + key_len..offset + key_len + value_len]
+    }
+
+    fn child(buf: &[u8], i: usize) -> u64 {
+        read_u64(value(buf, i), 0)
+    }
+
+    /// Bytes available for new records and slots, counting space freed by removals
+    pub fn space(buf: &[u8]) -> usize {
+        read_u16(buf, HEAP) - (SLOTS + count(buf) * SLOT_SIZE) + read_u16(buf, DEAD)
+    }
+
+    /// `Ok` with the slot of `key`, or `Err` with where it would be inserted
+    pub fn search(buf: &[u8], key: &[u8]) -> Result<usize, usize> {
+        let (mut lo, mut hi) = (0, count(buf));
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self::key(buf, mid).cmp(key) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(lo)
+    }
+
+    /// The child of an inner node to follow for `key`, and the slot it came from unless it
+    /// is `upper`. With `excluded` the child for keys greater than `key` is chosen.
+    pub fn route(buf: &[u8], key: &[u8], excluded: bool) -> (u64, Option<usize>) {
+        let i = match search(buf, key) {
+            Ok(i) if excluded => i + 1,
+            Ok(i) | Err(i) => i,
+        };
+        if i < count(buf) {
+            (child(buf, i), Some(i))
+        } else {
+            (upper(buf), None)
+        }
+    }
+
+    /// Insert a record at slot `i`. The caller checked there is [`space`] for it.
+    pub fn insert(buf: &mut [u8], i: usize, key: &[u8], value: &[u8]) {
+        let count = count(buf);
+        let len = key.len() + value.len();
+        if read_u16(buf, HEAP) - (SLOTS + (count + 1) * SLOT_SIZE) < len {
+            compact(buf);
+        }
+
+        let offset = read_u16(buf, HEAP) - len;
+        buf[offset..offset + key.len()].copy_from_slice(key);
+        buf[offset + key.len()..offset + len].copy_from_slice(value);
+        write_u16(buf, HEAP, offset);
+
+        let at = SLOTS + i * SLOT_SIZE;
+        buf.copy_within(at..SLOTS + count * SLOT_SIZE, at + SLOT_SIZE);
+        write_u16(buf, at, offset);
+        write_u16(buf, at + 2, key.len());
+        write_u16(buf, at + 4, value.len());
+        write_u16(buf, COUNT, count + 1);
+    }
+
+    /// Insert or replace, returning `false` if the node is too full
+    pub fn upsert(buf: &mut [u8], key: &[u8], value: &[u8]) -> bool {
+        let needed = key.len() + value.len() + SLOT_SIZE;
+        match search(buf, key) {
+            Ok(i) => {
+                let (_, key_len, value_len) = slot(buf, i);
+                if space(buf) + key_len + value_len + SLOT_SIZE < needed {
+                    return false;
+                }
+                remove(buf, i);
+                insert(buf, i, key, value);
+            }
+            Err(i) => {
+                if space(buf) < needed {
+                    return false;
+                }
+                insert(buf, i, key, value);
+            }
+        }
+        true
+    }
+
+    pub fn remove(buf: &mut [u8], i: usize) {
+        let count = count(buf);
+        let (_, key_len, value_len) = slot(buf, i);
+        let dead = read_u16(buf, DEAD) + key_len + value_len;
+        write_u16(buf, DEAD, dead);
+
+        let at = SLOTS + i * SLOT_SIZE;
+        buf.copy_within(at + SLOT_SIZE..SLOTS + count * SLOT_SIZE, at);
+        write_u16(buf, COUNT, count - 1);
+    }
+
+    /// Rewrite the records to reclaim the space of removed ones
+    fn compact(buf: &mut [u8]) {
+        let old = buf.to_vec();
+        rebuild(buf, &old, 0..count(&old), upper(&old));
+    }
+
+    /// Reset `buf` to the slots `range` of `old` at the level of `old`
+    fn rebuild(buf: &mut [u8], old: &[u8], range: std::ops::Range<usize>, upper: u64) {
+        init(buf, level(old), upper);
+        for (n, i) in range.enumerate() {
+            insert(buf, n, key(old, i), value(old, i));
+        }
+    }
+
+    /// Length of the separator [`split`] will push up
+    pub fn separator_len(buf: &[u8]) -> usize {
+        key(buf, split_slot(buf)).len()
+    }
+
+    fn split_slot(buf: &[u8]) -> usize {
+        let mid = count(buf) / 2;
+        if level(buf) == 0 {
+            mid - 1
+        } else {
+            mid
+        }
+    }
+
+    /// Move the lower half of `buf` into the empty page `left` and return the separator: keys
+    /// of `left` are at most the separator, keys remaining in `buf` are greater.
+    pub fn split(buf: &mut [u8], left: &mut [u8]) -> Vec<u8> {
+        let old = buf.to_vec();
+        let count = count(&old);
+        let sep = split_slot(&old);
+
+        if level(&old) == 0 {
+            rebuild(left, &old, 0..sep + 1, 0);
+            rebuild(buf, &old, sep + 1..count, 0);
+        } else {
+            rebuild(left, &old, 0..sep, child(&old, sep));
+            rebuild(buf, &old, sep + 1..count, upper(&old));
+        }
+
+        key(&old, sep).to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use rustix::fd::AsFd;
+
+    use super::*;
+    use crate::{testutil::TempFile, Error, OwnedExmapFd};
+
+    #[test]
+    fn node_insert_remove() {
+        let mut page = vec![0; 512];
+        node::init(&mut page, 0, 0);
+
+        for k in [5u8, 1, 3] {
+            assert!(node::upsert(&mut page, &[k], &[k; 8]));
+        }
+        assert!(node::upsert(&mut page, &[3], b"replaced"));
+        assert_eq!(node::count(&page), 3);
+        assert_eq!(node::search(&page, &[3]), Ok(1));
+        assert_eq!(node::value(&page, 1), b"replaced");
+        assert_eq!(node::search(&page, &[4]), Err(2));
+
+        // Fill up, then make sure removed space is reclaimed
+        let mut k = 10u8;
+        while node::upsert(&mut page, &[k], &[0; 20]) {
+            k += 1;
+        }
+        let full = node::count(&page);
+        node::remove(&mut page, 0);
+        node::remove(&mut page, 0);
+        assert!(node::upsert(&mut page, &[k], &[0; 20]));
+        assert_eq!(node::count(&page), full - 1);
+        assert_eq!(node::key(&page, 0), &[5]);
+    }
+
+    #[test]
+    fn node_split() {
+        let mut page = vec![0; 512];
+        let mut left = vec![0; 512];
+        node::init(&mut page, 0, 0);
+        for k in 0..10u8 {
+            node::upsert(&mut page, &[k], &[k]);
+        }
+
+        let separator = node::split(&mut page, &mut left);
+        assert_eq!(separator, [4]);
+        assert_eq!(node::count(&left), 5);
+        assert_eq!(node::key(&left, 4), &[4]);
+        assert_eq!(node::key(&page, 0), &[5]);
+
+        // Inner nodes push the middle key up and keep its child as the upper of the left half
+        let mut inner = vec![0; 512];
+        let mut inner_left = vec![0; 512];
+        node::init(&mut inner, 1, 99);
+        for k in 0..5u8 {
+            node::upsert(&mut inner, &[k * 10], &u64::from(k).to_le_bytes());
+        }
+        assert_eq!(node::split(&mut inner, &mut inner_left), [20]);
+        assert_eq!(node::route(&inner_left, &[10], false), (1, Some(1)));
+        assert_eq!(node::route(&inner_left, &[15], false), (2, None));
+        assert_eq!(node::route(&inner, &[30], false), (3, Some(0)));
+        assert_eq!(node::route(&inner, &[30], true), (4, Some(1)));
+        assert_eq!(node::route(&inner, &[50], false), (99, None));
+    }
+
+    /// Writers on interleaved keys keep splitting the same leaves and inner nodes, while lookups
+    /// and scans restart whenever a split or an eviction changed a node underneath them
+    #[test]
+    fn concurrent_writers() {
+        const THREADS: u32 = 4;
+        const KEYS: u32 = 2000;
+        let value = |k: u32| [k as u8; 32];
+
+        let path = TempFile::new("btree");
+        let backing = path.create();
+        let exmap_fd = OwnedExmapFd::<4096>::open().unwrap();
+        let mem = exmap_fd
+            .create(1024 * 4096, THREADS as u16, 64, Some(backing.as_fd()))
+            .unwrap();
+        let bm = unsafe { BufferManager::new(&exmap_fd, mem, THREADS as u16, 64) }.unwrap();
+        let tree = BTree::create(&bm).unwrap();
+
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let tree = &tree;
+                s.spawn(move || {
+                    for i in 0..KEYS {
+                        let k = i * THREADS + t;
+                        tree.insert(&k.to_be_bytes(), &value(k)).unwrap();
+                        let old = i / 2 * THREADS + t;
+                        assert_eq!(
+                            tree.lookup(&old.to_be_bytes()).unwrap().as_deref(),
+                            Some(&value(old)[..])
+                        );
+                    }
+                    // Remove the odd keys of this thread while the others may still insert
+                    for i in (1..KEYS).step_by(2) {
+                        assert!(tree.remove(&(i * THREADS + t).to_be_bytes()).unwrap());
+                    }
+
+                    let mut last = None;
+                    tree.scan(Bound::Unbounded, |key, _| {
+                        assert!(last.as_deref() < Some(key));
+                        last = Some(key.to_vec());
+                        true
+                    })
+                    .unwrap();
+                });
+            }
+        });
+
+        let mut keys = Vec::new();
+        tree.scan(Bound::Included(&8u32.to_be_bytes()), |key, v| {
+            let k = u32::from_be_bytes(key.try_into().unwrap());
+            assert_eq!(v, value(k));
+            keys.push(k);
+            true
+        })
+        .unwrap();
+        let expected: Vec<u32> = (2..KEYS)
+            .step_by(2)
+            .flat_map(|i| (0..THREADS).map(move |t| i * THREADS + t))
+            .collect();
+        assert_eq!(keys, expected);
+        assert_eq!(tree.lookup(&THREADS.to_be_bytes()).unwrap(), None);
+
+        // A meta page out of range fails instead of panicking
+        assert!(matches!(
+            BTree::open(&bm, 1024).lookup(b"key"),
+            Err(Error::Io(io::Errno::INVAL))
+        ));
+
+        bm.close().unwrap();
+    }
+}
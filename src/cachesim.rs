@@ -0,0 +1,415 @@
+//! Offline cache simulation over page access traces, for picking a `buffer_size` and an
+//! eviction policy without an exmap device.
+//!
+//! A trace is text with one access per line, `THREAD PAGE` or just `PAGE` for thread 0, and
+//! `#` starting a comment. The threads share one buffer and are simulated interleaved in the
+//! order of the lines.
+
+use std::{
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
+    fmt,
+    io::{self, BufRead, ErrorKind},
+    str::FromStr,
+};
+
+use crate::bm::{ResidentSet, EVICT_BATCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub thread: u32,
+    pub pid: u64,
+}
+
+/// Read a trace in the format described in the [module docs](self)
+pub fn read_accesses(input: impl BufRead) -> io::Result<Vec<Access>> {
+    let mut accesses = Vec::new();
+    for (n, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("line {}: expected THREAD PAGE or PAGE", n + 1),
+            )
+        };
+
+        let access = match (fields.next(), fields.next(), fields.next()) {
+            (None, ..) => continue,
+            (Some(pid), None, _) => Access {
+                thread: 0,
+                pid: pid.parse().map_err(|_| invalid())?,
+            },
+            (Some(thread), Some(pid), None) => Access {
+                thread: thread.parse().map_err(|_| invalid())?,
+                pid: pid.parse().map_err(|_| invalid())?,
+            },
+            _ => return Err(invalid()),
+        };
+        accesses.push(access);
+    }
+    Ok(accesses)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// The [`BufferManager`](crate::bm::BufferManager) clock: second chance over the resident
+    /// set in hash order, evicting [`EVICT_BATCH`] pages once the buffer is full
+    BmClock,
+    /// The [`PreadPool`](crate::pool::PreadPool) clock: second chance over the frames, one
+    /// victim per miss
+    PoolClock,
+    Lru,
+    /// Belady's optimal policy, the lower bound for any policy
+    Opt,
+}
+
+impl Policy {
+    pub const ALL: [Policy; 4] = [Self::BmClock, Self::PoolClock, Self::Lru, Self::Opt];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::BmClock => "bm-clock",
+            Self::PoolClock => "pool-clock",
+            Self::Lru => "lru",
+            Self::Opt => "opt",
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| format!("unknown policy {s}"))
+    }
+}
+
+/// Misses of one policy at one buffer size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Point {
+    pub policy: Policy,
+    /// Buffer size in pages
+    pub pages: usize,
+    pub accesses: u64,
+    pub misses: u64,
+}
+
+impl Point {
+    pub fn miss_ratio(&self) -> f64 {
+        match self.accesses {
+            0 => 0.0,
+            n => self.misses as f64 / n as f64,
+        }
+    }
+}
+
+/// Number of different pages in the trace, the buffer size past which only cold misses remain
+pub fn distinct_pages(accesses: &[Access]) -> usize {
+    accesses.iter().map(|a| a.pid).collect::<HashSet<_>>().len()
+}
+
+/// Run the trace through `policy` with a buffer of `pages` pages
+pub fn simulate(policy: Policy, pages: usize, accesses: &[Access]) -> Point {
+    Trace::new(accesses).simulate(policy, pages)
+}
+
+/// A trace to simulate at many points. What OPT needs to know about the future is worked out
+/// once and shared by every simulation, also across threads.
+pub struct Trace<'a> {
+    accesses: &'a [Access],
+    /// Index of the next access to the same page for every access, `usize::MAX` for none
+    next: Vec<usize>,
+}
+
+impl<'a> Trace<'a> {
+    pub fn new(accesses: &'a [Access]) -> Self {
+        let mut next = vec![usize::MAX; accesses.len()];
+        let mut seen = HashMap::new();
+        for (i, a) in accesses.iter().enumerate().rev() {
+            if let Some(later) = seen.insert(a.pid, i) {
+                next[i] = later;
+            }
+        }
+        Self { accesses, next }
+    }
+
+    /// Run the trace through `policy` with a buffer of `pages` pages
+    pub fn simulate(&self, policy: Policy, pages: usize) -> Point {
+        assert!(pages > 0, "the buffer needs at least one page");
+
+        let mut cache: Box<dyn Cache + '_> = match policy {
+            Policy::BmClock => Box::new(BmClock::new(pages)),
+            Policy::PoolClock => Box::new(PoolClock::new(pages)),
+            Policy::Lru => Box::new(Lru::new(pages)),
+            Policy::Opt => Box::new(Opt::new(pages, &self.next)),
+        };
+        let misses = self
+            .accesses
+            .iter()
+            .filter(|a| !cache.access(a.pid))
+            .count();
+
+        Point {
+            policy,
+            pages,
+            accesses: self.accesses.len() as u64,
+            misses: misses as u64,
+        }
+    }
+}
+
+trait Cache {
+    /// Touch `pid`, loading it on a miss. Returns whether it was a hit.
+    fn access(&mut self, pid: u64) -> bool;
+}
+
+struct BmClock {
+    capacity: usize,
+    resident: ResidentSet,
+    /// Resident pages and whether the clock marked them since their last access
+    marked: HashMap<u64, bool>,
+}
+
+impl BmClock {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            resident: ResidentSet::new(capacity),
+            marked: HashMap::new(),
+        }
+    }
+
+    /// Same as `BufferManager::select_victims`, minus the latching
+    fn evict(&mut self, pages: usize) -> usize {
+        let mut victims = Vec::with_capacity(pages);
+        let mut budget = 2 * self.resident.capacity();
+        while victims.len() < pages && budget > 0 {
+            let batch = EVICT_BATCH.min(budget);
+            budget -= batch;
+            self.resident.clock_batch(batch, |pid| {
+                if victims.len() >= pages {
+                    return;
+                }
+                let marked = self.marked.get_mut(&pid).expect("resident page");
+                match *marked {
+                    true => victims.push(pid),
+                    false => *marked = true,
+                }
+            });
+        }
+
+        for pid in &victims {
+            self.resident.remove(*pid);
+            self.marked.remove(pid);
+        }
+        victims.len()
+    }
+}
+
+impl Cache for BmClock {
+    fn access(&mut self, pid: u64) -> bool {
+        if let Some(marked) = self.marked.get_mut(&pid) {
+            *marked = false;
+            return true;
+        }
+
+        while self.marked.len() + 1 > self.capacity {
+            if self.evict(EVICT_BATCH) == 0 {
+                break;
+            }
+        }
+        self.resident.insert(pid);
+        self.marked.insert(pid, false);
+        false
+    }
+}
+
+struct PoolClock {
+    capacity: usize,
+    /// `(pid, referenced)` of every used frame
+    frames: Vec<(u64, bool)>,
+    table: HashMap<u64, usize>,
+    hand: usize,
+}
+
+impl PoolClock {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            frames: Vec::with_capacity(capacity),
+            table: HashMap::new(),
+            hand: 0,
+        }
+    }
+}
+
+impl Cache for PoolClock {
+    fn access(&mut self, pid: u64) -> bool {
+        if let Some(&f) = self.table.get(&pid) {
+            self.frames[f].1 = true;
+            return true;
+        }
+
+        let f = if self.frames.len() < self.capacity {
+            self.frames.push((pid, true));
+            self.frames.len() - 1
+        } else {
+            loop {
+                let f = self.hand;
+                self.hand = (self.hand + 1) % self.frames.len();
+                let (old, referenced) = &mut self.frames[f];
+                if !std::mem::take(referenced) {
+                    self.table.remove(old);
+                    self.frames[f] = (pid, true);
+                    break f;
+                }
+            }
+        };
+        self.table.insert(pid, f);
+        false
+    }
+}
+
+struct Lru {
+    capacity: usize,
+    clock: u64,
+    /// Last access of every resident page, and the other way round
+    last: HashMap<u64, u64>,
+    order: BTreeMap<u64, u64>,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            clock: 0,
+            last: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+}
+
+impl Cache for Lru {
+    fn access(&mut self, pid: u64) -> bool {
+        self.clock += 1;
+        let hit = match self.last.insert(pid, self.clock) {
+            Some(prev) => {
+                self.order.remove(&prev);
+                true
+            }
+            None => {
+                if self.last.len() > self.capacity {
+                    let (_, victim) = self.order.pop_first().expect("full cache");
+                    self.last.remove(&victim);
+                }
+                false
+            }
+        };
+        self.order.insert(self.clock, pid);
+        hit
+    }
+}
+
+struct Opt<'t> {
+    capacity: usize,
+    /// [`Trace::next`]
+    next: &'t [usize],
+    i: usize,
+    /// Next use of every resident page
+    resident: HashMap<u64, usize>,
+    /// `(next use, pid)`, with stale entries skipped when popped
+    heap: BinaryHeap<(usize, u64)>,
+}
+
+impl<'t> Opt<'t> {
+    fn new(capacity: usize, next: &'t [usize]) -> Self {
+        Self {
+            capacity,
+            next,
+            i: 0,
+            resident: HashMap::new(),
+            heap: BinaryHeap::new(),
+        }
+    }
+}
+
+impl Cache for Opt<'_> {
+    fn access(&mut self, pid: u64) -> bool {
+        let next = self.next[self.i];
+        self.i += 1;
+
+        let hit = self.resident.contains_key(&pid);
+        if !hit && self.resident.len() == self.capacity {
+            // Evict the page used furthest in the future
+            while let Some((use_at, victim)) = self.heap.pop() {
+                if self.resident.get(&victim) == Some(&use_at) {
+                    self.resident.remove(&victim);
+                    break;
+                }
+            }
+        }
+        self.resident.insert(pid, next);
+        self.heap.push((next, pid));
+        hit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(pids: &[u64]) -> Vec<Access> {
+        pids.iter().map(|&pid| Access { thread: 0, pid }).collect()
+    }
+
+    #[test]
+    fn parse_trace() {
+        let text = "# thread page\n0 7\n\n3 9  # comment\n12\n";
+        assert_eq!(
+            read_accesses(text.as_bytes()).unwrap(),
+            [
+                Access { thread: 0, pid: 7 },
+                Access { thread: 3, pid: 9 },
+                Access { thread: 0, pid: 12 }
+            ]
+        );
+        assert!(read_accesses("1 2 3\n".as_bytes()).is_err());
+        assert!(read_accesses("x\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn policies() {
+        // A loop one page larger than the buffer: LRU always misses, OPT does not
+        let accesses = trace(&[0, 1, 2, 0, 1, 2, 0, 1, 2]);
+        assert_eq!(simulate(Policy::Lru, 2, &accesses).misses, 9);
+        assert_eq!(simulate(Policy::Opt, 2, &accesses).misses, 6);
+        assert_eq!(distinct_pages(&accesses), 3);
+
+        for policy in Policy::ALL {
+            // Only cold misses once everything fits
+            assert_eq!(simulate(policy, 3, &accesses).misses, 3, "{policy}");
+            let point = simulate(policy, 1, &accesses);
+            assert_eq!(point.misses, 9, "{policy}");
+            assert_eq!(point.miss_ratio(), 1.0);
+        }
+
+        // Larger buffers never do worse under OPT
+        let accesses = trace(&(0..2000).map(|i| (i * i) % 97).collect::<Vec<_>>());
+        let trace = Trace::new(&accesses);
+        let misses: Vec<_> = (1..100)
+            .map(|pages| trace.simulate(Policy::Opt, pages).misses)
+            .collect();
+        assert!(misses.windows(2).all(|w| w[0] >= w[1]));
+        assert!(simulate(Policy::BmClock, 50, &accesses).misses >= misses[49]);
+    }
+}
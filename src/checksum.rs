@@ -0,0 +1,96 @@
+//! Optional page header carrying a CRC32C of the page.
+//!
+//! ```text
+//! 0        4        8                16
+//! +--------+--------+----------------+--------------
+//! | crc32c | flags  |    page LSN    | payload ...
+//! +--------+--------+----------------+--------------
+//! ```
+//!
+//! The checksum covers everything after itself. A page that is entirely zero has never been
+//! written and is accepted as is. The page LSN is only used with a [`Wal`](crate::wal::Wal).
+
+pub const HEADER_SIZE: usize = 16;
+
+const CHECKSUM: std::ops::Range<usize> = 0..4;
+
+const CRC32C_POLY: u32 = 0x82F6_3B78;
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC32C_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        CRC32C_TABLE[((crc ^ u32::from(b)) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// The checksum stored in the page header
+pub fn stored(page: &[u8]) -> u32 {
+    u32::from_le_bytes(page[CHECKSUM].try_into().unwrap())
+}
+
+/// The checksum the page should have
+pub fn compute(page: &[u8]) -> u32 {
+    crc32c(&page[CHECKSUM.end..])
+}
+
+/// Fill in the checksum before writing the page back
+pub fn stamp(page: &mut [u8]) {
+    let crc = compute(page);
+    page[CHECKSUM].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Check a page that was just read. Returns the stored and computed checksum on mismatch.
+pub fn verify(page: &[u8]) -> Result<(), (u32, u32)> {
+    let (stored, computed) = (stored(page), compute(page));
+    if stored == computed || page.iter().all(|&b| b == 0) {
+        Ok(())
+    } else {
+        Err((stored, computed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    #[test]
+    fn stamp_and_verify() {
+        let mut page = [0u8; 4096];
+        assert!(verify(&page).is_ok());
+
+        page[HEADER_SIZE..HEADER_SIZE + 5].copy_from_slice(b"hello");
+        assert!(verify(&page).is_err());
+
+        stamp(&mut page);
+        assert!(verify(&page).is_ok());
+
+        page[4000] ^= 0x10;
+        let (stored, computed) = verify(&page).unwrap_err();
+        assert_ne!(stored, computed);
+    }
+}
@@ -0,0 +1,266 @@
+//! How to set up an exmap, loaded from TOML and overridden by environment variables.
+//!
+//! ```toml
+//! vma_size = "16G"
+//! interfaces = 8
+//! buffer_size = "2G"
+//! backing = "/var/lib/exmap/data"
+//! ```
+//!
+//! Sizes are bytes, as integers or strings with a binary `K`, `M`, `G` or `T` suffix. Every
+//! field has a default and an override in the environment, e.g. `EXMAP_BUFFER_SIZE=4G`.
+
+use std::path::{Path, PathBuf};
+
+use rustix::{
+    fd::{AsFd, OwnedFd},
+    fs::{self, Mode, OFlags},
+};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{trace::Tracer, Error, OwnedExmapFd, Result, VirtMem};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExmapConfig {
+    /// Size of the VMA in bytes
+    #[serde(deserialize_with = "size")]
+    pub vma_size: u64,
+    pub interfaces: u16,
+    /// Physical memory of the exmap in bytes
+    #[serde(deserialize_with = "size")]
+    pub buffer_size: u64,
+    pub backing: Option<PathBuf>,
+    /// Record every exmap action to this file, see [`trace`](crate::trace)
+    pub trace: Option<PathBuf>,
+}
+
+impl Default for ExmapConfig {
+    fn default() -> Self {
+        Self {
+            vma_size: 16 << 20,
+            interfaces: 4,
+            buffer_size: 8 << 20,
+            backing: None,
+            trace: None,
+        }
+    }
+}
+
+/// Parse a byte count with an optional binary `K`, `M`, `G` or `T` suffix
+pub fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let (digits, shift) = match s.as_bytes().last().map(u8::to_ascii_uppercase) {
+        Some(b'K') => (&s[..s.len() - 1], 10),
+        Some(b'M') => (&s[..s.len() - 1], 20),
+        Some(b'G') => (&s[..s.len() - 1], 30),
+        Some(b'T') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    let n: u64 = digits.parse().map_err(|e| format!("{e}"))?;
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| "too large".to_string())
+}
+
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Suffixed(String),
+    }
+
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(n) => Ok(n),
+        Size::Suffixed(s) => {
+            parse_size(&s).map_err(|e| serde::de::Error::custom(format!("size {s:?}: {e}")))
+        }
+    }
+}
+
+impl ExmapConfig {
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        toml::from_str(toml).map_err(|e| Error::Config(e.to_string()))
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("{}: {e}", path.display())))
+            .and_then(|toml| Self::from_toml(&toml))
+    }
+
+    /// The defaults, overridden by the file at `path` if given and then by the environment,
+    /// validated for pages of `page_size` bytes
+    pub fn load(path: Option<&Path>, page_size: usize) -> Result<Self> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        config.validate(page_size)?;
+        Ok(config)
+    }
+
+    /// Override fields from `EXMAP_VMA_SIZE`, `EXMAP_INTERFACES`, `EXMAP_BUFFER_SIZE`,
+    /// `EXMAP_BACKING` and `EXMAP_TRACE`
+    pub fn apply_env(&mut self) -> Result<()> {
+        self.apply_vars(|name| std::env::var(name).ok())
+    }
+
+    fn apply_vars(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        let invalid =
+            |name: &str, value: &str, e: String| Error::Config(format!("{name}={value}: {e}"));
+
+        if let Some(v) = var("EXMAP_VMA_SIZE") {
+            self.vma_size = parse_size(&v).map_err(|e| invalid("EXMAP_VMA_SIZE", &v, e))?;
+        }
+        if let Some(v) = var("EXMAP_INTERFACES") {
+            self.interfaces = v
+                .parse()
+                .map_err(|e| invalid("EXMAP_INTERFACES", &v, format!("{e}")))?;
+        }
+        if let Some(v) = var("EXMAP_BUFFER_SIZE") {
+            self.buffer_size = parse_size(&v).map_err(|e| invalid("EXMAP_BUFFER_SIZE", &v, e))?;
+        }
+        if let Some(v) = var("EXMAP_BACKING") {
+            self.backing = Some(v.into());
+        }
+        if let Some(v) = var("EXMAP_TRACE") {
+            self.trace = Some(v.into());
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self, page_size: usize) -> Result<()> {
+        let page_size = page_size as u64;
+        let invalid = |msg: String| Err(Error::Config(msg));
+
+        if self.vma_size == 0 || page_size == 0 || self.vma_size % page_size != 0 {
+            return invalid(format!(
+                "vma_size must be a multiple of the {page_size} byte page size"
+            ));
+        }
+        if usize::try_from(self.vma_size).is_err() {
+            return invalid("vma_size does not fit the address space".to_string());
+        }
+        if self.interfaces == 0 {
+            return invalid("interfaces must be at least 1".to_string());
+        }
+        if self.buffer_size < page_size {
+            return invalid("buffer_size must be at least one page".to_string());
+        }
+        Ok(())
+    }
+
+    /// `buffer_size` in pages, as exmap takes it
+    pub fn buffer_pages(&self, page_size: usize) -> usize {
+        (self.buffer_size / page_size as u64) as usize
+    }
+
+    /// Open `backing`, if given
+    pub fn open_backing(&self) -> Result<Option<OwnedFd>> {
+        self.backing
+            .as_ref()
+            .map(|path| {
+                fs::openat(
+                    fs::cwd(),
+                    path,
+                    OFlags::RDWR | OFlags::CLOEXEC,
+                    Mode::empty(),
+                )
+                .map_err(Error::from)
+            })
+            .transpose()
+    }
+
+    /// Validate, open the device and the backing file, set up the exmap and run `f` on it.
+    /// A trace, if configured, is flushed once `f` returns, whether or not it failed.
+    pub fn with_exmap<const P: usize, T, E: From<Error>>(
+        &self,
+        f: impl FnOnce(&OwnedExmapFd<P>, VirtMem<'_, '_, P>) -> std::result::Result<T, E>,
+    ) -> std::result::Result<T, E> {
+        self.validate(P)?;
+        let backing = self.open_backing()?;
+        let mut exmap_fd = OwnedExmapFd::<P>::open().map_err(Error::from)?;
+        if let Some(path) = &self.trace {
+            let tracer = Tracer::create(path)
+                .map_err(|e| Error::Config(format!("{}: {e}", path.display())))?;
+            exmap_fd = exmap_fd.with_tracer(tracer);
+        }
+
+        let mem = exmap_fd
+            .create(
+                self.vma_size as usize,
+                self.interfaces,
+                self.buffer_pages(P),
+                backing.as_ref().map(|fd| fd.as_fd()),
+            )
+            .map_err(Error::from)?;
+        let res = f(&exmap_fd, mem);
+        if let Some(tracer) = exmap_fd.tracer() {
+            tracer.finish().map_err(|e| {
+                Error::Io(rustix::io::Errno::from_io_error(&e).unwrap_or(rustix::io::Errno::IO))
+            })?;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_and_override() {
+        let mut config = ExmapConfig::from_toml(
+            r#"
+            vma_size = "1G"
+            interfaces = 2
+            buffer_size = 65536
+            backing = "/tmp/data"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.vma_size, 1 << 30);
+        assert_eq!(config.interfaces, 2);
+        assert_eq!(config.buffer_pages(4096), 16);
+        assert_eq!(config.backing, Some("/tmp/data".into()));
+        assert_eq!(config.trace, None);
+
+        config
+            .apply_vars(|name| (name == "EXMAP_BUFFER_SIZE").then(|| "4M".to_string()))
+            .unwrap();
+        assert_eq!(config.buffer_size, 4 << 20);
+        assert_eq!(config.interfaces, 2);
+        assert!(config.validate(4096).is_ok());
+
+        assert!(config
+            .apply_vars(|name| (name == "EXMAP_INTERFACES").then(|| "many".to_string()))
+            .is_err());
+        assert!(ExmapConfig::from_toml("interfaces = 2\nbogus = 1").is_err());
+        assert!(ExmapConfig::from_toml("vma_size = \"1Q\"").is_err());
+        assert_eq!(ExmapConfig::from_toml("").unwrap(), ExmapConfig::default());
+    }
+
+    #[test]
+    fn validation() {
+        let valid = ExmapConfig::default();
+        assert!(valid.validate(4096).is_ok());
+        for invalid in [
+            ExmapConfig {
+                vma_size: 4097,
+                ..valid.clone()
+            },
+            ExmapConfig {
+                interfaces: 0,
+                ..valid.clone()
+            },
+            ExmapConfig {
+                buffer_size: 100,
+                ..valid.clone()
+            },
+        ] {
+            assert!(matches!(invalid.validate(4096), Err(Error::Config(_))));
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Opening `O_DIRECT` backing files.
+//!
+//! exmap reads and writes whole pages, so a backing file can bypass the page cache as long as
+//! `PAGE_SIZE` is a multiple of the direct I/O alignment: the logical sector size of the device
+//! underneath.
+
+use rustix::{
+    fd::{AsFd, BorrowedFd, OwnedFd},
+    fs::{self, FallocateFlags, FileType, Mode, OFlags},
+    io,
+    path::Arg,
+};
+
+use crate::sys;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "riscv64"))]
+const O_DIRECT: u32 = 0o40000;
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+const O_DIRECT: u32 = 0o200000;
+#[cfg(any(target_arch = "powerpc", target_arch = "powerpc64"))]
+const O_DIRECT: u32 = 0o400000;
+#[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+const O_DIRECT: u32 = 0o100000;
+
+/// rustix does not expose `O_DIRECT`
+fn o_direct() -> OFlags {
+    // SAFETY:
+    // The value matches the kernel's definition for the target architecture
+    unsafe { OFlags::from_bits_unchecked(O_DIRECT) }
+}
+
+/// The file offset alignment `O_DIRECT` needs for a regular file
+fn dio_alignment(fd: &OwnedFd) -> io::Result<u32> {
+    let stx = sys::statx(fd, sys::STATX_DIOALIGN)?;
+    if stx.stx_mask & sys::STATX_DIOALIGN != 0 {
+        // 0 means the file does not support direct I/O at all
+        return match stx.stx_dio_offset_align {
+            0 => Err(io::Errno::INVAL),
+            align => Ok(align),
+        };
+    }
+
+    // Before Linux 6.1, fall back to the logical sector size of the device holding the file,
+    // looking through a partition to its disk
+    let dev = format!("/sys/dev/block/{}:{}", stx.stx_dev_major, stx.stx_dev_minor);
+    let sector_size = ["queue", "../queue"].iter().find_map(|queue| {
+        std::fs::read_to_string(format!("{dev}/{queue}/logical_block_size"))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    });
+    // Not backed by a block device, e.g. NFS. 512 is the smallest sector size
+    Ok(sector_size.unwrap_or(512))
+}
+
+#[derive(Debug)]
+pub struct DirectFile<const PAGE_SIZE: usize> {
+    fd: OwnedFd,
+    block_size: u32,
+}
+
+impl<const PAGE_SIZE: usize> DirectFile<PAGE_SIZE> {
+    /// Open, or create, `path` with `O_DIRECT`. The file is preallocated to `exmap_size` bytes,
+    /// the size that will be passed to [`OwnedExmapFd::create`](crate::OwnedExmapFd::create).
+    ///
+    /// Fails with `EINVAL` if `PAGE_SIZE` is not a multiple of the logical block size or
+    /// `exmap_size` is not a multiple of `PAGE_SIZE`.
+    pub fn open<P: Arg>(path: P, exmap_size: usize) -> io::Result<Self> {
+        if exmap_size % PAGE_SIZE != 0 {
+            return Err(io::Errno::INVAL);
+        }
+
+        let fd = fs::openat(
+            fs::cwd(),
+            path,
+            OFlags::RDWR | OFlags::CREATE | OFlags::CLOEXEC | o_direct(),
+            Mode::from_raw_mode(0o644),
+        )?;
+
+        let is_block_device =
+            FileType::from_raw_mode(fs::fstat(&fd)?.st_mode) == FileType::BlockDevice;
+        let block_size = if is_block_device {
+            io::ioctl_blksszget(&fd)?
+        } else {
+            dio_alignment(&fd)?
+        };
+
+        if block_size == 0 || PAGE_SIZE % block_size as usize != 0 {
+            return Err(io::Errno::INVAL);
+        }
+
+        // Block devices already have their size
+        if !is_block_device {
+            fs::fallocate(&fd, FallocateFlags::empty(), 0, exmap_size as u64)?;
+        }
+
+        Ok(Self { fd, block_size })
+    }
+
+    /// The direct I/O alignment the file was checked against
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// The fd to pass as `backing_fd` to [`OwnedExmapFd::create`](crate::OwnedExmapFd::create)
+    pub fn backing_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+
+    pub fn into_inner(self) -> OwnedFd {
+        self.fd
+    }
+}
+
+impl<const PAGE_SIZE: usize> AsFd for DirectFile<PAGE_SIZE> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempFile;
+
+    #[test]
+    fn preallocates() {
+        let path = TempFile::new("direct");
+
+        let file = match DirectFile::<4096>::open(&*path, 16 * 4096) {
+            Ok(file) => file,
+            Err(io::Errno::INVAL) => {
+                eprintln!(
+                    "skipping preallocates: {} does not support O_DIRECT",
+                    path.display()
+                );
+                return;
+            }
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(4096 % file.block_size(), 0);
+        assert_eq!(fs::fstat(file.backing_fd()).unwrap().st_size, 16 * 4096);
+
+        assert_eq!(
+            DirectFile::<4096>::open(&*path, 100).unwrap_err(),
+            io::Errno::INVAL
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+
+use rustix::io;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Io(io::Errno),
+    /// A page read from the backing store does not match its checksum
+    Corrupt {
+        pid: u64,
+        stored: u32,
+        computed: u32,
+    },
+    /// The backing file has no superblock
+    NotFormatted,
+    /// The backing file was created with a different configuration
+    Mismatch {
+        what: &'static str,
+        expected: u64,
+        found: u64,
+    },
+    /// An invalid [`ExmapConfig`](crate::ExmapConfig)
+    Config(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => e.fmt(f),
+            Self::Corrupt {
+                pid,
+                stored,
+                computed,
+            } => write!(
+                f,
+                "page {pid} is corrupt: stored checksum {stored:#010x}, computed {computed:#010x}"
+            ),
+            Self::NotFormatted => f.write_str("backing file has no superblock"),
+            Self::Mismatch {
+                what,
+                expected,
+                found,
+            } => write!(f, "{what} mismatch: expected {expected}, found {found}"),
+            Self::Config(msg) => write!(f, "invalid configuration: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Errno> for Error {
+    fn from(e: io::Errno) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e.into(),
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
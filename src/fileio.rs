@@ -0,0 +1,59 @@
+use rustix::{fd::AsFd, io};
+
+/// `pread` until `buf` is full. Reading past the end of the file is an `EIO`.
+pub(crate) fn pread_exact<Fd: AsFd>(fd: Fd, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match io::pread(&fd, buf, offset) {
+            Ok(0) => return Err(io::Errno::IO),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(io::Errno::INTR) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// `pread` until `buf` is full, zero-filling whatever lies past the end of the file
+pub(crate) fn pread_or_zero<Fd: AsFd>(
+    fd: Fd,
+    mut buf: &mut [u8],
+    mut offset: u64,
+) -> io::Result<()> {
+    while !buf.is_empty() {
+        match io::pread(&fd, buf, offset) {
+            Ok(0) => {
+                buf.fill(0);
+                break;
+            }
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(io::Errno::INTR) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// `pwrite` until all of `buf` is written
+pub(crate) fn pwrite_all<Fd: AsFd>(fd: Fd, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match io::pwrite(&fd, buf, offset) {
+            Ok(0) => return Err(io::Errno::IO),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(io::Errno::INTR) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
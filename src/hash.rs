@@ -0,0 +1,466 @@
+//! Extendible hash index over buffer manager pages.
+//!
+//! A meta page holds the global depth and where the directory lives. The directory is a run of
+//! pages with one bucket page id per entry, indexed by the low `global depth` bits of the hash.
+//! Buckets hold fixed size `u64` key and value pairs.
+//!
+//! Lookups never latch: they read the directory entry and the bucket optimistically and
+//! validate both page versions, restarting if a split got in between. Inserts upgrade only the
+//! bucket. Splits are serialized by an exclusive latch on the meta page; a split bumps the
+//! version of the bucket and of every directory page it repoints, and doubling the directory
+//! frees the old run, so readers still on old pages restart.
+
+use crate::{
+    bm::{BufferManager, ExclusiveGuard, OptimisticGuard},
+    checksum, Result,
+};
+
+/// Every page starts with the checksum header
+const BASE: usize = checksum::HEADER_SIZE;
+
+/// Meta page fields
+const GLOBAL_DEPTH: usize = BASE;
+const DIRECTORY: usize = BASE + 8;
+const DIRECTORY_PAGES: usize = BASE + 16;
+
+/// Bucket fields
+const LOCAL_DEPTH: usize = BASE;
+const COUNT: usize = BASE + 8;
+const ENTRIES: usize = BASE + 16;
+const ENTRY_SIZE: usize = 16;
+
+pub struct HashIndex<'bm, 'a, 'b, const P: usize> {
+    bm: &'bm BufferManager<'a, 'b, P>,
+    meta: u64,
+}
+
+impl<'bm, 'a, 'b, const P: usize> HashIndex<'bm, 'a, 'b, P> {
+    /// Key value pairs per bucket
+    pub const BUCKET_CAPACITY: usize = (P - ENTRIES) / ENTRY_SIZE;
+
+    /// Directory entries per page
+    const DIRECTORY_ENTRIES: u64 = ((P - BASE) / 8) as u64;
+
+    /// Allocate a meta page, a one entry directory and a single bucket
+    pub fn create(bm: &'bm BufferManager<'a, 'b, P>) -> Result<Self> {
+        let mut meta = bm.allocate_page()?;
+        let mut directory = bm.allocate_page()?;
+        let bucket = bm.allocate_page()?;
+
+        write_u64(&mut directory[..], BASE, bucket.pid());
+        write_u64(&mut meta[..], GLOBAL_DEPTH, 0);
+        write_u64(&mut meta[..], DIRECTORY, directory.pid());
+        write_u64(&mut meta[..], DIRECTORY_PAGES, 1);
+
+        Ok(Self {
+            bm,
+            meta: meta.pid(),
+        })
+    }
+
+    /// Open the index whose meta page is `meta`, as returned by [`meta`](Self::meta)
+    pub fn open(bm: &'bm BufferManager<'a, 'b, P>, meta: u64) -> Self {
+        Self { bm, meta }
+    }
+
+    pub fn meta(&self) -> u64 {
+        self.meta
+    }
+
+    pub fn get(&self, key: u64) -> Result<Option<u64>> {
+        let hash = hash(key);
+        let mut buf = vec![0; P];
+        loop {
+            if self.bucket(hash, &mut buf)?.is_some() {
+                return Ok(bucket::find(&buf, key).map(|i| bucket::entry(&buf, i).1));
+            }
+        }
+    }
+
+    /// Insert `key` or replace its value, returning the previous value
+    pub fn insert(&self, key: u64, value: u64) -> Result<Option<u64>> {
+        let hash = hash(key);
+        let mut buf = vec![0; P];
+        loop {
+            let Some(guard) = self.bucket(hash, &mut buf)? else {
+                continue;
+            };
+            let Some(mut page) = guard.upgrade() else {
+                continue;
+            };
+
+            if let Some(i) = bucket::find(&page[..], key) {
+                let previous = bucket::entry(&page[..], i).1;
+                bucket::set(&mut page[..], i, key, value);
+                return Ok(Some(previous));
+            }
+            if bucket::count(&page[..]) < Self::BUCKET_CAPACITY {
+                bucket::push(&mut page[..], key, value);
+                return Ok(None);
+            }
+
+            drop(page);
+            self.split(hash)?;
+        }
+    }
+
+    /// Remove `key`, returning its value
+    pub fn remove(&self, key: u64) -> Result<Option<u64>> {
+        let hash = hash(key);
+        let mut buf = vec![0; P];
+        loop {
+            let Some(guard) = self.bucket(hash, &mut buf)? else {
+                continue;
+            };
+            let Some(i) = bucket::find(&buf, key) else {
+                return Ok(None);
+            };
+            // Unchanged since the copy if the upgrade succeeds, so `i` still holds
+            if let Some(mut page) = guard.upgrade() {
+                let value = bucket::entry(&page[..], i).1;
+                bucket::swap_remove(&mut page[..], i);
+                return Ok(Some(value));
+            }
+        }
+    }
+
+    /// Where directory entry `i` lives, as page index into the directory and byte offset
+    fn locate(i: u64) -> (u64, usize) {
+        (
+            i / Self::DIRECTORY_ENTRIES,
+            BASE + (i % Self::DIRECTORY_ENTRIES) as usize * 8,
+        )
+    }
+
+    /// Find the bucket for `hash` optimistically, leaving a validated copy of it in `buf`.
+    /// `None` means a version changed and the caller restarts.
+    fn bucket(&self, hash: u64, buf: &mut [u8]) -> Result<Option<OptimisticGuard<'bm, 'a, 'b, P>>> {
+        let meta = self.bm.fix_o(self.meta)?;
+        meta.copy_to(buf);
+        let global_depth = read_u64(buf, GLOBAL_DEPTH);
+        let directory = read_u64(buf, DIRECTORY);
+        if !meta.validate() {
+            return Ok(None);
+        }
+
+        let (page, offset) = Self::locate(hash & mask(global_depth));
+        let entry = self.bm.fix_o(directory + page)?;
+        entry.copy_to(buf);
+        let pid = read_u64(buf, offset);
+        // A doubling in between frees the directory page, so the meta page must be unchanged
+        // for `pid` to be the current entry
+        if !entry.validate() || !meta.validate() {
+            return Ok(None);
+        }
+
+        // The entry must still point at the bucket once it is pinned down
+        let bucket = self.bm.fix_o(pid)?;
+        if !entry.validate() || !meta.validate() {
+            return Ok(None);
+        }
+        bucket.copy_to(buf);
+        Ok(bucket.validate().then_some(bucket))
+    }
+
+    /// Split the full bucket for `hash`, doubling the directory first if needed
+    fn split(&self, hash: u64) -> Result<()> {
+        let mut meta = self.bm.fix_x(self.meta)?;
+        loop {
+            let global_depth = read_u64(&meta[..], GLOBAL_DEPTH);
+            let directory = read_u64(&meta[..], DIRECTORY);
+
+            let (page, offset) = Self::locate(hash & mask(global_depth));
+            let pid = read_u64(&self.bm.fix_s(directory + page)?[..], offset);
+            let mut full = self.bm.fix_x(pid)?;
+            // Someone else split it in the meantime
+            if bucket::count(&full[..]) < Self::BUCKET_CAPACITY {
+                return Ok(());
+            }
+
+            let local_depth = read_u64(&full[..], LOCAL_DEPTH);
+            if local_depth == global_depth {
+                drop(full);
+                self.double(&mut meta)?;
+                continue;
+            }
+
+            let mut sibling = self.bm.allocate_page()?;
+            bucket::split(&mut full[..], &mut sibling[..], local_depth);
+
+            // Repoint the entries whose hashes now belong to the sibling
+            let first = (hash & mask(local_depth)) | (1 << local_depth);
+            let mut latched: Option<ExclusiveGuard<'_, 'a, 'b, P>> = None;
+            for i in (first..1 << global_depth).step_by(1 << (local_depth + 1)) {
+                let (page, offset) = Self::locate(i);
+                let entry = match &mut latched {
+                    Some(guard) if guard.pid() == directory + page => guard,
+                    _ => latched.insert(self.bm.fix_x(directory + page)?),
+                };
+                write_u64(&mut entry[..], offset, sibling.pid());
+            }
+
+            return Ok(());
+        }
+    }
+
+    /// Move the directory to a run twice the size and free the old one
+    fn double(&self, meta: &mut ExclusiveGuard<'_, 'a, 'b, P>) -> Result<()> {
+        let global_depth = read_u64(&meta[..], GLOBAL_DEPTH);
+        let directory = read_u64(&meta[..], DIRECTORY);
+        let pages = read_u64(&meta[..], DIRECTORY_PAGES);
+
+        let entries = 1u64 << (global_depth + 1);
+        let new_pages = (entries + Self::DIRECTORY_ENTRIES - 1) / Self::DIRECTORY_ENTRIES;
+        let mut new = self.bm.allocate_run(new_pages)?;
+        {
+            let old = self.bm.fix_run_s(directory, pages)?;
+            for i in 0..entries {
+                let (page, offset) = Self::locate(i & mask(global_depth));
+                let pid = read_u64(&old[..], page as usize * P + offset);
+                let (page, offset) = Self::locate(i);
+                write_u64(&mut new[..], page as usize * P + offset, pid);
+            }
+        }
+
+        write_u64(&mut meta[..], GLOBAL_DEPTH, global_depth + 1);
+        write_u64(&mut meta[..], DIRECTORY, new.pid());
+        write_u64(&mut meta[..], DIRECTORY_PAGES, new_pages);
+        drop(new);
+
+        Ok(self.bm.free_run(directory, pages)?)
+    }
+}
+
+/// Bijective mix so the low bits of the hash depend on the whole key
+fn hash(key: u64) -> u64 {
+    let mut h = key;
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    h ^ (h >> 31)
+}
+
+fn mask(depth: u64) -> u64 {
+    (1 << depth) - 1
+}
+
+fn read_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+fn write_u64(buf: &mut [u8], at: usize, v: u64) {
+    buf[at..at + 8].copy_from_slice(&v.to_le_bytes());
+}
+
+/// Bucket layout on a page buffer
+mod bucket {
+    use super::*;
+
+    pub fn count(buf: &[u8]) -> usize {
+        read_u64(buf, COUNT) as usize
+    }
+
+    pub fn entry(buf: &[u8], i: usize) -> (u64, u64) {
+        let at = ENTRIES + i * ENTRY_SIZE;
+        (read_u64(buf, at), read_u64(buf, at + 8))
+    }
+
+    pub fn find(buf: &[u8], key: u64) -> Option<usize> {
+        (0..count(buf)).find(|&i| entry(buf, i).0 == key)
+    }
+
+    pub fn set(buf: &mut [u8], i: usize, key: u64, value: u64) {
+        let at = ENTRIES + i * ENTRY_SIZE;
+        write_u64(buf, at, key);
+        write_u64(buf, at + 8, value);
+    }
+
+    /// The caller checked there is room
+    pub fn push(buf: &mut [u8], key: u64, value: u64) {
+        let count = count(buf);
+        set(buf, count, key, value);
+        write_u64(buf, COUNT, count as u64 + 1);
+    }
+
+    pub fn swap_remove(buf: &mut [u8], i: usize) {
+        let last = count(buf) - 1;
+        let (key, value) = entry(buf, last);
+        set(buf, i, key, value);
+        write_u64(buf, COUNT, last as u64);
+    }
+
+    /// Move the entries with hash bit `local_depth` set to the empty page `sibling`
+    pub fn split(buf: &mut [u8], sibling: &mut [u8], local_depth: u64) {
+        write_u64(buf, LOCAL_DEPTH, local_depth + 1);
+        write_u64(sibling, LOCAL_DEPTH, local_depth + 1);
+        write_u64(sibling, COUNT, 0);
+
+        let mut i = 0;
+        while i < count(buf) {
+            let (key, value) = entry(buf, i);
+            if hash(key) & (1 << local_depth) != 0 {
+                push(sibling, key, value);
+                swap_remove(buf, i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicU64, Ordering},
+        thread,
+    };
+
+    use rustix::fd::AsFd;
+
+    use super::*;
+    use crate::{testutil::TempFile, OwnedExmapFd};
+
+    #[test]
+    fn bucket_split() {
+        let mut page = vec![0; 4096];
+        let mut sibling = vec![0; 4096];
+        for key in 0..100 {
+            bucket::push(&mut page, key, key * 2);
+        }
+        let i = bucket::find(&page, 7).unwrap();
+        bucket::swap_remove(&mut page, i);
+        assert_eq!(bucket::find(&page, 7), None);
+        assert_eq!(bucket::count(&page), 99);
+
+        bucket::split(&mut page, &mut sibling, 0);
+        assert_eq!(bucket::count(&page) + bucket::count(&sibling), 99);
+        assert!((0..bucket::count(&sibling)).all(|i| hash(bucket::entry(&sibling, i).0) & 1 == 1));
+        assert!((0..bucket::count(&page)).all(|i| hash(bucket::entry(&page, i).0) & 1 == 0));
+        let holder = if hash(99) & 1 == 1 { &sibling } else { &page };
+        let i = bucket::find(holder, 99).unwrap();
+        assert_eq!(bucket::entry(holder, i), (99, 198));
+        assert_eq!(read_u64(&sibling, LOCAL_DEPTH), 1);
+    }
+
+    #[test]
+    fn directory_layout() {
+        // 510 entries per 4 KiB page after the header
+        assert_eq!(HashIndex::<4096>::locate(0), (0, BASE));
+        assert_eq!(HashIndex::<4096>::locate(510), (1, BASE));
+        assert_eq!(HashIndex::<4096>::locate(511), (1, BASE + 8));
+    }
+
+    /// Writers fill the same buckets, so splits and directory doublings race each other and
+    /// the optimistic lookups, which restart whenever a split repointed what they read
+    #[test]
+    fn concurrent_writers() {
+        const THREADS: u64 = 4;
+        const KEYS: u64 = 20_000;
+
+        let path = TempFile::new("hash");
+        let backing = path.create();
+        let exmap_fd = OwnedExmapFd::<4096>::open().unwrap();
+        let mem = exmap_fd
+            .create(2048 * 4096, THREADS as u16, 64, Some(backing.as_fd()))
+            .unwrap();
+        let bm = unsafe { BufferManager::new(&exmap_fd, mem, THREADS as u16, 64) }.unwrap();
+        let index = HashIndex::create(&bm).unwrap();
+
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let index = &index;
+                s.spawn(move || {
+                    for i in 0..KEYS {
+                        let key = i * THREADS + t;
+                        assert_eq!(index.insert(key, !key).unwrap(), None);
+                        let old = i / 2 * THREADS + t;
+                        assert_eq!(index.get(old).unwrap(), Some(!old));
+                    }
+                });
+            }
+        });
+        // Every thread removes its odd keys
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let index = &index;
+                s.spawn(move || {
+                    for i in (1..KEYS).step_by(2) {
+                        let key = i * THREADS + t;
+                        assert_eq!(index.remove(key).unwrap(), Some(!key));
+                    }
+                    assert_eq!(index.insert(t, t).unwrap(), Some(!t));
+                });
+            }
+        });
+
+        for key in 0..KEYS * THREADS {
+            let expected = match key / THREADS % 2 {
+                _ if key < THREADS => Some(key),
+                0 => Some(!key),
+                _ => None,
+            };
+            assert_eq!(index.get(key).unwrap(), expected);
+        }
+        // All keys were in at once, which takes at least 315 buckets, so the directory doubled
+        // past its first page
+        let meta = bm.fix_s(index.meta()).unwrap();
+        assert!(read_u64(&meta[..], GLOBAL_DEPTH) >= 9);
+        assert!(read_u64(&meta[..], DIRECTORY_PAGES) > 1);
+        drop(meta);
+
+        bm.close().unwrap();
+    }
+
+    /// Readers look up keys while a writer keeps doubling the directory, and check every
+    /// answer against a model of what the writer had inserted before and after the lookup
+    #[test]
+    fn lookups_during_doublings() {
+        const READERS: u64 = 3;
+        const KEYS: u64 = 80_000;
+
+        let path = TempFile::new("hash-lookups");
+        let backing = path.create();
+        let exmap_fd = OwnedExmapFd::<4096>::open().unwrap();
+        let mem = exmap_fd
+            .create(2048 * 4096, READERS as u16 + 1, 64, Some(backing.as_fd()))
+            .unwrap();
+        let bm = unsafe { BufferManager::new(&exmap_fd, mem, READERS as u16 + 1, 64) }.unwrap();
+        let index = HashIndex::create(&bm).unwrap();
+        // Keys below this are in the index
+        let inserted = AtomicU64::new(0);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for key in 0..KEYS {
+                    assert_eq!(index.insert(key, !key).unwrap(), None);
+                    inserted.store(key + 1, Ordering::Release);
+                }
+            });
+            for r in 0..READERS {
+                let (index, inserted) = (&index, &inserted);
+                s.spawn(move || {
+                    let check = |key: u64| {
+                        let before = inserted.load(Ordering::Acquire);
+                        let found = index.get(key).unwrap();
+                        let after = inserted.load(Ordering::Acquire);
+                        match found {
+                            Some(value) => assert!(value == !key && key < after, "key {key}"),
+                            None => assert!(key >= before, "lost key {key}"),
+                        }
+                    };
+                    // Stride over the keys, past the inserted ones too, and check the newest
+                    let mut key = r;
+                    while inserted.load(Ordering::Acquire) < KEYS {
+                        check(key);
+                        check(inserted.load(Ordering::Acquire).saturating_sub(1));
+                        key = (key + 7919) % (KEYS + 100);
+                    }
+                });
+            }
+        });
+
+        let meta = bm.fix_s(index.meta()).unwrap();
+        assert!(read_u64(&meta[..], DIRECTORY_PAGES) > 1);
+        drop(meta);
+        bm.close().unwrap();
+    }
+}
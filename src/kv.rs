@@ -0,0 +1,319 @@
+//! Embedded key value store on a backing file.
+//!
+//! The file starts with a [`Superblock`] and the bitmap of a [`PageAllocator`]. The first data
+//! page is the meta page of a [`BTree`] holding the entries, and every page of the file is
+//! faulted in and written back by a [`BufferManager`] with the file as exmap backing fd.
+//!
+//! [`Kv::with_file`] sets all of this up for the duration of a closure. [`Kv::open`] works on a
+//! buffer manager the caller built, for control over the exmap setup.
+//!
+//! There is no logging: after a crash the file holds whatever pages were written back, and
+//! [`Kv::was_clean`] reports it.
+
+use std::{
+    collections::VecDeque,
+    ops::{Bound, Deref},
+    path::Path,
+};
+
+use rustix::{
+    fd::{AsFd, BorrowedFd},
+    fs::{self, Mode, OFlags},
+    io,
+};
+
+use crate::{
+    allocator::PageAllocator,
+    bm::BufferManager,
+    btree::{self, BTree},
+    superblock::Superblock,
+    Error, OwnedExmapFd, Result,
+};
+
+/// Entries fetched per tree scan by [`Range`]
+const RANGE_BATCH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvOptions {
+    /// Size of a new file in pages, including the superblock and bitmap. An existing file
+    /// keeps its size.
+    pub page_count: u64,
+    /// Pages of physical memory for the exmap
+    pub buffer_size: usize,
+    pub interfaces: u16,
+}
+
+impl Default for KvOptions {
+    fn default() -> Self {
+        Self {
+            page_count: 1 << 18,
+            buffer_size: 1 << 14,
+            interfaces: 4,
+        }
+    }
+}
+
+/// Unmaps the buffer manager of [`Kv::with_file`] on every way out
+struct Mapped<'a, 'b, const P: usize>(Option<BufferManager<'a, 'b, P>>);
+
+impl<'a, 'b, const P: usize> Mapped<'a, 'b, P> {
+    fn close(mut self) -> io::Result<()> {
+        self.0.take().expect("mapped until dropped").close()
+    }
+}
+
+impl<'a, 'b, const P: usize> Deref for Mapped<'a, 'b, P> {
+    type Target = BufferManager<'a, 'b, P>;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref().expect("mapped until dropped")
+    }
+}
+
+impl<'a, 'b, const P: usize> Drop for Mapped<'a, 'b, P> {
+    fn drop(&mut self) {
+        if let Some(bm) = self.0.take() {
+            let _ = bm.close();
+        }
+    }
+}
+
+pub struct Kv<'bm, 'a, 'b, const P: usize> {
+    bm: &'bm BufferManager<'a, 'b, P>,
+    tree: BTree<'bm, 'a, 'b, P>,
+    fd: BorrowedFd<'b>,
+    sb: Superblock,
+}
+
+impl<'bm, 'a, 'b, const P: usize> Kv<'bm, 'a, 'b, P> {
+    /// Open or create the store in the file at `path` and run `f` on it. The store is synced
+    /// and marked clean once `f` succeeds.
+    pub fn with_file<T>(
+        path: impl AsRef<Path>,
+        options: KvOptions,
+        f: impl FnOnce(&Kv<'_, '_, '_, P>) -> Result<T>,
+    ) -> Result<T> {
+        let file = fs::openat(
+            fs::cwd(),
+            path.as_ref(),
+            OFlags::RDWR | OFlags::CREATE | OFlags::CLOEXEC,
+            Mode::from_raw_mode(0o600),
+        )?;
+
+        let (sb, allocator) = match Superblock::open::<P, _>(&file, None) {
+            Ok(sb) => (sb, PageAllocator::from_superblock(file.as_fd(), &sb)?),
+            Err(Error::NotFormatted) => {
+                let sb = Superblock::create::<P, _>(&file, options.page_count)?;
+                let allocator = PageAllocator::format(file.as_fd(), sb.page_count)?;
+                // Nothing to recover in a new file
+                (Superblock { clean: true, ..sb }, allocator)
+            }
+            Err(e) => return Err(e),
+        };
+
+        let exmap_fd = OwnedExmapFd::<P>::open()?;
+        let mem = exmap_fd.create(
+            sb.exmap_size(),
+            options.interfaces,
+            options.buffer_size,
+            Some(file.as_fd()),
+        )?;
+        let bm = Mapped(Some(
+            unsafe { BufferManager::new(&exmap_fd, mem, options.interfaces, options.buffer_size)? }
+                .with_allocator(allocator),
+        ));
+
+        let kv = Kv::open(&bm, file.as_fd(), sb)?;
+        let res = f(&kv)?;
+        kv.close()?;
+        bm.close()?;
+        Ok(res)
+    }
+
+    /// Open the store on `bm`, whose backing fd and allocator belong to `fd`. `sb` is what
+    /// [`Superblock::open`] or [`Superblock::create`] returned for it. An empty file gets a
+    /// new tree.
+    pub fn open(
+        bm: &'bm BufferManager<'a, 'b, P>,
+        fd: BorrowedFd<'b>,
+        sb: Superblock,
+    ) -> Result<Self> {
+        let allocator = bm.allocator().ok_or(io::Errno::INVAL)?;
+        let meta = allocator.first_data_page();
+
+        let tree = if allocator.is_allocated(meta) {
+            BTree::open(bm, meta)
+        } else {
+            let tree = BTree::create(bm)?;
+            if tree.meta() != meta {
+                return Err(Error::Mismatch {
+                    what: "tree meta page",
+                    expected: meta,
+                    found: tree.meta(),
+                });
+            }
+            tree
+        };
+
+        Ok(Self { bm, tree, fd, sb })
+    }
+
+    /// Whether the file was closed cleanly before it was opened, or is new
+    pub fn was_clean(&self) -> bool {
+        self.sb.clean
+    }
+
+    /// Largest key plus value that can be stored
+    pub const fn max_entry() -> usize {
+        BTree::<P>::MAX_ENTRY
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.tree.lookup(key)
+    }
+
+    /// Insert or replace. Fails with `EINVAL` past [`max_entry`](Self::max_entry).
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.tree.insert(key, value)
+    }
+
+    /// Remove `key`, returning whether it was present
+    pub fn delete(&self, key: &[u8]) -> Result<bool> {
+        self.tree.remove(key)
+    }
+
+    /// Entries with keys between `start` and `end` in key order
+    pub fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Range<'_, 'bm, 'a, 'b, P> {
+        Range {
+            kv: self,
+            next: btree::to_owned(start),
+            end: btree::to_owned(end),
+            batch: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// All entries in key order
+    pub fn iter(&self) -> Range<'_, 'bm, 'a, 'b, P> {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Write back the dirty pages and the allocator bitmap
+    pub fn sync(&self) -> Result<()> {
+        self.bm.flush()?;
+        if let Some(allocator) = self.bm.allocator() {
+            allocator.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Sync and mark the file as cleanly closed
+    pub fn close(mut self) -> Result<()> {
+        self.sync()?;
+        Ok(self.sb.close(self.fd)?)
+    }
+}
+
+/// Iterator over a key range, see [`Kv::range`]. Entries are fetched in batches and no latch
+/// is held between calls to `next`, so concurrent changes may or may not be seen.
+pub struct Range<'kv, 'bm, 'a, 'b, const P: usize> {
+    kv: &'kv Kv<'bm, 'a, 'b, P>,
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    batch: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+impl<'kv, 'bm, 'a, 'b, const P: usize> Range<'kv, 'bm, 'a, 'b, P> {
+    fn fill(&mut self) -> Result<()> {
+        let (end, batch) = (&self.end, &mut self.batch);
+        let mut exhausted = true;
+        self.kv.tree.scan(as_slice(&self.next), |key, value| {
+            let in_range = match end {
+                Bound::Included(end) => key <= end.as_slice(),
+                Bound::Excluded(end) => key < end.as_slice(),
+                Bound::Unbounded => true,
+            };
+            if !in_range {
+                return false;
+            }
+
+            batch.push_back((key.to_vec(), value.to_vec()));
+            exhausted = batch.len() < RANGE_BATCH;
+            exhausted
+        })?;
+
+        self.done = exhausted;
+        if let Some((key, _)) = self.batch.back() {
+            self.next = Bound::Excluded(key.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Borrow the key of `bound`
+fn as_slice(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+impl<'kv, 'bm, 'a, 'b, const P: usize> Iterator for Range<'kv, 'bm, 'a, 'b, P> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty() && !self.done {
+            if let Err(e) = self.fill() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.batch.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempFile;
+
+    #[test]
+    fn put_get_reopen() {
+        let path = TempFile::new("kv");
+        let options = KvOptions {
+            page_count: 4096,
+            buffer_size: 256,
+            interfaces: 2,
+        };
+
+        Kv::<4096>::with_file(&path, options, |kv| {
+            assert!(kv.was_clean());
+            for i in 0u32..2000 {
+                kv.put(&i.to_be_bytes(), &[i as u8; 100])?;
+            }
+            assert!(kv.delete(&7u32.to_be_bytes())?);
+            Ok(())
+        })
+        .unwrap();
+
+        Kv::<4096>::with_file(&path, options, |kv| {
+            assert!(kv.was_clean());
+            assert_eq!(kv.get(&8u32.to_be_bytes())?, Some(vec![8; 100]));
+            assert_eq!(kv.get(&7u32.to_be_bytes())?, None);
+
+            let keys = kv
+                .range(
+                    Bound::Included(&5u32.to_be_bytes()[..]),
+                    Bound::Excluded(&200u32.to_be_bytes()[..]),
+                )
+                .map(|entry| entry.map(|(key, _)| u32::from_be_bytes(key.try_into().unwrap())))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(keys.len(), 194);
+            assert_eq!(keys[..3], [5, 6, 8]);
+            Ok(())
+        })
+        .unwrap();
+    }
+}
@@ -1,4 +1,37 @@
+pub mod allocator;
+pub mod blob;
+pub mod bm;
+pub mod btree;
+pub mod cachesim;
+pub mod checksum;
+pub mod config;
+pub mod direct;
+mod error;
+mod fileio;
+pub mod hash;
+pub mod kv;
+pub mod pool;
+pub mod psi;
+pub mod residency;
+mod retry;
+pub mod slotted;
+pub mod superblock;
 mod sys;
+pub mod tablespace;
+#[cfg(test)]
+mod testutil;
+pub mod trace;
+#[cfg(feature = "sqlite")]
+pub mod vfs;
+pub mod wal;
+pub mod workload;
+
+pub use config::ExmapConfig;
+pub use error::{Error, Result};
+pub use retry::{Evict, IovFailure, RetryOutcome};
+
+use residency::Residency;
+use trace::{Opcode, Tracer};
 
 use std::{
     ffi::c_void,
@@ -26,13 +59,22 @@ pub struct InterfaceWrapper<'a, T> {
     state: PhantomData<T>,
 }
 
+/// The result of an operation that consumes an interface. A failed ioctl hands the interface
+/// back, emptied, together with the error.
+pub type OpResult<'a, T> = std::result::Result<T, (InterfaceWrapper<'a, InterfaceIov>, io::Errno)>;
+
+// SAFETY:
+// The interface memory is only accessed through the wrapper, which is not `Sync`
+unsafe impl<'a, T> Send for InterfaceWrapper<'a, T> {}
+
 const MMAP_INTERFACE: usize = std::mem::size_of::<sys::exmap_user_interface>() as usize;
 
 impl<'a, T> InterfaceWrapper<'a, T> {
     pub const MAX_COUNT: usize = sys::EXMAP_USER_INTERFACE_PAGES as usize;
+    /// Longest run of pages a single iov can cover
+    pub const MAX_PAGES: u64 = sys::EXMAP_PAGE_MAX_PAGES as u64 - 1;
 
     pub fn unmap(self) -> io::Result<()> {
-        println!("drop interface[{}] at {:p}", self.index, self.data);
         unsafe { mm::munmap(self.data as *mut _, MMAP_INTERFACE) }
     }
 
@@ -68,18 +110,41 @@ impl<'a> InterfaceWrapper<'a, InterfaceResult> {
 }
 
 impl<'a> InterfaceWrapper<'a, InterfaceIov> {
-    pub fn alloc(self) -> (InterfaceWrapper<'a, InterfaceResult>, u16) {
-        // Result is stored in the memory map
-        let res = self.exmap_fd.alloc(self.index, self.len).unwrap();
+    /// Returns the number of iovs that failed. Per iov results are stored in the interface.
+    pub fn alloc(self) -> OpResult<'a, (InterfaceWrapper<'a, InterfaceResult>, u16)> {
+        self.issue(Opcode::Alloc)
+    }
 
-        (unsafe { self.into_res() }, res)
+    pub fn free(self) -> OpResult<'a, (InterfaceWrapper<'a, InterfaceResult>, u16)> {
+        self.issue(Opcode::Free)
     }
 
-    pub fn free(self) -> (InterfaceWrapper<'a, InterfaceResult>, u16) {
-        // Result is stored in the memory map
-        let res = self.exmap_fd.free(self.index, self.len).unwrap();
+    /// Allocate the pages and fill them from the backing fd
+    pub fn read(self) -> OpResult<'a, (InterfaceWrapper<'a, InterfaceResult>, u16)> {
+        self.issue(Opcode::Read)
+    }
+
+    /// Write the pages to the backing fd
+    pub fn write(self) -> OpResult<'a, (InterfaceWrapper<'a, InterfaceResult>, u16)> {
+        self.issue(Opcode::Write)
+    }
 
-        (unsafe { self.into_res() }, res)
+    /// Issue `opcode` on the pushed iovs, keeping the interface if the ioctl fails
+    fn issue(
+        mut self,
+        opcode: Opcode,
+    ) -> OpResult<'a, (InterfaceWrapper<'a, InterfaceResult>, u16)> {
+        // Result is stored in the memory map
+        match self
+            .exmap_fd
+            .action(self.index, self.data, self.len, opcode)
+        {
+            Ok(res) => Ok((unsafe { self.into_res() }, res)),
+            Err(e) => {
+                self.len = 0;
+                Err((self, e))
+            }
+        }
     }
 
     unsafe fn into_res(self) -> InterfaceWrapper<'a, InterfaceResult> {
@@ -151,12 +216,22 @@ impl<'b> IndexMut<u16> for InterfaceWrapper<'b, InterfaceIov> {
 }
 
 #[derive(Debug)]
-pub struct OwnedExmapFd<const PAGE_SIZE: usize>(OwnedFd);
+pub struct OwnedExmapFd<const PAGE_SIZE: usize>(OwnedFd, Option<Tracer>);
 
 impl<const PAGE_SIZE: usize> OwnedExmapFd<PAGE_SIZE> {
     pub fn open() -> io::Result<OwnedExmapFd<PAGE_SIZE>> {
         let fd = fs::openat(fs::cwd(), "/dev/exmap", OFlags::RDWR, Mode::empty())?;
-        Ok(OwnedExmapFd(fd))
+        Ok(OwnedExmapFd(fd, None))
+    }
+
+    /// Record every action issued through the fd to `tracer`
+    pub fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.1 = Some(tracer);
+        self
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.1.as_ref()
     }
 
     #[inline]
@@ -181,11 +256,6 @@ impl<const PAGE_SIZE: usize> OwnedExmapFd<PAGE_SIZE> {
         let interface_num = EXMAP_OFF_INTERFACE(index.into()) as u64;
         let data = self._mmap(MMAP_INTERFACE, interface_num)? as *mut sys::exmap_user_interface;
 
-        println!(
-            "mmap interface[{}] at address {:#X}",
-            interface_num, data as usize
-        );
-
         Ok(InterfaceWrapper {
             data,
             len: 0,
@@ -235,7 +305,10 @@ impl<const PAGE_SIZE: usize> OwnedExmapFd<PAGE_SIZE> {
         let data = self.mmap_vm(exmap_size)?;
 
         // Configure exmap
-        let _ = self.setup(backing_fd, max_interfaces, buffer_size)?;
+        if let Err(e) = self.setup(backing_fd, max_interfaces, buffer_size) {
+            unsafe { mm::munmap(data.cast(), exmap_size) }?;
+            return Err(e);
+        }
 
         Ok(VirtMem {
             exmap_fd: self.as_fd(),
@@ -246,46 +319,67 @@ impl<const PAGE_SIZE: usize> OwnedExmapFd<PAGE_SIZE> {
     }
 
     fn as_fd(&self) -> BorrowedExmapFd<'_> {
-        BorrowedExmapFd(self.0.as_fd())
-    }
-}
-
-impl<const PAGE_SIZE: usize> Drop for OwnedExmapFd<PAGE_SIZE> {
-    fn drop(&mut self) {
-        println!("Dropping file descriptor");
+        BorrowedExmapFd(self.0.as_fd(), self.1.as_ref())
     }
 }
 
 impl<const PAGE_SIZE: usize> FromRawFd for OwnedExmapFd<PAGE_SIZE> {
     unsafe fn from_raw_fd(fd: rustix::fd::RawFd) -> OwnedExmapFd<PAGE_SIZE> {
-        unsafe { OwnedExmapFd(OwnedFd::from_raw_fd(fd)) }
+        unsafe { OwnedExmapFd(OwnedFd::from_raw_fd(fd), None) }
     }
 }
 
 #[derive(Debug)]
-pub struct BorrowedExmapFd<'a>(BorrowedFd<'a>);
+pub struct BorrowedExmapFd<'a>(BorrowedFd<'a>, Option<&'a Tracer>);
 
 impl<'a> BorrowedExmapFd<'a> {
-    fn alloc(&self, interface: u16, iov_len: u16) -> io::Result<u16> {
+    /// Run `opcode` on the first `iov_len` iovs of the interface mapped at `data`
+    fn action(
+        &self,
+        interface: u16,
+        data: *mut sys::exmap_user_interface,
+        iov_len: u16,
+        opcode: Opcode,
+    ) -> io::Result<u16> {
         let params = sys::exmap_action_params {
             interface,
             iov_len,
-            opcode: sys::EXMAP_OP_ALLOC as u16,
+            opcode: opcode.raw() as u16,
             flags: 0, // TODO: Figure out flag situation
         };
 
-        unsafe { sys::exmap_ioctl(&self.0, &params).map(|c| c as u16) }
-    }
+        let Some(tracer) = self.1 else {
+            return unsafe { sys::exmap_ioctl(&self.0, &params).map(|c| c as u16) };
+        };
 
-    fn free(&self, interface: u16, iov_len: u16) -> io::Result<u16> {
-        let params = sys::exmap_action_params {
-            interface,
-            iov_len,
-            opcode: sys::EXMAP_OP_FREE as u16,
-            flags: 0, // TODO: Figure out flag situation
+        // The results overwrite the iovs, so only look at them around the ioctl
+        let iovs = || unsafe { &(&(*data).anon1.iov)[..iov_len.into()] };
+        let requested = iovs()
+            .iter()
+            .map(|v| unsafe { (v.anon1.anon1.page(), v.anon1.anon1.len()) })
+            .collect();
+        let start = tracer.now();
+        let ret = unsafe { sys::exmap_ioctl(&self.0, &params).map(|c| c as u16) };
+        let duration = tracer.now() - start;
+        let results = match ret {
+            Ok(_) => iovs()
+                .iter()
+                .map(|v| unsafe { (v.anon1.anon2.res, v.anon1.anon2.pages) })
+                .collect(),
+            Err(_) => Vec::new(),
         };
 
-        unsafe { sys::exmap_ioctl(&self.0, &params).map(|c| c as u16) }
+        tracer.record(&trace::Record {
+            start,
+            duration,
+            interface,
+            opcode,
+            flags: params.flags,
+            iovs: requested,
+            ret: ret.map_err(|e| e.raw_os_error()),
+            results,
+        });
+        ret
     }
 }
 
@@ -297,14 +391,6 @@ pub struct VirtMem<'a, 'b, const PAGE_SIZE: usize> {
 }
 
 impl<'a, 'b, const P: usize> VirtMem<'a, 'b, P> {
-    pub fn read() {
-        todo!()
-    }
-
-    pub fn readv() {
-        todo!()
-    }
-
     #[inline]
     pub fn as_ptr(&self) -> *const u8 {
         self.data
@@ -320,9 +406,51 @@ impl<'a, 'b, const P: usize> VirtMem<'a, 'b, P> {
         self.size
     }
 
-    pub fn unmap(self) {
-        println!("unmap vmmap");
-        unsafe { mm::munmap(self.as_mut_ptr().cast(), self.size()) }.unwrap();
+    /// Number of pages in the mapping
+    #[inline]
+    pub fn page_count(&self) -> u64 {
+        (self.size / P) as u64
+    }
+
+    pub fn page(&self, pid: u64) -> &[u8; P] {
+        assert!(pid < self.page_count(), "page {pid} out of range");
+        unsafe { &*self.data.add(pid as usize * P).cast() }
+    }
+
+    pub fn page_mut(&mut self, pid: u64) -> &mut [u8; P] {
+        assert!(pid < self.page_count(), "page {pid} out of range");
+        unsafe { &mut *self.data.add(pid as usize * P).cast() }
+    }
+
+    /// Which pages are backed by memory, according to `mincore`. Assumes `P` is the host page
+    /// size, as exmap does.
+    pub fn residency(&self) -> io::Result<Residency> {
+        // Ask about a bounded number of pages at a time so huge VMAs need little memory
+        const CHUNK: u64 = 1 << 16;
+
+        let mut vec = vec![0u8; CHUNK.min(self.page_count()) as usize];
+        let mut chunk = 0..0;
+        let mut error = None;
+        let residency = Residency::from_fn(self.page_count(), |pid| {
+            if pid >= chunk.end && error.is_none() {
+                chunk = pid..(pid + CHUNK).min(self.page_count());
+                let len = (chunk.end - chunk.start) as usize;
+                let addr = unsafe { self.data.add(pid as usize * P) };
+                if let Err(e) = unsafe { sys::mincore(addr, len * P, &mut vec[..len]) } {
+                    error = Some(e);
+                }
+            }
+            error.is_none() && vec[(pid - chunk.start) as usize] & 1 != 0
+        });
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(residency),
+        }
+    }
+
+    pub fn unmap(self) -> io::Result<()> {
+        unsafe { mm::munmap(self.as_mut_ptr().cast(), self.size()) }
     }
 }
 
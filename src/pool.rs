@@ -0,0 +1,408 @@
+//! A classic buffer pool, as the baseline the exmap [`BufferManager`](crate::bm::BufferManager)
+//! is measured against.
+//!
+//! A hash table maps page ids to a fixed set of frames and pages are copied in and out with
+//! `pread` and `pwrite`. Victims are picked by the same second chance clock, so benchmarks
+//! compare how pages get into memory rather than the eviction policy. Frames are aligned to the
+//! page size, so the file may be opened with `O_DIRECT`.
+
+use std::{
+    alloc::{self, Layout},
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread,
+};
+
+use rustix::{fd::BorrowedFd, fs, io};
+
+use crate::{bm::BufferPool, fileio, Result};
+
+/// Page id of a frame that holds no page
+const NO_PAGE: u64 = u64::MAX;
+
+struct Frame {
+    latch: RwLock<()>,
+    /// Only raised with the table locked, so a frame without pins can be taken over
+    pins: AtomicUsize,
+    referenced: AtomicBool,
+    dirty: AtomicBool,
+    /// Only changed with the table locked and the latch held exclusively
+    pid: AtomicU64,
+}
+
+struct Table {
+    frames: HashMap<u64, usize>,
+    free: Vec<usize>,
+    hand: usize,
+}
+
+enum Victim {
+    Clean(usize),
+    Dirty(usize),
+    None,
+}
+
+pub struct PreadPool<'f, const P: usize> {
+    fd: BorrowedFd<'f>,
+    frames: Box<[Frame]>,
+    data: NonNull<u8>,
+    table: Mutex<Table>,
+    faults: AtomicU64,
+}
+
+// SAFETY:
+// The frame data is only accessed through guards holding the frame latch.
+unsafe impl<'f, const P: usize> Send for PreadPool<'f, P> {}
+unsafe impl<'f, const P: usize> Sync for PreadPool<'f, P> {}
+
+impl<'f, const P: usize> PreadPool<'f, P> {
+    /// A pool of `capacity` frames over the pages of `fd`
+    pub fn new(fd: BorrowedFd<'f>, capacity: usize) -> Self {
+        assert!(capacity > 0);
+        let data = unsafe { alloc::alloc_zeroed(Self::layout(capacity)) };
+        let data =
+            NonNull::new(data).unwrap_or_else(|| alloc::handle_alloc_error(Self::layout(capacity)));
+
+        let frames = (0..capacity)
+            .map(|_| Frame {
+                latch: RwLock::new(()),
+                pins: AtomicUsize::new(0),
+                referenced: AtomicBool::new(false),
+                dirty: AtomicBool::new(false),
+                pid: AtomicU64::new(NO_PAGE),
+            })
+            .collect();
+
+        Self {
+            fd,
+            frames,
+            data,
+            table: Mutex::new(Table {
+                frames: HashMap::with_capacity(capacity),
+                free: (0..capacity).rev().collect(),
+                hand: 0,
+            }),
+            faults: AtomicU64::new(0),
+        }
+    }
+
+    fn layout(capacity: usize) -> Layout {
+        Layout::from_size_align(capacity * P, P).expect("page size is a power of two")
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.frames.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Table> {
+        self.table.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn frame_ptr(&self, frame: usize) -> *mut u8 {
+        unsafe { self.data.as_ptr().add(frame * P) }
+    }
+
+    /// Pin the frame holding `pid`, reading the page in if needed
+    fn pin(&self, pid: u64) -> Result<usize> {
+        loop {
+            let mut table = self.lock();
+            if let Some(&f) = table.frames.get(&pid) {
+                self.frames[f].pins.fetch_add(1, Ordering::AcqRel);
+                self.frames[f].referenced.store(true, Ordering::Relaxed);
+                return Ok(f);
+            }
+
+            let f = match table.free.pop() {
+                Some(f) => f,
+                None => match self.victim(&mut table) {
+                    Victim::Clean(f) => {
+                        let old = self.frames[f].pid.load(Ordering::Acquire);
+                        table.frames.remove(&old);
+                        f
+                    }
+                    Victim::Dirty(f) => {
+                        // Write it back without the table locked, then look again
+                        self.frames[f].pins.fetch_add(1, Ordering::AcqRel);
+                        drop(table);
+                        let res = self.write_back(f);
+                        self.unpin(f);
+                        res?;
+                        continue;
+                    }
+                    Victim::None => {
+                        drop(table);
+                        thread::yield_now();
+                        continue;
+                    }
+                },
+            };
+
+            let frame = &self.frames[f];
+            frame.pins.store(1, Ordering::Release);
+            frame.referenced.store(true, Ordering::Relaxed);
+            let latch = frame.latch.write().unwrap_or_else(PoisonError::into_inner);
+            frame.pid.store(pid, Ordering::Release);
+            table.frames.insert(pid, f);
+            drop(table);
+
+            // Whoever finds the page meanwhile waits on the latch
+            let page = unsafe { std::slice::from_raw_parts_mut(self.frame_ptr(f), P) };
+            if let Err(e) = fileio::pread_exact(self.fd, page, pid * P as u64) {
+                let mut table = self.lock();
+                table.frames.remove(&pid);
+                frame.pid.store(NO_PAGE, Ordering::Release);
+                drop(table);
+                drop(latch);
+                self.unpin(f);
+                return Err(e.into());
+            }
+            self.faults.fetch_add(1, Ordering::Relaxed);
+            return Ok(f);
+        }
+    }
+
+    fn unpin(&self, frame: usize) {
+        self.frames[frame].pins.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// Second chance clock over the unpinned frames
+    fn victim(&self, table: &mut Table) -> Victim {
+        for _ in 0..2 * self.frames.len() {
+            let f = table.hand;
+            table.hand = (table.hand + 1) % self.frames.len();
+
+            let frame = &self.frames[f];
+            if frame.pins.load(Ordering::Acquire) > 0
+                || frame.referenced.swap(false, Ordering::Relaxed)
+            {
+                continue;
+            }
+            return match frame.dirty.load(Ordering::Acquire) {
+                true => Victim::Dirty(f),
+                false => Victim::Clean(f),
+            };
+        }
+        Victim::None
+    }
+
+    /// Write a pinned frame back if it is dirty
+    fn write_back(&self, f: usize) -> io::Result<bool> {
+        let frame = &self.frames[f];
+        let _latch = frame.latch.read().unwrap_or_else(PoisonError::into_inner);
+        if !frame.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(false);
+        }
+
+        let pid = frame.pid.load(Ordering::Acquire);
+        let page = unsafe { std::slice::from_raw_parts(self.frame_ptr(f), P) };
+        if let Err(e) = fileio::pwrite_all(self.fd, page, pid * P as u64) {
+            frame.dirty.store(true, Ordering::Release);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Latch the page exclusively, reading it in if needed
+    pub fn fix_x(&self, pid: u64) -> Result<ExclusiveGuard<'_, 'f, P>> {
+        loop {
+            let frame = self.pin(pid)?;
+            let latch = self.frames[frame].latch.write();
+            let latch = latch.unwrap_or_else(PoisonError::into_inner);
+            if self.frames[frame].pid.load(Ordering::Acquire) == pid {
+                return Ok(ExclusiveGuard {
+                    pool: self,
+                    frame,
+                    latch: Some(latch),
+                });
+            }
+            // The read failed for whoever was bringing the page in
+            drop(latch);
+            self.unpin(frame);
+        }
+    }
+
+    /// Latch the page shared, reading it in if needed
+    pub fn fix_s(&self, pid: u64) -> Result<SharedGuard<'_, 'f, P>> {
+        loop {
+            let frame = self.pin(pid)?;
+            let latch = self.frames[frame].latch.read();
+            let latch = latch.unwrap_or_else(PoisonError::into_inner);
+            if self.frames[frame].pid.load(Ordering::Acquire) == pid {
+                return Ok(SharedGuard {
+                    pool: self,
+                    frame,
+                    latch: Some(latch),
+                });
+            }
+            drop(latch);
+            self.unpin(frame);
+        }
+    }
+
+    /// Write back every dirty page and make it durable, without evicting anything. Returns the
+    /// number of pages written. The caller must not hold an exclusive guard.
+    pub fn flush(&self) -> Result<usize> {
+        let mut written = 0;
+        for f in 0..self.frames.len() {
+            if !self.frames[f].dirty.load(Ordering::Acquire) {
+                continue;
+            }
+            // Pinned, the frame keeps its page while it is written
+            let table = self.lock();
+            self.frames[f].pins.fetch_add(1, Ordering::AcqRel);
+            drop(table);
+            let res = self.write_back(f);
+            self.unpin(f);
+            written += usize::from(res?);
+        }
+        fs::fdatasync(self.fd)?;
+        Ok(written)
+    }
+
+    /// Pages read in since the pool was created
+    pub fn faults(&self) -> u64 {
+        self.faults.load(Ordering::Relaxed)
+    }
+}
+
+impl<'f, const P: usize> Drop for PreadPool<'f, P> {
+    /// Dirty pages that were not [`flush`](Self::flush)ed are lost
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.data.as_ptr(), Self::layout(self.frames.len())) }
+    }
+}
+
+impl<'f, const P: usize> BufferPool<P> for PreadPool<'f, P> {
+    type Exclusive<'g>
+        = ExclusiveGuard<'g, 'f, P>
+    where
+        Self: 'g;
+    type Shared<'g>
+        = SharedGuard<'g, 'f, P>
+    where
+        Self: 'g;
+
+    fn fix_x(&self, pid: u64) -> Result<Self::Exclusive<'_>> {
+        PreadPool::fix_x(self, pid)
+    }
+
+    fn fix_s(&self, pid: u64) -> Result<Self::Shared<'_>> {
+        PreadPool::fix_s(self, pid)
+    }
+
+    fn flush(&self) -> Result<usize> {
+        PreadPool::flush(self)
+    }
+
+    fn faults(&self) -> u64 {
+        PreadPool::faults(self)
+    }
+}
+
+/// An exclusively latched page. Mutable access marks the page dirty.
+pub struct ExclusiveGuard<'p, 'f, const P: usize> {
+    pool: &'p PreadPool<'f, P>,
+    frame: usize,
+    /// Released before the frame is unpinned
+    latch: Option<RwLockWriteGuard<'p, ()>>,
+}
+
+impl<'p, 'f, const P: usize> Deref for ExclusiveGuard<'p, 'f, P> {
+    type Target = [u8; P];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*(self.pool.frame_ptr(self.frame) as *const [u8; P]) }
+    }
+}
+
+impl<'p, 'f, const P: usize> DerefMut for ExclusiveGuard<'p, 'f, P> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.pool.frames[self.frame]
+            .dirty
+            .store(true, Ordering::Release);
+        unsafe { &mut *(self.pool.frame_ptr(self.frame) as *mut [u8; P]) }
+    }
+}
+
+impl<'p, 'f, const P: usize> Drop for ExclusiveGuard<'p, 'f, P> {
+    fn drop(&mut self) {
+        self.latch.take();
+        self.pool.unpin(self.frame);
+    }
+}
+
+/// A page latched in shared mode
+pub struct SharedGuard<'p, 'f, const P: usize> {
+    pool: &'p PreadPool<'f, P>,
+    frame: usize,
+    latch: Option<RwLockReadGuard<'p, ()>>,
+}
+
+impl<'p, 'f, const P: usize> Deref for SharedGuard<'p, 'f, P> {
+    type Target = [u8; P];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*(self.pool.frame_ptr(self.frame) as *const [u8; P]) }
+    }
+}
+
+impl<'p, 'f, const P: usize> Drop for SharedGuard<'p, 'f, P> {
+    fn drop(&mut self) {
+        self.latch.take();
+        self.pool.unpin(self.frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustix::fd::AsFd;
+
+    use super::*;
+    use crate::testutil::TempFile;
+
+    const PAGE: usize = 4096;
+    const PAGES: u64 = 64;
+
+    #[test]
+    fn evicts_and_writes_back() {
+        let path = TempFile::new("pool");
+        let file = path.create();
+        fs::ftruncate(&file, PAGES * PAGE as u64).unwrap();
+
+        let pool = PreadPool::<PAGE>::new(file.as_fd(), 8);
+        thread::scope(|s| {
+            for t in 0..4u64 {
+                let pool = &pool;
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        let pid = (i * 7 + t * 13) % PAGES;
+                        let mut page = pool.fix_x(pid).unwrap();
+                        let count = u64::from_le_bytes(page[..8].try_into().unwrap());
+                        page[..8].copy_from_slice(&(count + 1).to_le_bytes());
+                    }
+                });
+            }
+        });
+        assert!(pool.faults() > PAGES);
+        assert!(matches!(
+            pool.fix_s(PAGES),
+            Err(crate::Error::Io(io::Errno::IO))
+        ));
+        pool.flush().unwrap();
+
+        let mut total = 0;
+        let mut page = [0; PAGE];
+        for pid in 0..PAGES {
+            fileio::pread_exact(&file, &mut page, pid * PAGE as u64).unwrap();
+            total += u64::from_le_bytes(page[..8].try_into().unwrap());
+        }
+        assert_eq!(total, 4000);
+
+        drop(pool);
+    }
+}
@@ -0,0 +1,249 @@
+//! Shrinks the buffer manager budget under Linux memory pressure.
+//!
+//! Reads a PSI file such as `/proc/pressure/memory` or a cgroup's `memory.pressure`:
+//!
+//! ```text
+//! some avg10=0.00 avg60=0.00 avg300=0.00 total=0
+//! full avg10=0.00 avg60=0.00 avg300=0.00 total=0
+//! ```
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use crate::bm::BufferManager;
+
+pub const PROC_MEMORY_PRESSURE: &str = "/proc/pressure/memory";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PressureLine {
+    pub avg10: f32,
+    pub avg60: f32,
+    pub avg300: f32,
+    /// Total stall time in microseconds
+    pub total: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Pressure {
+    pub some: PressureLine,
+    /// Not reported for the system wide cpu file
+    pub full: Option<PressureLine>,
+}
+
+impl Pressure {
+    pub fn parse(s: &str) -> io::Result<Self> {
+        let mut some = None;
+        let mut full = None;
+
+        for line in s.lines() {
+            let mut fields = line.split_whitespace();
+            let target = match fields.next() {
+                Some("some") => &mut some,
+                Some("full") => &mut full,
+                Some(_) => return Err(invalid(line)),
+                None => continue,
+            };
+
+            let mut parsed = PressureLine::default();
+            for field in fields {
+                let (key, value) = field.split_once('=').ok_or_else(|| invalid(line))?;
+                match key {
+                    "avg10" => parsed.avg10 = value.parse().map_err(|_| invalid(line))?,
+                    "avg60" => parsed.avg60 = value.parse().map_err(|_| invalid(line))?,
+                    "avg300" => parsed.avg300 = value.parse().map_err(|_| invalid(line))?,
+                    "total" => parsed.total = value.parse().map_err(|_| invalid(line))?,
+                    _ => {}
+                }
+            }
+            *target = Some(parsed);
+        }
+
+        Ok(Self {
+            some: some.ok_or_else(|| invalid(s))?,
+            full,
+        })
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+}
+
+fn invalid(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed pressure line: {line:?}"),
+    )
+}
+
+/// Once `some avg10` reaches `stall` percent, keep at most `keep` of `buffer_size` resident
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Threshold {
+    pub stall: f32,
+    pub keep: f32,
+}
+
+pub struct PressureWatcher {
+    path: PathBuf,
+    thresholds: Vec<Threshold>,
+}
+
+impl PressureWatcher {
+    pub fn new(path: impl Into<PathBuf>, mut thresholds: Vec<Threshold>) -> Self {
+        thresholds.sort_by(|a, b| a.stall.total_cmp(&b.stall));
+        Self {
+            path: path.into(),
+            thresholds,
+        }
+    }
+
+    /// Watch the system wide memory pressure with a default set of thresholds
+    pub fn system() -> Self {
+        Self::new(
+            PROC_MEMORY_PRESSURE,
+            vec![
+                Threshold {
+                    stall: 5.0,
+                    keep: 0.75,
+                },
+                Threshold {
+                    stall: 20.0,
+                    keep: 0.5,
+                },
+                Threshold {
+                    stall: 50.0,
+                    keep: 0.25,
+                },
+            ],
+        )
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Budget to use for `pressure`, given the budget without any pressure in `ceiling`
+    pub fn target(&self, pressure: &Pressure, ceiling: usize) -> usize {
+        let keep = self
+            .thresholds
+            .iter()
+            .rev()
+            .find(|t| pressure.some.avg10 >= t.stall)
+            .map_or(1.0, |t| t.keep.clamp(0.0, 1.0));
+
+        (ceiling as f64 * f64::from(keep)) as usize
+    }
+
+    /// Read the pressure file once and apply the resulting budget, scaled from `ceiling`, the
+    /// budget the caller configured. It never goes above `ceiling` or the kernel limit. Lowering
+    /// the budget evicts with batched frees. Returns the new budget if it changed.
+    pub fn poll<const P: usize>(
+        &self,
+        bm: &BufferManager<'_, '_, P>,
+        ceiling: usize,
+    ) -> io::Result<Option<usize>> {
+        let target = self.target(&Pressure::read(&self.path)?, ceiling.min(bm.limit()));
+        if target == bm.budget() {
+            return Ok(None);
+        }
+
+        bm.set_budget(target)?;
+        Ok(Some(target))
+    }
+
+    /// Poll every `interval` until `stop` is set
+    pub fn run<const P: usize>(
+        &self,
+        bm: &BufferManager<'_, '_, P>,
+        ceiling: usize,
+        interval: Duration,
+        stop: &AtomicBool,
+    ) -> io::Result<()> {
+        while !stop.load(Ordering::Relaxed) {
+            self.poll(bm, ceiling)?;
+            thread::sleep(interval);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustix::fd::AsFd;
+
+    use super::*;
+    use crate::{testutil::TempFile, OwnedExmapFd};
+
+    const SAMPLE: &str = "some avg10=12.50 avg60=3.00 avg300=0.75 total=123456\n\
+                          full avg10=1.00 avg60=0.50 avg300=0.10 total=789\n";
+
+    #[test]
+    fn parse() {
+        let pressure = Pressure::parse(SAMPLE).unwrap();
+        assert_eq!(pressure.some.avg10, 12.5);
+        assert_eq!(pressure.some.total, 123456);
+        assert_eq!(pressure.full.unwrap().avg60, 0.5);
+
+        let some_only = Pressure::parse("some avg10=0.00 avg60=0.00 avg300=0.00 total=0").unwrap();
+        assert!(some_only.full.is_none());
+
+        assert!(Pressure::parse("").is_err());
+        assert!(Pressure::parse("some avg10=abc").is_err());
+    }
+
+    #[test]
+    fn synthetic_file() {
+        let path = TempFile::new("psi");
+        let watcher = PressureWatcher::system();
+        let watcher = PressureWatcher::new(&*path, watcher.thresholds);
+
+        fs::write(&path, "some avg10=0.00 avg60=0.00 avg300=0.00 total=0\n").unwrap();
+        assert_eq!(watcher.target(&Pressure::read(&path).unwrap(), 1000), 1000);
+
+        fs::write(&path, SAMPLE).unwrap();
+        assert_eq!(watcher.target(&Pressure::read(&path).unwrap(), 1000), 750);
+
+        fs::write(&path, "some avg10=60.00 avg60=0.00 avg300=0.00 total=0\n").unwrap();
+        assert_eq!(watcher.target(&Pressure::read(&path).unwrap(), 1000), 250);
+    }
+
+    #[test]
+    fn poll_stays_below_the_configured_budget() {
+        let path = TempFile::new("psi-poll");
+        let backing = TempFile::new("psi-poll-backing");
+        let backing = backing.create();
+        let watcher = PressureWatcher::new(&*path, PressureWatcher::system().thresholds);
+
+        let exmap_fd = OwnedExmapFd::<4096>::open().unwrap();
+        let mem = exmap_fd
+            .create(256 * 4096, 1, 64, Some(backing.as_fd()))
+            .unwrap();
+        let bm = unsafe { BufferManager::new(&exmap_fd, mem, 1, 64) }.unwrap();
+        bm.set_budget(40).unwrap();
+
+        fs::write(&path, "some avg10=0.00 avg60=0.00 avg300=0.00 total=0\n").unwrap();
+        assert_eq!(watcher.poll(&bm, 40).unwrap(), None);
+        assert_eq!(bm.budget(), 40);
+
+        fs::write(&path, "some avg10=25.00 avg60=0.00 avg300=0.00 total=0\n").unwrap();
+        assert_eq!(watcher.poll(&bm, 40).unwrap(), Some(20));
+        assert_eq!(bm.budget(), 20);
+
+        // Back to the configured budget once the pressure is gone, not to the kernel limit
+        fs::write(&path, "some avg10=0.00 avg60=0.00 avg300=0.00 total=0\n").unwrap();
+        assert_eq!(watcher.poll(&bm, 40).unwrap(), Some(40));
+        assert_eq!(bm.budget(), 40);
+
+        // A ceiling above the kernel limit is capped
+        assert_eq!(watcher.poll(&bm, 1000).unwrap(), Some(64));
+        assert_eq!(bm.budget(), 64);
+
+        bm.close().unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+//! Which pages of an exmap are backed by memory, as runs of resident pages.
+//!
+//! [`VirtMem::residency`](crate::VirtMem::residency) asks the kernel with `mincore`, and
+//! [`BufferManager::residency`](crate::bm::BufferManager::residency) reads the page table.
+
+use std::{fmt, ops::Range};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Residency {
+    page_count: u64,
+    runs: Vec<Range<u64>>,
+}
+
+impl Residency {
+    /// Residency of pages `0..page_count`, asking `resident` about each in order
+    pub fn from_fn(page_count: u64, mut resident: impl FnMut(u64) -> bool) -> Self {
+        let mut runs: Vec<Range<u64>> = Vec::new();
+        for pid in 0..page_count {
+            if !resident(pid) {
+                continue;
+            }
+            match runs.last_mut() {
+                Some(run) if run.end == pid => run.end += 1,
+                _ => runs.push(pid..pid + 1),
+            }
+        }
+        Self { page_count, runs }
+    }
+
+    pub fn page_count(&self) -> u64 {
+        self.page_count
+    }
+
+    /// Maximal runs of resident pages, in order
+    pub fn runs(&self) -> &[Range<u64>] {
+        &self.runs
+    }
+
+    /// Number of resident pages
+    pub fn resident(&self) -> u64 {
+        self.runs.iter().map(|run| run.end - run.start).sum()
+    }
+
+    /// One character per `page_count / width` pages, denser the more of them are resident
+    pub fn strip(&self, width: usize) -> String {
+        const SHADES: &[u8] = b" .:-=+*#%@";
+
+        let width = (width as u64).clamp(1, self.page_count.max(1));
+        let mut runs = self.runs.iter().peekable();
+        (0..width)
+            .map(|i| {
+                let cell = i * self.page_count / width..(i + 1) * self.page_count / width;
+                let mut resident = 0;
+                while let Some(run) = runs.peek() {
+                    resident += run
+                        .end
+                        .min(cell.end)
+                        .saturating_sub(run.start.max(cell.start));
+                    if run.end > cell.end {
+                        break;
+                    }
+                    runs.next();
+                }
+
+                let len = (cell.end - cell.start).max(1);
+                let shade = match resident {
+                    0 => 0,
+                    // Any resident page shows, only a full cell is darkest
+                    r if r == len => SHADES.len() - 1,
+                    r => 1 + (r * (SHADES.len() as u64 - 2) / len) as usize,
+                };
+                SHADES[shade] as char
+            })
+            .collect()
+    }
+}
+
+/// The runs as `START-END` page ranges, inclusive, or `PAGE` for a single page
+impl fmt::Display for Residency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, run) in self.runs.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            match run.end - run.start {
+                1 => write!(f, "{}", run.start)?,
+                _ => write!(f, "{}-{}", run.start, run.end - 1)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_and_strip() {
+        let resident = [0, 1, 2, 5, 8, 9];
+        let r = Residency::from_fn(10, |pid| resident.contains(&pid));
+        assert_eq!(r.runs(), [0..3, 5..6, 8..10]);
+        assert_eq!(r.resident(), 6);
+        assert_eq!(r.to_string(), "0-2 5 8-9");
+
+        assert_eq!(r.strip(10), "@@@  @  @@");
+        assert_eq!(r.strip(5), "@++ @");
+        assert_eq!(r.strip(100).len(), 10);
+
+        let empty = Residency::from_fn(0, |_| true);
+        assert_eq!(empty.resident(), 0);
+        assert_eq!(empty.to_string(), "");
+        assert_eq!(empty.strip(4), " ");
+    }
+}
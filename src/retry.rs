@@ -0,0 +1,403 @@
+use rustix::io;
+
+use crate::{sys, trace::Opcode, InterfaceIov, InterfaceWrapper, OpResult};
+
+/// Chooses pages to give back to exmap when an operation runs out of memory.
+///
+/// The selected pages are freed with `EXMAP_OP_FREE` on the interface that hit the failure, so
+/// the pages land in its free list.
+pub trait Evict {
+    /// Select up to `pages` pages that are safe to free. An empty selection stops the retries.
+    fn select(&mut self, pages: usize) -> io::Result<Vec<u64>>;
+
+    /// Called once the selected pages have been freed.
+    fn released(&mut self, pages: &[u64]);
+
+    /// Called instead of [`released`](Self::released) when freeing the selected pages failed.
+    /// They may still be mapped.
+    fn kept(&mut self, pages: &[u64]);
+}
+
+/// An iov that still failed after retrying
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IovFailure {
+    pub page: u64,
+    pub len: u64,
+    /// Negative errno reported by exmap
+    pub res: i32,
+}
+
+impl IovFailure {
+    pub fn errno(&self) -> io::Errno {
+        io::Errno::from_raw_os_error(-self.res)
+    }
+}
+
+/// Final outcome of an operation that was retried after eviction
+#[derive(Debug, Clone, Default)]
+pub struct RetryOutcome {
+    /// Number of times the ioctl was issued
+    pub attempts: u32,
+    /// Pages released by the evictor across all rounds
+    pub evicted: usize,
+    /// Iovs that never succeeded
+    pub failed: Vec<IovFailure>,
+}
+
+impl RetryOutcome {
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl<'a> InterfaceWrapper<'a, InterfaceIov> {
+    /// Like [`alloc`](Self::alloc), but iovs that fail with `ENOMEM` are retried after asking
+    /// `evict` to release pages. At most `max_attempts` ioctls are issued.
+    ///
+    /// The returned interface is empty and ready for the next batch, and so is the one handed
+    /// back on error.
+    pub fn alloc_retry<E: Evict>(
+        self,
+        max_attempts: u32,
+        evict: &mut E,
+    ) -> OpResult<'a, (InterfaceWrapper<'a, InterfaceIov>, RetryOutcome)> {
+        self.retry(Opcode::Alloc, max_attempts, evict)
+    }
+
+    /// Like [`read`](Self::read), retrying iovs that fail with `ENOMEM` after eviction.
+    pub fn read_retry<E: Evict>(
+        self,
+        max_attempts: u32,
+        evict: &mut E,
+    ) -> OpResult<'a, (InterfaceWrapper<'a, InterfaceIov>, RetryOutcome)> {
+        self.retry(Opcode::Read, max_attempts, evict)
+    }
+
+    fn retry<E: Evict>(
+        mut self,
+        opcode: Opcode,
+        max_attempts: u32,
+        evict: &mut E,
+    ) -> OpResult<'a, (InterfaceWrapper<'a, InterfaceIov>, RetryOutcome)> {
+        // The results overwrite the iovs so the requests need to be kept around
+        let pending = self.iter().map(|v| (v.page(), v.len())).collect();
+        self.len = 0;
+
+        let mut target = Target {
+            interface: Some(self),
+            opcode,
+        };
+        let res = retry(&mut target, pending, max_attempts, evict);
+        let interface = target.interface.expect("handed back after every ioctl");
+        match res {
+            Ok(outcome) => Ok((interface, outcome)),
+            Err(e) => Err((interface, e)),
+        }
+    }
+
+    /// Frees `pages`, merging consecutive page ids into one iov and issuing as many ioctls as
+    /// needed. Stops at the first batch with an iov exmap failed to free.
+    pub fn free_pages(mut self, pages: &[u64]) -> OpResult<'a, InterfaceWrapper<'a, InterfaceIov>> {
+        debug_assert_eq!(self.len(), 0);
+
+        let max_len = u64::from(sys::EXMAP_PAGE_MAX_PAGES - 1);
+        let mut i = 0;
+        while i < pages.len() {
+            let start = pages[i];
+            let mut len = 1;
+            while i + (len as usize) < pages.len()
+                && pages[i + len as usize] == start + len
+                && len < max_len
+            {
+                len += 1;
+            }
+            i += len as usize;
+
+            if self.push(start, len).is_err() {
+                self = self.free_batch()?;
+                self.push(start, len).expect("interface was just emptied");
+            }
+        }
+
+        if self.len() > 0 {
+            self = self.free_batch()?;
+        }
+
+        Ok(self)
+    }
+
+    /// Free the pushed iovs, failing if any of them failed
+    fn free_batch(self) -> OpResult<'a, InterfaceWrapper<'a, InterfaceIov>> {
+        let (res, _) = self.issue(Opcode::Free)?;
+        let failed = res.iter().find(|v| v.res < 0).map(|v| v.res);
+        let interface = res.into_iov();
+        match failed {
+            Some(res) => Err((interface, io::Errno::from_raw_os_error(-res))),
+            None => Ok(interface),
+        }
+    }
+}
+
+/// What a retried operation runs on, so the retry policy can be tested without exmap
+trait Issue {
+    /// Issue the operation on `iovs` and return the per iov results
+    fn issue(&mut self, iovs: &[(u64, u64)]) -> io::Result<Vec<i32>>;
+
+    /// Free `pages` so the next attempt can use their memory
+    fn free(&mut self, pages: &[u64]) -> io::Result<()>;
+}
+
+struct Target<'a> {
+    /// Only empty while an ioctl is running
+    interface: Option<InterfaceWrapper<'a, InterfaceIov>>,
+    opcode: Opcode,
+}
+
+impl<'a> Target<'a> {
+    fn with<T>(
+        &mut self,
+        f: impl FnOnce(
+            InterfaceWrapper<'a, InterfaceIov>,
+        ) -> OpResult<'a, (InterfaceWrapper<'a, InterfaceIov>, T)>,
+    ) -> io::Result<T> {
+        let interface = self
+            .interface
+            .take()
+            .expect("handed back after every ioctl");
+        let (interface, res) = match f(interface) {
+            Ok((interface, res)) => (interface, Ok(res)),
+            Err((interface, e)) => (interface, Err(e)),
+        };
+        self.interface = Some(interface);
+        res
+    }
+}
+
+impl Issue for Target<'_> {
+    fn issue(&mut self, iovs: &[(u64, u64)]) -> io::Result<Vec<i32>> {
+        let opcode = self.opcode;
+        self.with(|mut interface| {
+            for &(page, len) in iovs {
+                interface
+                    .push(page, len)
+                    .expect("retried iovs are a subset of the original batch");
+            }
+            let (res, _) = interface.issue(opcode)?;
+            let results = res.iter().map(|v| v.res).collect();
+            Ok((res.into_iov(), results))
+        })
+    }
+
+    fn free(&mut self, pages: &[u64]) -> io::Result<()> {
+        self.with(|interface| interface.free_pages(pages).map(|i| (i, ())))
+    }
+}
+
+fn retry<E: Evict>(
+    target: &mut impl Issue,
+    mut pending: Vec<(u64, u64)>,
+    max_attempts: u32,
+    evict: &mut E,
+) -> io::Result<RetryOutcome> {
+    let enomem = -io::Errno::NOMEM.raw_os_error();
+    let mut outcome = RetryOutcome::default();
+
+    loop {
+        outcome.attempts += 1;
+
+        // Only trust the per iov results rather than the failure count
+        let results = target.issue(&pending)?;
+        let mut retryable = Vec::new();
+        for (&(page, len), &res) in pending.iter().zip(&results) {
+            if res == enomem {
+                retryable.push((page, len));
+            } else if res < 0 {
+                outcome.failed.push(IovFailure { page, len, res });
+            }
+        }
+
+        if retryable.is_empty() {
+            return Ok(outcome);
+        }
+
+        let victims = if outcome.attempts < max_attempts.max(1) {
+            let needed = retryable.iter().map(|&(_, len)| len as usize).sum();
+            evict.select(needed)?
+        } else {
+            Vec::new()
+        };
+
+        // Either out of attempts or nothing left to evict
+        if victims.is_empty() {
+            outcome
+                .failed
+                .extend(retryable.into_iter().map(|(page, len)| IovFailure {
+                    page,
+                    len,
+                    res: enomem,
+                }));
+            return Ok(outcome);
+        }
+        if let Err(e) = target.free(&victims) {
+            evict.kept(&victims);
+            return Err(e);
+        }
+        evict.released(&victims);
+        outcome.evicted += victims.len();
+        pending = retryable;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Maps pages while it has memory left
+    struct Exmap {
+        free: usize,
+        issued: u32,
+        fail_free: bool,
+    }
+
+    impl Issue for Exmap {
+        fn issue(&mut self, iovs: &[(u64, u64)]) -> io::Result<Vec<i32>> {
+            self.issued += 1;
+            Ok(iovs
+                .iter()
+                .map(|&(_, len)| match self.free.checked_sub(len as usize) {
+                    Some(free) => {
+                        self.free = free;
+                        0
+                    }
+                    None => -io::Errno::NOMEM.raw_os_error(),
+                })
+                .collect())
+        }
+
+        fn free(&mut self, pages: &[u64]) -> io::Result<()> {
+            if self.fail_free {
+                return Err(io::Errno::INVAL);
+            }
+            self.free += pages.len();
+            Ok(())
+        }
+    }
+
+    /// Hands out at most `per_round` of `resident` per selection
+    struct Evictor {
+        resident: Vec<u64>,
+        per_round: usize,
+        released: Vec<u64>,
+        kept: Vec<u64>,
+    }
+
+    impl Evict for Evictor {
+        fn select(&mut self, pages: usize) -> io::Result<Vec<u64>> {
+            let n = pages.min(self.per_round).min(self.resident.len());
+            Ok(self.resident.drain(..n).collect())
+        }
+
+        fn released(&mut self, pages: &[u64]) {
+            self.released.extend_from_slice(pages);
+        }
+
+        fn kept(&mut self, pages: &[u64]) {
+            self.kept.extend_from_slice(pages);
+        }
+    }
+
+    fn evictor(resident: u64, per_round: usize) -> Evictor {
+        Evictor {
+            resident: (100..100 + resident).collect(),
+            per_round,
+            released: Vec::new(),
+            kept: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn retries_after_eviction() {
+        let mut exmap = Exmap {
+            free: 1,
+            issued: 0,
+            fail_free: false,
+        };
+        let mut evict = evictor(8, 8);
+        let outcome = retry(&mut exmap, vec![(0, 1), (10, 2)], 4, &mut evict).unwrap();
+
+        assert!(outcome.is_complete());
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(outcome.evicted, 2);
+        assert_eq!(evict.released, [100, 101]);
+        assert_eq!(exmap.issued, 2);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut exmap = Exmap {
+            free: 0,
+            issued: 0,
+            fail_free: false,
+        };
+        let mut evict = evictor(64, 1);
+        let outcome = retry(&mut exmap, vec![(0, 4)], 3, &mut evict).unwrap();
+
+        assert_eq!(outcome.attempts, 3);
+        assert_eq!(exmap.issued, 3);
+        assert_eq!(outcome.evicted, 2);
+        assert_eq!(
+            outcome.failed,
+            [IovFailure {
+                page: 0,
+                len: 4,
+                res: -io::Errno::NOMEM.raw_os_error(),
+            }]
+        );
+        assert_eq!(outcome.failed[0].errno(), io::Errno::NOMEM);
+    }
+
+    #[test]
+    fn stops_when_nothing_is_evictable() {
+        let mut exmap = Exmap {
+            free: 0,
+            issued: 0,
+            fail_free: false,
+        };
+        let mut evict = evictor(0, 8);
+        let outcome = retry(&mut exmap, vec![(0, 1)], 4, &mut evict).unwrap();
+
+        assert_eq!(outcome.attempts, 1);
+        assert_eq!(outcome.evicted, 0);
+        assert_eq!(outcome.failed.len(), 1);
+    }
+
+    #[test]
+    fn zero_attempts_still_issues_once() {
+        let mut exmap = Exmap {
+            free: 0,
+            issued: 0,
+            fail_free: false,
+        };
+        let mut evict = evictor(8, 8);
+        let outcome = retry(&mut exmap, vec![(0, 1)], 0, &mut evict).unwrap();
+
+        assert_eq!(outcome.attempts, 1);
+        assert!(evict.released.is_empty());
+        assert!(!outcome.is_complete());
+    }
+
+    #[test]
+    fn failed_free_keeps_victims() {
+        let mut exmap = Exmap {
+            free: 0,
+            issued: 0,
+            fail_free: true,
+        };
+        let mut evict = evictor(8, 8);
+        let err = retry(&mut exmap, vec![(0, 2)], 4, &mut evict).unwrap_err();
+
+        assert_eq!(err, io::Errno::INVAL);
+        assert_eq!(evict.kept, [100, 101]);
+        assert!(evict.released.is_empty());
+    }
+}
@@ -0,0 +1,300 @@
+//! Slotted layout for variable length records on a single page.
+//!
+//! ```text
+//! 0          16      18     20       22            24
+//! +----------+-------+------+--------+-------------+----------------+--------+-------------+
+//! | checksum | slots | heap | frag   |  reserved   | slot array ... |  free  | records ... |
+//! +----------+-------+------+--------+-------------+----------------+--------+-------------+
+//! ```
+//!
+//! A slot is a record offset and length. Records are addressed by slot number, which stays the
+//! same across updates and compaction. A deleted record leaves an empty slot with offset 0 that
+//! the next insert reuses; `frag` counts the heap bytes of deleted and shrunk records that
+//! [`SlottedPage::compact`] gives back.
+
+use crate::checksum;
+
+/// Page header, after the checksum header every page starts with
+const BASE: usize = checksum::HEADER_SIZE;
+const SLOTS: usize = BASE;
+const HEAP: usize = BASE + 2;
+const FRAGMENTED: usize = BASE + 4;
+const HEADER_SIZE: usize = BASE + 8;
+const SLOT_SIZE: usize = 4;
+
+pub struct SlottedPage<'p, const P: usize> {
+    page: &'p mut [u8; P],
+}
+
+impl<'p, const P: usize> SlottedPage<'p, P> {
+    /// View a page that is already in slotted layout
+    pub fn new(page: &'p mut [u8; P]) -> Self {
+        assert!(P <= u16::MAX as usize, "page offsets are 16 bit");
+        Self { page }
+    }
+
+    /// Format the page as an empty slotted page
+    pub fn init(page: &'p mut [u8; P]) -> Self {
+        let mut slotted = Self::new(page);
+        slotted.set(SLOTS, 0);
+        slotted.set(HEAP, P);
+        slotted.set(FRAGMENTED, 0);
+        slotted
+    }
+
+    /// Largest record a fresh page can hold
+    pub const fn max_record() -> usize {
+        P - HEADER_SIZE - SLOT_SIZE
+    }
+
+    fn get_u16(&self, at: usize) -> usize {
+        u16::from_le_bytes([self.page[at], self.page[at + 1]]).into()
+    }
+
+    fn set(&mut self, at: usize, v: usize) {
+        self.page[at..at + 2].copy_from_slice(&(v as u16).to_le_bytes());
+    }
+
+    fn slot_count(&self) -> usize {
+        self.get_u16(SLOTS)
+    }
+
+    fn heap(&self) -> usize {
+        self.get_u16(HEAP)
+    }
+
+    fn slot(&self, slot: u16) -> Option<(usize, usize)> {
+        let slot = usize::from(slot);
+        if slot >= self.slot_count() {
+            return None;
+        }
+        let at = HEADER_SIZE + slot * SLOT_SIZE;
+        match (self.get_u16(at), self.get_u16(at + 2)) {
+            (0, _) => None,
+            slot => Some(slot),
+        }
+    }
+
+    fn set_slot(&mut self, slot: usize, offset: usize, len: usize) {
+        let at = HEADER_SIZE + slot * SLOT_SIZE;
+        self.set(at, offset);
+        self.set(at + 2, len);
+    }
+
+    /// Number of live records
+    pub fn len(&self) -> usize {
+        (0..self.slot_count() as u16)
+            .filter(|&s| self.slot(s).is_some())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes between the slot array and the heap
+    pub fn contiguous_free(&self) -> usize {
+        self.heap() - (HEADER_SIZE + self.slot_count() * SLOT_SIZE)
+    }
+
+    /// Bytes available to records after a compaction, including the space of a new slot.
+    /// This is what a free-space map should track for the page.
+    pub fn free_space(&self) -> usize {
+        self.contiguous_free() + self.get_u16(FRAGMENTED)
+    }
+
+    /// Whether a record of `len` bytes fits, compacting if needed
+    pub fn fits(&self, len: usize) -> bool {
+        let slot = if self.free_slot().is_some() {
+            0
+        } else {
+            SLOT_SIZE
+        };
+        self.free_space() >= len + slot
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        (0..self.slot_count()).find(|&s| self.slot(s as u16).is_none())
+    }
+
+    pub fn get(&self, slot: u16) -> Option<&[u8]> {
+        let (offset, len) = self.slot(slot)?;
+        Some(&self.page[offset..offset + len])
+    }
+
+    /// Modify a record in place without changing its length
+    pub fn get_mut(&mut self, slot: u16) -> Option<&mut [u8]> {
+        let (offset, len) = self.slot(slot)?;
+        Some(&mut self.page[offset..offset + len])
+    }
+
+    /// Store a record, returning its slot, or `None` if it does not fit
+    pub fn insert(&mut self, record: &[u8]) -> Option<u16> {
+        if !self.fits(record.len()) {
+            return None;
+        }
+
+        let slot = match self.free_slot() {
+            Some(slot) => slot,
+            None => {
+                let slot = self.slot_count();
+                self.set(SLOTS, slot + 1);
+                // Mark the new slot empty while the record is placed
+                self.set_slot(slot, 0, 0);
+                slot
+            }
+        };
+        let offset = self.place(record.len());
+        self.page[offset..offset + record.len()].copy_from_slice(record);
+        self.set_slot(slot, offset, record.len());
+        Some(slot as u16)
+    }
+
+    /// Carve `len` bytes off the heap, compacting first if they are not contiguous
+    fn place(&mut self, len: usize) -> usize {
+        if self.contiguous_free() < len {
+            self.compact();
+        }
+        let offset = self.heap() - len;
+        self.set(HEAP, offset);
+        offset
+    }
+
+    /// Replace a record, keeping its slot. Returns `false` if the slot is empty or the new
+    /// record does not fit, leaving the page unchanged.
+    pub fn update(&mut self, slot: u16, record: &[u8]) -> bool {
+        let Some((offset, len)) = self.slot(slot) else {
+            return false;
+        };
+
+        if record.len() <= len {
+            self.page[offset..offset + record.len()].copy_from_slice(record);
+            self.set_slot(slot.into(), offset, record.len());
+            let fragmented = self.get_u16(FRAGMENTED) + len - record.len();
+            self.set(FRAGMENTED, fragmented);
+            return true;
+        }
+
+        if self.free_space() + len < record.len() {
+            return false;
+        }
+        // Drop the old record first so compaction can reclaim it
+        self.set_slot(slot.into(), 0, 0);
+        let fragmented = self.get_u16(FRAGMENTED) + len;
+        self.set(FRAGMENTED, fragmented);
+
+        let offset = self.place(record.len());
+        self.page[offset..offset + record.len()].copy_from_slice(record);
+        self.set_slot(slot.into(), offset, record.len());
+        true
+    }
+
+    /// Remove a record, returning whether the slot held one
+    pub fn delete(&mut self, slot: u16) -> bool {
+        let Some((_, len)) = self.slot(slot) else {
+            return false;
+        };
+        self.set_slot(slot.into(), 0, 0);
+        let fragmented = self.get_u16(FRAGMENTED) + len;
+        self.set(FRAGMENTED, fragmented);
+
+        // Trailing empty slots can go
+        let mut count = self.slot_count();
+        while count > 0 && self.slot(count as u16 - 1).is_none() {
+            count -= 1;
+        }
+        self.set(SLOTS, count);
+        true
+    }
+
+    /// Move the records to the end of the page so all free space is contiguous
+    pub fn compact(&mut self) {
+        let mut copy = *self.page;
+        let old = SlottedPage::new(&mut copy);
+
+        self.set(HEAP, P);
+        self.set(FRAGMENTED, 0);
+        for slot in 0..self.slot_count() {
+            if let Some(record) = old.get(slot as u16) {
+                let offset = self.heap() - record.len();
+                self.page[offset..offset + record.len()].copy_from_slice(record);
+                self.set(HEAP, offset);
+                self.set_slot(slot, offset, record.len());
+            }
+        }
+    }
+
+    /// Live records with their slots, in slot order
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &[u8])> + '_ {
+        (0..self.slot_count() as u16).filter_map(|slot| Some((slot, self.get(slot)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_update_delete() {
+        let mut page = [0xa5; 256];
+        let mut slotted = SlottedPage::init(&mut page);
+        assert_eq!(slotted.free_space(), 232);
+
+        let a = slotted.insert(b"alpha").unwrap();
+        let b = slotted.insert(b"bravo").unwrap();
+        let c = slotted.insert(b"").unwrap();
+        assert_eq!((a, b, c), (0, 1, 2));
+        assert_eq!(slotted.get(c), Some(&b""[..]));
+        assert_eq!(slotted.free_space(), 232 - 3 * 4 - 10);
+
+        assert!(slotted.update(a, b"al"));
+        assert!(slotted.update(b, b"bravissimo"));
+        slotted.get_mut(a).unwrap()[1] = b'L';
+        assert_eq!(slotted.get(a), Some(&b"aL"[..]));
+        assert_eq!(slotted.get(b), Some(&b"bravissimo"[..]));
+
+        assert!(slotted.delete(a));
+        assert!(!slotted.delete(a));
+        assert_eq!(slotted.get(a), None);
+        // The empty slot is reused
+        assert_eq!(slotted.insert(b"charlie"), Some(a));
+
+        let records: Vec<_> = slotted.iter().collect();
+        assert_eq!(
+            records,
+            vec![(0, &b"charlie"[..]), (1, &b"bravissimo"[..]), (2, &b""[..])]
+        );
+        assert_eq!(slotted.len(), 3);
+
+        // Reopening sees the same records, and the checksum header was left alone
+        assert_eq!(SlottedPage::new(&mut page).get(1), Some(&b"bravissimo"[..]));
+        assert_eq!(page[..BASE], [0xa5; BASE]);
+    }
+
+    #[test]
+    fn compaction_reclaims_space() {
+        let mut page = [0; 128];
+        let mut slotted = SlottedPage::init(&mut page);
+
+        let mut slots = Vec::new();
+        while let Some(slot) = slotted.insert(&[7; 20]) {
+            slots.push(slot);
+        }
+        assert!(slotted.free_space() < 24);
+        let full = slotted.free_space();
+
+        // Free the middle records, then insert one needing both holes
+        slotted.delete(slots[1]);
+        slotted.delete(slots[2]);
+        assert_eq!(slotted.contiguous_free(), full);
+        assert_eq!(slotted.free_space(), full + 40);
+        let big = slotted.insert(&[9; 40]).unwrap();
+        assert_eq!(slotted.get(big), Some(&[9; 40][..]));
+        assert_eq!(slotted.get(slots[0]), Some(&[7; 20][..]));
+        assert_eq!(slotted.get(slots[3]), Some(&[7; 20][..]));
+
+        assert_eq!(slotted.insert(&[1; 100]), None);
+        assert!(!slotted.update(slots[0], &[1; 100]));
+        assert_eq!(slotted.get(slots[0]), Some(&[7; 20][..]));
+    }
+}
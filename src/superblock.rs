@@ -0,0 +1,244 @@
+//! Versioned superblock stored at page 0 of a backing file.
+//!
+//! It records the geometry the file was created with, so reopening it can size the exmap with
+//! [`Superblock::exmap_size`] instead of passing the values by hand. A buffer manager over the
+//! file must not hand out page 0: give it a [`PageAllocator`](crate::allocator::PageAllocator)
+//! or build it [`with_superblock`](crate::bm::BufferManager::with_superblock).
+//!
+//! ```text
+//! 0         8         12          16           24      40      44       48
+//! +---------+---------+-----------+------------+-------+-------+--------+
+//! |  magic  | version | page size | page count | uuid  | flags | crc32c |
+//! +---------+---------+-----------+------------+-------+-------+--------+
+//! ```
+
+use std::{fs::File, io::Read};
+
+use rustix::{fd::AsFd, fs, io};
+
+use crate::{checksum::crc32c, fileio, Error, Result};
+
+const MAGIC: [u8; 8] = *b"EXMAPSB\0";
+const ENCODED_SIZE: usize = 48;
+const FLAG_CLEAN: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Superblock {
+    pub version: u32,
+    pub page_size: u32,
+    /// Pages in the file, including the superblock
+    pub page_count: u64,
+    pub uuid: [u8; 16],
+    /// Set when the file was closed cleanly, cleared while it is open
+    pub clean: bool,
+}
+
+impl Superblock {
+    pub const VERSION: u32 = 1;
+
+    /// The superblock always lives at page 0
+    pub const PID: u64 = 0;
+
+    pub fn new<const P: usize>(page_count: u64) -> io::Result<Self> {
+        Ok(Self {
+            version: Self::VERSION,
+            page_size: P as u32,
+            page_count,
+            uuid: new_uuid()?,
+            clean: true,
+        })
+    }
+
+    /// The `exmap_size` to pass to [`OwnedExmapFd::create`](crate::OwnedExmapFd::create)
+    pub fn exmap_size(&self) -> usize {
+        self.page_count as usize * self.page_size as usize
+    }
+
+    pub fn encode(&self) -> [u8; ENCODED_SIZE] {
+        let mut buf = [0; ENCODED_SIZE];
+        buf[0..8].copy_from_slice(&MAGIC);
+        buf[8..12].copy_from_slice(&self.version.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.page_size.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.page_count.to_le_bytes());
+        buf[24..40].copy_from_slice(&self.uuid);
+        let flags = if self.clean { FLAG_CLEAN } else { 0 };
+        buf[40..44].copy_from_slice(&flags.to_le_bytes());
+        let crc = crc32c(&buf[..44]);
+        buf[44..48].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < ENCODED_SIZE || buf[0..8] != MAGIC {
+            return Err(Error::NotFormatted);
+        }
+
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        let stored = u32_at(44);
+        let computed = crc32c(&buf[..44]);
+        if stored != computed {
+            return Err(Error::Corrupt {
+                pid: Self::PID,
+                stored,
+                computed,
+            });
+        }
+
+        Ok(Self {
+            version: u32_at(8),
+            page_size: u32_at(12),
+            page_count: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+            uuid: buf[24..40].try_into().unwrap(),
+            clean: u32_at(40) & FLAG_CLEAN != 0,
+        })
+    }
+
+    pub fn read<Fd: AsFd>(fd: Fd) -> Result<Self> {
+        let mut buf = [0; ENCODED_SIZE];
+        match fileio::pread_exact(fd, &mut buf, 0) {
+            // Shorter than a superblock
+            Err(io::Errno::IO) => return Err(Error::NotFormatted),
+            res => res?,
+        }
+        Self::decode(&buf)
+    }
+
+    /// Write and sync the superblock
+    pub fn write<Fd: AsFd>(&self, fd: Fd) -> io::Result<()> {
+        fileio::pwrite_all(&fd, &self.encode(), 0)?;
+        fs::fdatasync(&fd)
+    }
+
+    /// Format an empty backing file for `page_count` pages of size `P` and size the file. Fails
+    /// with `EEXIST` if the file already has a superblock and with `ENOTEMPTY` if it holds
+    /// anything else.
+    pub fn create<const P: usize, Fd: AsFd>(fd: Fd, page_count: u64) -> Result<Self> {
+        match Self::read(&fd) {
+            Err(Error::NotFormatted) => {}
+            Ok(_) | Err(Error::Corrupt { .. }) => return Err(io::Errno::EXIST.into()),
+            Err(e) => return Err(e),
+        }
+        if fs::fstat(&fd)?.st_size != 0 {
+            return Err(io::Errno::NOTEMPTY.into());
+        }
+
+        if page_count == 0 {
+            return Err(io::Errno::INVAL.into());
+        }
+
+        let mut sb = Self::new::<P>(page_count)?;
+        fs::ftruncate(&fd, sb.exmap_size() as u64)?;
+        sb.clean = false;
+        sb.write(&fd)?;
+        Ok(sb)
+    }
+
+    /// Read the superblock of an existing backing file and mark it as in use. Refuses files
+    /// written with another page size or format version, and, if `page_count` is given, another
+    /// size.
+    ///
+    /// The returned superblock reports whether the file was shut down cleanly last time.
+    pub fn open<const P: usize, Fd: AsFd>(fd: Fd, page_count: Option<u64>) -> Result<Self> {
+        let sb = Self::read(&fd)?;
+
+        let check = |what, expected: u64, found: u64| {
+            if expected == found {
+                Ok(())
+            } else {
+                Err(Error::Mismatch {
+                    what,
+                    expected,
+                    found,
+                })
+            }
+        };
+        check("version", Self::VERSION.into(), sb.version.into())?;
+        check("page size", P as u64, sb.page_size.into())?;
+        if let Some(page_count) = page_count {
+            check("page count", page_count, sb.page_count)?;
+        }
+
+        Self { clean: false, ..sb }.write(&fd)?;
+        Ok(sb)
+    }
+
+    /// Record a clean shutdown
+    pub fn close<Fd: AsFd>(&mut self, fd: Fd) -> io::Result<()> {
+        fs::fsync(&fd)?;
+        self.clean = true;
+        self.write(&fd)
+    }
+}
+
+/// Random version 4 uuid
+fn new_uuid() -> io::Result<[u8; 16]> {
+    let mut uuid = [0; 16];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut uuid))
+        .map_err(|e| io::Errno::from_io_error(&e).unwrap_or(io::Errno::IO))?;
+    uuid[6] = (uuid[6] & 0x0F) | 0x40;
+    uuid[8] = (uuid[8] & 0x3F) | 0x80;
+    Ok(uuid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempFile;
+
+    #[test]
+    fn create_and_reopen() {
+        let path = TempFile::new("sb");
+        let fd = path.create();
+
+        assert_eq!(
+            Superblock::open::<4096, _>(&fd, None),
+            Err(Error::NotFormatted)
+        );
+
+        let mut sb = Superblock::create::<4096, _>(&fd, 64).unwrap();
+        assert_eq!(fs::fstat(&fd).unwrap().st_size, 64 * 4096);
+        assert_eq!(sb.exmap_size(), 64 * 4096);
+        assert_eq!(
+            Superblock::create::<4096, _>(&fd, 64),
+            Err(Error::Io(io::Errno::EXIST))
+        );
+        sb.close(&fd).unwrap();
+
+        let reopened = Superblock::open::<4096, _>(&fd, Some(64)).unwrap();
+        assert!(reopened.clean);
+        assert_eq!(reopened.uuid, sb.uuid);
+        // Not closed since, so the next open sees an unclean shutdown
+        assert!(!Superblock::open::<4096, _>(&fd, None).unwrap().clean);
+
+        assert_eq!(
+            Superblock::open::<8192, _>(&fd, None),
+            Err(Error::Mismatch {
+                what: "page size",
+                expected: 8192,
+                found: 4096
+            })
+        );
+        assert!(matches!(
+            Superblock::open::<4096, _>(&fd, Some(32)),
+            Err(Error::Mismatch {
+                what: "page count",
+                ..
+            })
+        ));
+
+        fileio::pwrite_all(&fd, &[0xFF], 20).unwrap();
+        assert!(matches!(
+            Superblock::read(&fd),
+            Err(Error::Corrupt { pid: 0, .. })
+        ));
+
+        // Data that is not a superblock is not overwritten either
+        fileio::pwrite_all(&fd, b"not a superblock", 0).unwrap();
+        assert_eq!(
+            Superblock::create::<4096, _>(&fd, 64),
+            Err(Error::Io(io::Errno::NOTEMPTY))
+        );
+        assert_eq!(fs::fstat(&fd).unwrap().st_size, 64 * 4096);
+    }
+}
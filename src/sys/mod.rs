@@ -71,3 +71,67 @@ pub(crate) unsafe fn exmap_ioctl<Fd: AsRawFd>(
         )
     } as _)
 }
+
+// SAFETY:
+// `addr` is page aligned and `vec` has a byte for every page of `addr..addr + len`
+pub(crate) unsafe fn mincore(addr: *const u8, len: usize, vec: &mut [u8]) -> io::Result<()> {
+    to_result(unsafe {
+        sc::syscall3(
+            sc::nr::MINCORE,
+            addr as usize,
+            len,
+            vec.as_mut_ptr() as usize,
+        )
+    } as _)
+    .map(|_| ())
+}
+
+pub(crate) const AT_EMPTY_PATH: u32 = 0x1000;
+pub(crate) const STATX_DIOALIGN: u32 = 0x2000;
+
+/// `struct statx` as of Linux 6.1, which added the direct I/O alignment rustix does not know yet
+#[repr(C)]
+#[derive(Debug, Default)]
+pub(crate) struct statx {
+    pub(crate) stx_mask: u32,
+    pub(crate) stx_blksize: u32,
+    pub(crate) stx_attributes: u64,
+    pub(crate) stx_nlink: u32,
+    pub(crate) stx_uid: u32,
+    pub(crate) stx_gid: u32,
+    pub(crate) stx_mode: u16,
+    __spare0: u16,
+    pub(crate) stx_ino: u64,
+    pub(crate) stx_size: u64,
+    pub(crate) stx_blocks: u64,
+    pub(crate) stx_attributes_mask: u64,
+    __timestamps: [u64; 8],
+    pub(crate) stx_rdev_major: u32,
+    pub(crate) stx_rdev_minor: u32,
+    pub(crate) stx_dev_major: u32,
+    pub(crate) stx_dev_minor: u32,
+    pub(crate) stx_mnt_id: u64,
+    pub(crate) stx_dio_mem_align: u32,
+    pub(crate) stx_dio_offset_align: u32,
+    __spare3: [u64; 12],
+}
+
+const _: () = assert!(std::mem::size_of::<statx>() == 256);
+
+pub(crate) fn statx<Fd: AsRawFd>(fd: &Fd, mask: u32) -> io::Result<statx> {
+    let mut buf = statx::default();
+    // SAFETY:
+    // The path is an empty C string, so with `AT_EMPTY_PATH` the kernel only writes the 256 byte
+    // `buf` for `fd`
+    to_result(unsafe {
+        sc::syscall5(
+            sc::nr::STATX,
+            fd.as_raw_fd() as usize,
+            b"\0".as_ptr() as usize,
+            AT_EMPTY_PATH as usize,
+            mask as usize,
+            &mut buf as *mut statx as usize,
+        )
+    } as _)?;
+    Ok(buf)
+}
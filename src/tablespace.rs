@@ -0,0 +1,206 @@
+//! One flat page id space spread over several backing files.
+//!
+//! exmap only knows about a single backing fd, so a [`Tablespace`] is plugged into the buffer
+//! manager as a [`PageStore`]. Faults allocate the page through exmap and `pread` it from the
+//! file owning that page id, write backs `pwrite` to the same place.
+
+use std::sync::{PoisonError, RwLock};
+
+use rustix::{
+    fd::{AsFd, OwnedFd},
+    fs, io,
+};
+
+use crate::{bm::PageStore, fileio};
+
+#[derive(Debug)]
+struct Extent {
+    start: u64,
+    pages: u64,
+    file: OwnedFd,
+    /// Byte offset of `start` in the file
+    offset: u64,
+}
+
+impl Extent {
+    fn end(&self) -> u64 {
+        self.start + self.pages
+    }
+}
+
+#[derive(Debug)]
+pub struct Tablespace<const P: usize> {
+    page_count: u64,
+    /// Sorted by start page, never overlapping
+    extents: RwLock<Vec<Extent>>,
+}
+
+impl<const P: usize> Tablespace<P> {
+    /// `page_count` is the size of the exmap in pages, no file may be mapped past it
+    pub fn new(page_count: u64) -> Self {
+        Self {
+            page_count,
+            extents: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// First page id after every mapped file
+    pub fn end(&self) -> u64 {
+        self.extents
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .last()
+            .map_or(0, Extent::end)
+    }
+
+    /// Map `pages` pages of `file`, starting at byte `offset`, after the last mapped file.
+    /// Returns the first page id of the new range.
+    pub fn add_file(&self, file: OwnedFd, offset: u64, pages: u64) -> io::Result<u64> {
+        let mut extents = self.extents.write().unwrap_or_else(PoisonError::into_inner);
+        let start = extents.last().map_or(0, Extent::end);
+        self.insert(&mut extents, start, file, offset, pages)?;
+        Ok(start)
+    }
+
+    /// Map `pages` pages of `file`, starting at byte `offset`, to the page ids from `start`
+    pub fn add_file_at(
+        &self,
+        start: u64,
+        file: OwnedFd,
+        offset: u64,
+        pages: u64,
+    ) -> io::Result<()> {
+        let mut extents = self.extents.write().unwrap_or_else(PoisonError::into_inner);
+        self.insert(&mut extents, start, file, offset, pages)
+    }
+
+    fn insert(
+        &self,
+        extents: &mut Vec<Extent>,
+        start: u64,
+        file: OwnedFd,
+        offset: u64,
+        pages: u64,
+    ) -> io::Result<()> {
+        if pages == 0 {
+            return Err(io::Errno::INVAL);
+        }
+        match start.checked_add(pages) {
+            Some(end) if end <= self.page_count => {}
+            _ => return Err(io::Errno::NOSPC),
+        }
+
+        let idx = extents.partition_point(|e| e.start < start);
+        let overlaps_prev = idx > 0 && extents[idx - 1].end() > start;
+        let overlaps_next = idx < extents.len() && extents[idx].start < start + pages;
+        if overlaps_prev || overlaps_next {
+            return Err(io::Errno::EXIST);
+        }
+
+        extents.insert(
+            idx,
+            Extent {
+                start,
+                pages,
+                file,
+                offset,
+            },
+        );
+        Ok(())
+    }
+
+    /// Run `f` with the file and byte offset holding `pid`
+    fn with_location<T>(
+        &self,
+        pid: u64,
+        f: impl FnOnce(&OwnedFd, u64) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let extents = self.extents.read().unwrap_or_else(PoisonError::into_inner);
+        let idx = extents.partition_point(|e| e.end() <= pid);
+        match extents.get(idx) {
+            Some(e) if e.start <= pid => f(&e.file, e.offset + (pid - e.start) * P as u64),
+            _ => Err(io::Errno::NXIO),
+        }
+    }
+}
+
+impl<const P: usize> PageStore for Tablespace<P> {
+    /// Pages past the end of their file were never written and read as zeros
+    fn read_page(&self, pid: u64, page: &mut [u8]) -> io::Result<()> {
+        self.with_location(pid, |file, offset| {
+            fileio::pread_or_zero(file.as_fd(), page, offset)
+        })
+    }
+
+    fn write_page(&self, pid: u64, page: &[u8]) -> io::Result<()> {
+        self.with_location(pid, |file, offset| {
+            fileio::pwrite_all(file.as_fd(), page, offset)
+        })
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let extents = self.extents.read().unwrap_or_else(PoisonError::into_inner);
+        extents.iter().try_for_each(|e| fs::fdatasync(&e.file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::testutil::TempFile;
+
+    #[test]
+    fn routes_pages() {
+        let ts = Tablespace::<4096>::new(100);
+        let (path_a, path_b) = (TempFile::new("ts-a"), TempFile::new("ts-b"));
+        let (a, b) = (path_a.create(), path_b.create());
+
+        assert_eq!(ts.add_file(a, 0, 10).unwrap(), 0);
+        assert_eq!(ts.add_file(b, 4096, 5).unwrap(), 10);
+        assert_eq!(ts.end(), 15);
+
+        let page = [7u8; 4096];
+        ts.write_page(3, &page).unwrap();
+        ts.write_page(11, &[9u8; 4096]).unwrap();
+
+        let mut read = [0u8; 4096];
+        ts.read_page(3, &mut read).unwrap();
+        assert_eq!(read, page);
+
+        // Page 11 is the second page of file b, after the 4096 byte offset
+        let b_contents = fs::read(&path_b).unwrap();
+        assert_eq!(b_contents.len(), 3 * 4096);
+        assert!(b_contents[2 * 4096..].iter().all(|&v| v == 9));
+
+        assert_eq!(ts.read_page(20, &mut read), Err(io::Errno::NXIO));
+
+        // Never written, past the end of file a
+        ts.read_page(9, &mut read).unwrap();
+        assert!(read.iter().all(|&v| v == 0));
+    }
+
+    #[test]
+    fn rejects_overlap() {
+        let ts = Tablespace::<4096>::new(100);
+        let path = TempFile::new("ts-overlap");
+        let fd = path.create();
+
+        ts.add_file_at(50, fd.try_clone().unwrap(), 0, 10).unwrap();
+        assert_eq!(
+            ts.add_file_at(45, fd.try_clone().unwrap(), 0, 10),
+            Err(io::Errno::EXIST)
+        );
+        assert_eq!(
+            ts.add_file_at(59, fd.try_clone().unwrap(), 0, 1),
+            Err(io::Errno::EXIST)
+        );
+        assert_eq!(
+            ts.add_file_at(95, fd.try_clone().unwrap(), 0, 10),
+            Err(io::Errno::NOSPC)
+        );
+        ts.add_file_at(0, fd, 0, 50).unwrap();
+        assert_eq!(ts.end(), 60);
+    }
+}
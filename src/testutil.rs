@@ -0,0 +1,63 @@
+//! Helpers shared by the unit tests.
+
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+use rustix::{
+    fd::OwnedFd,
+    fs::{self, Mode, OFlags},
+};
+
+/// A file in the temp dir, named after the test process and `name`. It is removed on drop, so
+/// also when the test fails.
+#[derive(Debug)]
+pub(crate) struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub(crate) fn new(name: &str) -> Self {
+        Self {
+            path: std::env::temp_dir().join(format!("exmap-{}-{name}", std::process::id())),
+        }
+    }
+
+    /// Open the file read and write, creating it empty
+    pub(crate) fn create(&self) -> OwnedFd {
+        self.open(OFlags::TRUNC)
+    }
+
+    /// Open the file read and write with extra `flags`, creating it if needed
+    pub(crate) fn open(&self, flags: OFlags) -> OwnedFd {
+        fs::openat(
+            fs::cwd(),
+            &self.path,
+            OFlags::RDWR | OFlags::CREATE | OFlags::CLOEXEC | flags,
+            Mode::from_raw_mode(0o600),
+        )
+        .unwrap()
+    }
+}
+
+impl Deref for TempFile {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempFile {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // Some tests never get to create it
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,318 @@
+//! Recording of the exmap actions issued through an interface, for replaying bug reports.
+//!
+//! An [`OwnedExmapFd`](crate::OwnedExmapFd) given a [`Tracer`] appends a [`Record`] for every
+//! alloc, free, read and write ioctl, with the iovs asked for and the results exmap gave back.
+//! [`TraceReader`] reads them back.
+//!
+//! The file is a magic followed by records of LEB128 varints, zigzag encoded where signed:
+//!
+//! ```text
+//! start | duration | interface | opcode | flags | iov count | ret | (page, len)... | (res, pages)...
+//! ```
+//!
+//! Times are nanoseconds since the tracer was created. `ret` is the number of failed iovs, or
+//! a negated errno when the ioctl failed, in which case there are no per iov results.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    sync::{Mutex, PoisonError},
+    time::Instant,
+};
+
+use crate::sys;
+
+const MAGIC: [u8; 8] = *b"EXMAPTR1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Read,
+    Alloc,
+    Free,
+    Write,
+}
+
+impl Opcode {
+    pub(crate) fn raw(self) -> sys::exmap_opcode {
+        match self {
+            Self::Read => sys::EXMAP_OP_READ,
+            Self::Alloc => sys::EXMAP_OP_ALLOC,
+            Self::Free => sys::EXMAP_OP_FREE,
+            Self::Write => sys::EXMAP_OP_WRITE,
+        }
+    }
+
+    fn from_raw(raw: u64) -> Option<Self> {
+        [Self::Read, Self::Alloc, Self::Free, Self::Write]
+            .into_iter()
+            .find(|op| u64::from(op.raw()) == raw)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Alloc => "alloc",
+            Self::Free => "free",
+            Self::Write => "write",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Nanoseconds since the tracer was created
+    pub start: u64,
+    pub duration: u64,
+    pub interface: u16,
+    pub opcode: Opcode,
+    pub flags: u64,
+    /// `(page, len)` of every iov
+    pub iovs: Vec<(u64, u64)>,
+    /// Failed iovs, or the errno of a failed ioctl
+    pub ret: Result<u16, i32>,
+    /// `(res, pages)` of every iov, empty if the ioctl failed
+    pub results: Vec<(i32, i16)>,
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn put_signed(buf: &mut Vec<u8>, v: i64) {
+    put_varint(buf, ((v << 1) ^ (v >> 63)) as u64);
+}
+
+impl Record {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_varint(buf, self.start);
+        put_varint(buf, self.duration);
+        put_varint(buf, self.interface.into());
+        put_varint(buf, self.opcode.raw().into());
+        put_varint(buf, self.flags);
+        put_varint(buf, self.iovs.len() as u64);
+        match self.ret {
+            Ok(failed) => put_signed(buf, failed.into()),
+            Err(errno) => put_signed(buf, -i64::from(errno)),
+        }
+        for &(page, len) in &self.iovs {
+            put_varint(buf, page);
+            put_varint(buf, len);
+        }
+        for &(res, pages) in &self.results {
+            put_signed(buf, res.into());
+            put_signed(buf, pages.into());
+        }
+    }
+}
+
+/// Appends records to a trace file. Safe to share between threads.
+#[derive(Debug)]
+pub struct Tracer {
+    out: Mutex<TraceOut>,
+    epoch: Instant,
+}
+
+#[derive(Debug)]
+struct TraceOut {
+    file: BufWriter<File>,
+    buf: Vec<u8>,
+    /// First write error, reported by [`Tracer::finish`] since actions cannot fail on it
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&MAGIC)?;
+        Ok(Self {
+            out: Mutex::new(TraceOut {
+                file,
+                buf: Vec::new(),
+                error: None,
+            }),
+            epoch: Instant::now(),
+        })
+    }
+
+    /// Nanoseconds since the tracer was created
+    pub(crate) fn now(&self) -> u64 {
+        self.epoch.elapsed().as_nanos() as u64
+    }
+
+    pub fn record(&self, record: &Record) {
+        let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        let TraceOut { file, buf, error } = &mut *out;
+        if error.is_some() {
+            return;
+        }
+        buf.clear();
+        record.encode(buf);
+        if let Err(e) = file.write_all(buf) {
+            *error = Some(e);
+        }
+    }
+
+    /// Flush the trace, failing if any record could not be written
+    pub fn finish(&self) -> io::Result<()> {
+        let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(e) = out.error.take() {
+            return Err(e);
+        }
+        out.file.flush()
+    }
+}
+
+/// Reads the records of a trace file in the order they were written
+pub struct TraceReader<R> {
+    input: R,
+}
+
+impl TraceReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not an exmap trace"));
+        }
+        Ok(Self { input })
+    }
+
+    /// `None` at a clean end of the input
+    fn varint(&mut self) -> io::Result<Option<u64>> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0];
+            if self.input.read(&mut byte)? == 0 {
+                return match shift {
+                    0 => Ok(None),
+                    _ => Err(ErrorKind::UnexpectedEof.into()),
+                };
+            }
+            v |= u64::from(byte[0] & 0x7F) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(Some(v));
+            }
+        }
+        Err(io::Error::new(ErrorKind::InvalidData, "varint too long"))
+    }
+
+    fn field(&mut self) -> io::Result<u64> {
+        self.varint()?
+            .ok_or_else(|| ErrorKind::UnexpectedEof.into())
+    }
+
+    fn signed(&mut self) -> io::Result<i64> {
+        let v = self.field()?;
+        Ok((v >> 1) as i64 ^ -((v & 1) as i64))
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let invalid = |what| io::Error::new(ErrorKind::InvalidData, what);
+
+        let Some(start) = self.varint()? else {
+            return Ok(None);
+        };
+        let duration = self.field()?;
+        let interface = u16::try_from(self.field()?).map_err(|_| invalid("interface"))?;
+        let opcode = Opcode::from_raw(self.field()?).ok_or_else(|| invalid("opcode"))?;
+        let flags = self.field()?;
+        let count = self.field()?;
+        if count > crate::InterfaceWrapper::<crate::InterfaceIov>::MAX_COUNT as u64 {
+            return Err(invalid("iov count"));
+        }
+        let ret = match self.signed()? {
+            failed @ 0.. => Ok(u16::try_from(failed).map_err(|_| invalid("ret"))?),
+            errno => Err(i32::try_from(-errno).map_err(|_| invalid("errno"))?),
+        };
+
+        let iovs = (0..count)
+            .map(|_| Ok((self.field()?, self.field()?)))
+            .collect::<io::Result<_>>()?;
+        let results = match ret {
+            Ok(_) => (0..count)
+                .map(|_| Ok((self.signed()? as i32, self.signed()? as i16)))
+                .collect::<io::Result<_>>()?,
+            Err(_) => Vec::new(),
+        };
+
+        Ok(Some(Record {
+            start,
+            duration,
+            interface,
+            opcode,
+            flags,
+            iovs,
+            ret,
+            results,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempFile;
+
+    #[test]
+    fn roundtrip() {
+        let path = TempFile::new("trace");
+        let records = [
+            Record {
+                start: 5,
+                duration: 1_000_000,
+                interface: 3,
+                opcode: Opcode::Alloc,
+                flags: 0,
+                iovs: vec![(0, 8), (1 << 40, 4095)],
+                ret: Ok(1),
+                results: vec![(0, 8), (-12, 0)],
+            },
+            Record {
+                start: 9,
+                duration: 2,
+                interface: 0,
+                opcode: Opcode::Write,
+                flags: 1,
+                iovs: vec![(7, 1)],
+                ret: Err(22),
+                results: Vec::new(),
+            },
+        ];
+
+        let tracer = Tracer::create(&path).unwrap();
+        records.iter().for_each(|r| tracer.record(r));
+        tracer.finish().unwrap();
+
+        let read = TraceReader::open(&path)
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read, records);
+
+        // A torn last record is an error, not the end
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.pop();
+        let mut reader = TraceReader::new(&bytes[..]).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+    }
+}
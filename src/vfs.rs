@@ -0,0 +1,595 @@
+//! SQLite VFS serving main database files from a [`BufferManager`].
+//!
+//! Every main database file gets its own exmap and buffer manager, and `xRead`/`xWrite` copy
+//! between SQLite's buffers and the buffer manager pages. Pages are read from and written back
+//! to the database file with `pread`/`pwrite`. Journals, temp files and everything that is not
+//! file I/O go to the default VFS.
+//!
+//! Locking is a no-op and there is no shared memory, so only one connection may use a
+//! database at a time and WAL mode is not available.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    mem::{self, ManuallyDrop},
+    ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use libsqlite3_sys as ffi;
+use rustix::{
+    fd::OwnedFd,
+    fs::{self, Mode, OFlags},
+    io,
+};
+
+use crate::{
+    bm::{BufferManager, PageStore},
+    fileio, OwnedExmapFd,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VfsOptions {
+    /// Largest database size in pages, the size of each exmap
+    pub max_pages: u64,
+    /// Pages of physical memory per database
+    pub buffer_size: usize,
+    pub interfaces: u16,
+}
+
+/// Register the VFS as `name`, optionally as the default. Returns the SQLite error code on
+/// failure. The VFS stays registered for the life of the process.
+pub fn register<const P: usize>(
+    name: &str,
+    options: VfsOptions,
+    make_default: bool,
+) -> Result<(), c_int> {
+    let name = CString::new(name).map_err(|_| ffi::SQLITE_MISUSE)?;
+    let fallback = unsafe { ffi::sqlite3_vfs_find(ptr::null()) };
+    if fallback.is_null() {
+        return Err(ffi::SQLITE_ERROR);
+    }
+
+    let state = Box::leak(Box::new(State {
+        options,
+        fallback,
+        name,
+        methods: io_methods::<P>(),
+    }));
+    let fallback = unsafe { &*fallback };
+    let vfs = Box::leak(Box::new(ffi::sqlite3_vfs {
+        iVersion: 1,
+        szOsFile: fallback.szOsFile.max(mem::size_of::<File<P>>() as c_int),
+        mxPathname: fallback.mxPathname,
+        pNext: ptr::null_mut(),
+        zName: state.name.as_ptr(),
+        pAppData: (state as *mut State).cast(),
+        xOpen: Some(x_open::<P>),
+        xDelete: Some(x_delete),
+        xAccess: Some(x_access),
+        xFullPathname: Some(x_full_pathname),
+        // These ignore the VFS argument in the unix VFS
+        xDlOpen: fallback.xDlOpen,
+        xDlError: fallback.xDlError,
+        xDlSym: fallback.xDlSym,
+        xDlClose: fallback.xDlClose,
+        xRandomness: Some(x_randomness),
+        xSleep: Some(x_sleep),
+        xCurrentTime: Some(x_current_time),
+        xGetLastError: Some(x_get_last_error),
+        xCurrentTimeInt64: None,
+        xSetSystemCall: None,
+        xGetSystemCall: None,
+        xNextSystemCall: None,
+    }));
+
+    match unsafe { ffi::sqlite3_vfs_register(vfs, make_default.into()) } {
+        ffi::SQLITE_OK => Ok(()),
+        rc => Err(rc),
+    }
+}
+
+struct State {
+    options: VfsOptions,
+    /// The VFS everything but main database I/O goes to
+    fallback: *mut ffi::sqlite3_vfs,
+    name: CString,
+    methods: ffi::sqlite3_io_methods,
+}
+
+/// What SQLite allocates for an open file. Files of the fallback VFS use the same allocation.
+#[repr(C)]
+struct File<const P: usize> {
+    base: ffi::sqlite3_file,
+    db: *mut Db<P>,
+}
+
+/// Reads and writes back pages of the database file, never past its current size
+struct DbStore {
+    file: Arc<OwnedFd>,
+    size: Arc<AtomicU64>,
+}
+
+impl PageStore for DbStore {
+    fn read_page(&self, pid: u64, page: &mut [u8]) -> io::Result<()> {
+        let offset = pid * page.len() as u64;
+        let mut done = 0;
+        while done < page.len() {
+            match io::pread(&self.file, &mut page[done..], offset + done as u64)? {
+                // Past the end of the file
+                0 => break,
+                n => done += n,
+            }
+        }
+        page[done..].fill(0);
+        Ok(())
+    }
+
+    fn write_page(&self, pid: u64, page: &[u8]) -> io::Result<()> {
+        let offset = pid * page.len() as u64;
+        let size = self.size.load(Ordering::Acquire);
+        if offset >= size {
+            return Ok(());
+        }
+        let len = page.len().min((size - offset) as usize);
+        fileio::pwrite_all(&self.file, &page[..len], offset)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        fs::fdatasync(&self.file)
+    }
+}
+
+struct Db<const P: usize> {
+    bm: ManuallyDrop<BufferManager<'static, 'static, P>>,
+    /// Leaked so the buffer manager can borrow it, freed after the buffer manager on drop
+    exmap_fd: *mut OwnedExmapFd<P>,
+    file: Arc<OwnedFd>,
+    size: Arc<AtomicU64>,
+}
+
+impl<const P: usize> Db<P> {
+    fn open(path: &CStr, flags: c_int, options: &VfsOptions) -> io::Result<Self> {
+        let mut oflags = OFlags::CLOEXEC;
+        if flags & ffi::SQLITE_OPEN_READONLY != 0 {
+            oflags |= OFlags::RDONLY;
+        } else {
+            oflags |= OFlags::RDWR;
+        }
+        if flags & ffi::SQLITE_OPEN_CREATE != 0 {
+            oflags |= OFlags::CREATE;
+        }
+        let file = Arc::new(fs::openat(
+            fs::cwd(),
+            path,
+            oflags,
+            Mode::from_raw_mode(0o644),
+        )?);
+
+        let size = fs::fstat(&file)?.st_size as u64;
+        if size > options.max_pages * P as u64 {
+            return Err(io::Errno::FBIG);
+        }
+        let size = Arc::new(AtomicU64::new(size));
+
+        let exmap_fd = Box::into_raw(Box::new(OwnedExmapFd::<P>::open()?));
+        let bm = unsafe {
+            let exmap_fd: &'static OwnedExmapFd<P> = &*exmap_fd;
+            exmap_fd
+                .create(
+                    options.max_pages as usize * P,
+                    options.interfaces,
+                    options.buffer_size,
+                    None,
+                )
+                .and_then(|mem| {
+                    BufferManager::new(exmap_fd, mem, options.interfaces, options.buffer_size)
+                })
+        };
+        let bm = match bm {
+            Ok(bm) => bm.with_store(DbStore {
+                file: file.clone(),
+                size: size.clone(),
+            }),
+            Err(e) => {
+                drop(unsafe { Box::from_raw(exmap_fd) });
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            bm: ManuallyDrop::new(bm),
+            exmap_fd,
+            file,
+            size,
+        })
+    }
+
+    /// Copy out of the pages covering `buf.len()` bytes at `offset`
+    fn read(&self, mut offset: u64, buf: &mut [u8]) -> crate::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let (pid, at) = (offset / P as u64, (offset % P as u64) as usize);
+            let len = (P - at).min(buf.len() - done);
+            let page = self.bm.fix_s(pid)?;
+            buf[done..done + len].copy_from_slice(&page[at..at + len]);
+            done += len;
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&self, mut offset: u64, buf: &[u8]) -> crate::Result<()> {
+        let end = offset + buf.len() as u64;
+        if end > self.bm.page_count() * P as u64 {
+            return Err(io::Errno::NOSPC.into());
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            let (pid, at) = (offset / P as u64, (offset % P as u64) as usize);
+            let len = (P - at).min(buf.len() - done);
+            let mut page = self.bm.fix_x(pid)?;
+            page[at..at + len].copy_from_slice(&buf[done..done + len]);
+            done += len;
+            offset += len as u64;
+        }
+        self.size.fetch_max(end, Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Set the file size. Shrinking zeroes the rest of the last page and drops the cached pages
+    /// past it, so their old contents neither get written back nor reappear when the file grows
+    /// again.
+    fn truncate(&self, size: u64) -> crate::Result<()> {
+        let old = self.size.load(Ordering::Acquire);
+        if size < old {
+            let (pid, at) = (size / P as u64, (size % P as u64) as usize);
+            let mut first = pid;
+            if at != 0 {
+                self.bm.fix_x(pid)?[at..].fill(0);
+                first += 1;
+            }
+            let end = (old + P as u64 - 1) / P as u64;
+            if end > first {
+                self.bm.free_run(first, end - first)?;
+            }
+        }
+
+        self.size.store(size, Ordering::Release);
+        fs::ftruncate(&self.file, size)?;
+        Ok(())
+    }
+}
+
+impl<const P: usize> Drop for Db<P> {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = ManuallyDrop::take(&mut self.bm).close();
+            drop(Box::from_raw(self.exmap_fd));
+        }
+    }
+}
+
+fn io_methods<const P: usize>() -> ffi::sqlite3_io_methods {
+    ffi::sqlite3_io_methods {
+        iVersion: 1,
+        xClose: Some(x_close::<P>),
+        xRead: Some(x_read::<P>),
+        xWrite: Some(x_write::<P>),
+        xTruncate: Some(x_truncate::<P>),
+        xSync: Some(x_sync::<P>),
+        xFileSize: Some(x_file_size::<P>),
+        xLock: Some(x_lock),
+        xUnlock: Some(x_lock),
+        xCheckReservedLock: Some(x_check_reserved_lock),
+        xFileControl: Some(x_file_control),
+        xSectorSize: Some(x_sector_size::<P>),
+        xDeviceCharacteristics: Some(x_device_characteristics),
+        xShmMap: None,
+        xShmLock: None,
+        xShmBarrier: None,
+        xShmUnmap: None,
+        xFetch: None,
+        xUnfetch: None,
+    }
+}
+
+unsafe fn state<'s>(vfs: *mut ffi::sqlite3_vfs) -> &'s State {
+    unsafe { &*(*vfs).pAppData.cast::<State>() }
+}
+
+unsafe fn db<'d, const P: usize>(file: *mut ffi::sqlite3_file) -> &'d Db<P> {
+    unsafe { &*(*file.cast::<File<P>>()).db }
+}
+
+unsafe extern "C" fn x_open<const P: usize>(
+    vfs: *mut ffi::sqlite3_vfs,
+    name: ffi::sqlite3_filename,
+    file: *mut ffi::sqlite3_file,
+    flags: c_int,
+    out_flags: *mut c_int,
+) -> c_int {
+    let state = unsafe { state(vfs) };
+    if flags & ffi::SQLITE_OPEN_MAIN_DB == 0 || name.is_null() {
+        let fallback = state.fallback;
+        return unsafe { (*fallback).xOpen.unwrap()(fallback, name, file, flags, out_flags) };
+    }
+
+    let file = file.cast::<File<P>>();
+    match Db::<P>::open(unsafe { CStr::from_ptr(name) }, flags, &state.options) {
+        Ok(db) => unsafe {
+            (*file).db = Box::into_raw(Box::new(db));
+            (*file).base.pMethods = &state.methods;
+            if !out_flags.is_null() {
+                *out_flags = flags;
+            }
+            ffi::SQLITE_OK
+        },
+        Err(_) => {
+            // SQLite does not close a file whose open failed
+            unsafe { (*file).base.pMethods = ptr::null() };
+            ffi::SQLITE_CANTOPEN
+        }
+    }
+}
+
+unsafe extern "C" fn x_close<const P: usize>(file: *mut ffi::sqlite3_file) -> c_int {
+    let db = unsafe { Box::from_raw((*file.cast::<File<P>>()).db) };
+    match db.bm.flush() {
+        Ok(_) => ffi::SQLITE_OK,
+        Err(_) => ffi::SQLITE_IOERR_CLOSE,
+    }
+}
+
+unsafe extern "C" fn x_read<const P: usize>(
+    file: *mut ffi::sqlite3_file,
+    buf: *mut c_void,
+    amount: c_int,
+    offset: ffi::sqlite3_int64,
+) -> c_int {
+    let db = unsafe { db::<P>(file) };
+    let buf = unsafe { std::slice::from_raw_parts_mut(buf.cast::<u8>(), amount as usize) };
+    let offset = offset as u64;
+
+    let available = db
+        .size
+        .load(Ordering::Acquire)
+        .saturating_sub(offset)
+        .min(buf.len() as u64) as usize;
+    if db.read(offset, &mut buf[..available]).is_err() {
+        return ffi::SQLITE_IOERR_READ;
+    }
+    if available < buf.len() {
+        buf[available..].fill(0);
+        return ffi::SQLITE_IOERR_SHORT_READ;
+    }
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_write<const P: usize>(
+    file: *mut ffi::sqlite3_file,
+    buf: *const c_void,
+    amount: c_int,
+    offset: ffi::sqlite3_int64,
+) -> c_int {
+    let db = unsafe { db::<P>(file) };
+    let buf = unsafe { std::slice::from_raw_parts(buf.cast::<u8>(), amount as usize) };
+    match db.write(offset as u64, buf) {
+        Ok(()) => ffi::SQLITE_OK,
+        Err(crate::Error::Io(io::Errno::NOSPC)) => ffi::SQLITE_FULL,
+        Err(_) => ffi::SQLITE_IOERR_WRITE,
+    }
+}
+
+unsafe extern "C" fn x_truncate<const P: usize>(
+    file: *mut ffi::sqlite3_file,
+    size: ffi::sqlite3_int64,
+) -> c_int {
+    match unsafe { db::<P>(file) }.truncate(size as u64) {
+        Ok(()) => ffi::SQLITE_OK,
+        Err(_) => ffi::SQLITE_IOERR_TRUNCATE,
+    }
+}
+
+unsafe extern "C" fn x_sync<const P: usize>(file: *mut ffi::sqlite3_file, _flags: c_int) -> c_int {
+    match unsafe { db::<P>(file) }.bm.flush() {
+        Ok(_) => ffi::SQLITE_OK,
+        Err(_) => ffi::SQLITE_IOERR_FSYNC,
+    }
+}
+
+unsafe extern "C" fn x_file_size<const P: usize>(
+    file: *mut ffi::sqlite3_file,
+    size: *mut ffi::sqlite3_int64,
+) -> c_int {
+    unsafe { *size = db::<P>(file).size.load(Ordering::Acquire) as ffi::sqlite3_int64 };
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_lock(_file: *mut ffi::sqlite3_file, _level: c_int) -> c_int {
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_check_reserved_lock(
+    _file: *mut ffi::sqlite3_file,
+    out: *mut c_int,
+) -> c_int {
+    unsafe { *out = 0 };
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_file_control(
+    _file: *mut ffi::sqlite3_file,
+    _op: c_int,
+    _arg: *mut c_void,
+) -> c_int {
+    ffi::SQLITE_NOTFOUND
+}
+
+unsafe extern "C" fn x_sector_size<const P: usize>(_file: *mut ffi::sqlite3_file) -> c_int {
+    P as c_int
+}
+
+unsafe extern "C" fn x_device_characteristics(_file: *mut ffi::sqlite3_file) -> c_int {
+    0
+}
+
+unsafe extern "C" fn x_delete(
+    vfs: *mut ffi::sqlite3_vfs,
+    name: *const c_char,
+    sync_dir: c_int,
+) -> c_int {
+    let fallback = unsafe { state(vfs) }.fallback;
+    unsafe { (*fallback).xDelete.unwrap()(fallback, name, sync_dir) }
+}
+
+unsafe extern "C" fn x_access(
+    vfs: *mut ffi::sqlite3_vfs,
+    name: *const c_char,
+    flags: c_int,
+    out: *mut c_int,
+) -> c_int {
+    let fallback = unsafe { state(vfs) }.fallback;
+    unsafe { (*fallback).xAccess.unwrap()(fallback, name, flags, out) }
+}
+
+unsafe extern "C" fn x_full_pathname(
+    vfs: *mut ffi::sqlite3_vfs,
+    name: *const c_char,
+    len: c_int,
+    out: *mut c_char,
+) -> c_int {
+    let fallback = unsafe { state(vfs) }.fallback;
+    unsafe { (*fallback).xFullPathname.unwrap()(fallback, name, len, out) }
+}
+
+unsafe extern "C" fn x_randomness(
+    vfs: *mut ffi::sqlite3_vfs,
+    len: c_int,
+    out: *mut c_char,
+) -> c_int {
+    let fallback = unsafe { state(vfs) }.fallback;
+    unsafe { (*fallback).xRandomness.unwrap()(fallback, len, out) }
+}
+
+unsafe extern "C" fn x_sleep(vfs: *mut ffi::sqlite3_vfs, micros: c_int) -> c_int {
+    let fallback = unsafe { state(vfs) }.fallback;
+    unsafe { (*fallback).xSleep.unwrap()(fallback, micros) }
+}
+
+unsafe extern "C" fn x_current_time(vfs: *mut ffi::sqlite3_vfs, out: *mut f64) -> c_int {
+    let fallback = unsafe { state(vfs) }.fallback;
+    unsafe { (*fallback).xCurrentTime.unwrap()(fallback, out) }
+}
+
+unsafe extern "C" fn x_get_last_error(
+    vfs: *mut ffi::sqlite3_vfs,
+    len: c_int,
+    out: *mut c_char,
+) -> c_int {
+    let fallback = unsafe { state(vfs) }.fallback;
+    unsafe { (*fallback).xGetLastError.unwrap()(fallback, len, out) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempFile;
+
+    fn exec(db: *mut ffi::sqlite3, sql: &str) {
+        let sql = CString::new(sql).unwrap();
+        let rc =
+            unsafe { ffi::sqlite3_exec(db, sql.as_ptr(), None, ptr::null_mut(), ptr::null_mut()) };
+        assert_eq!(rc, ffi::SQLITE_OK, "{:?}", unsafe {
+            CStr::from_ptr(ffi::sqlite3_errmsg(db))
+        });
+    }
+
+    fn query_i64(db: *mut ffi::sqlite3, sql: &str) -> i64 {
+        let sql = CString::new(sql).unwrap();
+        let mut stmt = ptr::null_mut();
+        unsafe {
+            let rc = ffi::sqlite3_prepare_v2(db, sql.as_ptr(), -1, &mut stmt, ptr::null_mut());
+            assert_eq!(rc, ffi::SQLITE_OK);
+            assert_eq!(ffi::sqlite3_step(stmt), ffi::SQLITE_ROW);
+            let v = ffi::sqlite3_column_int64(stmt, 0);
+            ffi::sqlite3_finalize(stmt);
+            v
+        }
+    }
+
+    fn open(path: &CStr, vfs: Option<&CStr>) -> *mut ffi::sqlite3 {
+        let mut db = ptr::null_mut();
+        let rc = unsafe {
+            ffi::sqlite3_open_v2(
+                path.as_ptr(),
+                &mut db,
+                ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
+                vfs.map_or(ptr::null(), CStr::as_ptr),
+            )
+        };
+        assert_eq!(rc, ffi::SQLITE_OK);
+        db
+    }
+
+    #[test]
+    fn readable_by_default_vfs() {
+        let options = VfsOptions {
+            max_pages: 4096,
+            buffer_size: 64,
+            interfaces: 2,
+        };
+        register::<4096>("exmap-test", options, false).unwrap();
+
+        let path = TempFile::new("vfs.db");
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        let vfs = CString::new("exmap-test").unwrap();
+
+        // Far more pages than the buffer, so pages get evicted and read back
+        let db = open(&c_path, Some(&vfs));
+        exec(db, "CREATE TABLE t (id INTEGER PRIMARY KEY, data BLOB)");
+        exec(
+            db,
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 5000) \
+             INSERT INTO t SELECT i, randomblob(300) FROM n",
+        );
+        assert_eq!(query_i64(db, "SELECT count(*) FROM t"), 5000);
+        assert_eq!(unsafe { ffi::sqlite3_close(db) }, ffi::SQLITE_OK);
+
+        let db = open(&c_path, None);
+        assert_eq!(query_i64(db, "SELECT count(*) FROM t"), 5000);
+        exec(db, "PRAGMA integrity_check");
+        assert_eq!(unsafe { ffi::sqlite3_close(db) }, ffi::SQLITE_OK);
+    }
+
+    #[test]
+    fn truncate_drops_cached_pages() {
+        let options = VfsOptions {
+            max_pages: 16,
+            buffer_size: 8,
+            interfaces: 1,
+        };
+        let path = TempFile::new("vfs-trunc");
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let db = Db::<4096>::open(&c_path, ffi::SQLITE_OPEN_CREATE, &options).unwrap();
+
+        db.write(0, &[0xAA; 3 * 4096]).unwrap();
+        db.truncate(100).unwrap();
+        assert_eq!(fs::fstat(&*db.file).unwrap().st_size, 100);
+
+        // Growing again must not bring back the old contents, cached or written back
+        db.write(3 * 4096, &[1]).unwrap();
+        let mut buf = vec![0xFF; 3 * 4096];
+        db.read(0, &mut buf).unwrap();
+        assert!(buf[..100].iter().all(|&b| b == 0xAA));
+        assert!(buf[100..].iter().all(|&b| b == 0));
+
+        db.bm.flush().unwrap();
+        fileio::pread_exact(&*db.file, &mut buf, 0).unwrap();
+        assert!(buf[100..].iter().all(|&b| b == 0));
+    }
+}
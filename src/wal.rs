@@ -0,0 +1,485 @@
+//! Redo log for changes made through exclusive guards.
+//!
+//! With a [`Wal`] attached to the [`BufferManager`](crate::bm::BufferManager), dropping an
+//! exclusive guard that changed its page appends a record with the changed byte range. Every
+//! page carries the LSN of the last record applied to it in the reserved bytes of the
+//! [`checksum`] header.
+//!
+//! [`Wal::commit`] appends a commit record and makes the log durable. Pages with changes newer
+//! than the last commit are never written back, so the backing file only ever holds committed
+//! changes and the log is all that is needed to bring it up to date. [`Wal::open`] replays the
+//! committed records over the backing file and drops the uncommitted tail. Replayed pages get
+//! a fresh checksum, so recovery works whether or not the pages are checksummed.
+//!
+//! ```text
+//! log:    | magic | start lsn | record | record | ...
+//! record: | lsn | pid | offset | len | kind | crc32c | data ...
+//! ```
+
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+};
+
+use rustix::{
+    fd::{AsFd, OwnedFd},
+    fs, io,
+};
+
+use crate::{checksum, fileio, Result};
+
+const MAGIC: [u8; 8] = *b"EXMAPWAL";
+const LOG_HEADER_SIZE: u64 = 16;
+const RECORD_HEADER_SIZE: usize = 32;
+
+const KIND_UPDATE: u32 = 0;
+const KIND_COMMIT: u32 = 1;
+
+/// Where the page LSN lives in the page header
+pub const PAGE_LSN: Range<usize> = 8..16;
+
+pub fn page_lsn(page: &[u8]) -> u64 {
+    u64::from_le_bytes(page[PAGE_LSN].try_into().unwrap())
+}
+
+pub fn set_page_lsn(page: &mut [u8], lsn: u64) {
+    page[PAGE_LSN].copy_from_slice(&lsn.to_le_bytes());
+}
+
+/// What recovery did when the log was opened
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Recovery {
+    /// Committed records applied to the backing file
+    pub applied: usize,
+    /// Committed records the backing file already had
+    pub skipped: usize,
+    /// Records after the last commit
+    pub discarded: usize,
+}
+
+struct Record {
+    lsn: u64,
+    pid: u64,
+    offset: usize,
+    kind: u32,
+    data: Vec<u8>,
+}
+
+impl Record {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend_from_slice(&self.lsn.to_le_bytes());
+        buf.extend_from_slice(&self.pid.to_le_bytes());
+        buf.extend_from_slice(&(self.offset as u32).to_le_bytes());
+        buf.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.kind.to_le_bytes());
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&self.data);
+
+        let crc = checksum::crc32c(&buf[start..]);
+        buf[start + 28..start + 32].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Decode the record at the start of `buf`, `None` at a torn or garbage tail
+    fn decode(buf: &[u8]) -> Option<(Self, usize)> {
+        let header = buf.get(..RECORD_HEADER_SIZE)?;
+        let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        let len = u32_at(20) as usize;
+        let stored = u32_at(28);
+
+        let total = RECORD_HEADER_SIZE + len;
+        let mut record = buf.get(..total)?.to_vec();
+        record[28..32].fill(0);
+        if checksum::crc32c(&record) != stored {
+            return None;
+        }
+
+        Some((
+            Self {
+                lsn: u64::from_le_bytes(header[0..8].try_into().unwrap()),
+                pid: u64::from_le_bytes(header[8..16].try_into().unwrap()),
+                offset: u32_at(16) as usize,
+                kind: u32_at(24),
+                data: record.split_off(RECORD_HEADER_SIZE),
+            },
+            total,
+        ))
+    }
+}
+
+struct State {
+    /// Encoded records not yet written to the log
+    buf: Vec<u8>,
+    next_lsn: u64,
+    /// Log offset the buffer goes to
+    end: u64,
+}
+
+pub struct Wal {
+    file: OwnedFd,
+    state: Mutex<State>,
+    committed: AtomicU64,
+}
+
+impl Wal {
+    /// Open the log, replaying its committed records over the pages of size `P` in
+    /// `backing`, then start a fresh log after them. An empty file becomes a new log.
+    pub fn open<const P: usize>(log: OwnedFd, backing: impl AsFd) -> Result<(Self, Recovery)> {
+        let len = fs::fstat(&log)?.st_size as u64;
+        let mut contents = vec![0; len as usize];
+        fileio::pread_exact(&log, &mut contents, 0)?;
+
+        let mut recovery = Recovery::default();
+        let mut next_lsn = 1;
+        if !contents.is_empty() {
+            if contents.len() < LOG_HEADER_SIZE as usize || contents[..8] != MAGIC {
+                return Err(io::Errno::INVAL.into());
+            }
+            next_lsn = u64::from_le_bytes(contents[8..16].try_into().unwrap());
+
+            let mut records = Vec::new();
+            let mut at = LOG_HEADER_SIZE as usize;
+            while let Some((record, len)) = Record::decode(&contents[at..]) {
+                next_lsn = next_lsn.max(record.lsn + 1);
+                records.push(record);
+                at += len;
+            }
+
+            let committed = records
+                .iter()
+                .rposition(|r| r.kind == KIND_COMMIT)
+                .map_or(0, |i| i + 1);
+            recovery.discarded = records.len() - committed;
+            records.truncate(committed);
+            Self::replay::<P>(&records, backing.as_fd(), &mut recovery)?;
+        }
+
+        let wal = Self {
+            file: log,
+            state: Mutex::new(State {
+                buf: Vec::new(),
+                next_lsn,
+                end: LOG_HEADER_SIZE,
+            }),
+            committed: AtomicU64::new(next_lsn - 1),
+        };
+        wal.reset()?;
+        Ok((wal, recovery))
+    }
+
+    fn replay<const P: usize>(
+        records: &[Record],
+        backing: rustix::fd::BorrowedFd<'_>,
+        recovery: &mut Recovery,
+    ) -> io::Result<()> {
+        let mut pages: HashMap<u64, Vec<u8>> = HashMap::new();
+        for record in records.iter().filter(|r| r.kind == KIND_UPDATE) {
+            if record.offset + record.data.len() > P {
+                return Err(io::Errno::INVAL);
+            }
+
+            let page = match pages.entry(record.pid) {
+                std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                std::collections::hash_map::Entry::Vacant(e) => {
+                    // Pages allocated past the end of the file may never have been written back
+                    let mut page = vec![0; P];
+                    fileio::pread_or_zero(backing, &mut page, record.pid * P as u64)?;
+                    e.insert(page)
+                }
+            };
+
+            if page_lsn(page) >= record.lsn {
+                recovery.skipped += 1;
+                continue;
+            }
+            page[record.offset..record.offset + record.data.len()].copy_from_slice(&record.data);
+            set_page_lsn(page, record.lsn);
+            recovery.applied += 1;
+        }
+
+        for (pid, page) in &mut pages {
+            checksum::stamp(page);
+            fileio::pwrite_all(backing, page, *pid * P as u64)?;
+        }
+        fs::fdatasync(backing)
+    }
+
+    /// LSN of the last durable commit. Pages with a newer page LSN may not be written back.
+    pub fn committed(&self) -> u64 {
+        self.committed.load(Ordering::Acquire)
+    }
+
+    /// Buffer a redo record setting `data` at byte `offset` of page `pid`, returning its LSN
+    pub fn log_update(&self, pid: u64, offset: usize, data: &[u8]) -> u64 {
+        self.append(KIND_UPDATE, pid, offset, data).0
+    }
+
+    fn append(
+        &self,
+        kind: u32,
+        pid: u64,
+        offset: usize,
+        data: &[u8],
+    ) -> (u64, MutexGuard<'_, State>) {
+        let mut state = self.lock();
+        let lsn = state.next_lsn;
+        state.next_lsn += 1;
+        Record {
+            lsn,
+            pid,
+            offset,
+            kind,
+            data: data.to_vec(),
+        }
+        .encode(&mut state.buf);
+        (lsn, state)
+    }
+
+    /// Make every change logged so far durable as one unit. Returns the commit LSN.
+    pub fn commit(&self) -> io::Result<u64> {
+        let (lsn, mut state) = self.append(KIND_COMMIT, 0, 0, &[]);
+        self.write_out(&mut state)?;
+        fs::fdatasync(&self.file)?;
+        self.committed.fetch_max(lsn, Ordering::AcqRel);
+        Ok(lsn)
+    }
+
+    /// Write the buffered records without committing them
+    pub fn flush(&self) -> io::Result<()> {
+        let mut state = self.lock();
+        self.write_out(&mut state)?;
+        fs::fdatasync(&self.file)
+    }
+
+    fn write_out(&self, state: &mut State) -> io::Result<()> {
+        fileio::pwrite_all(&self.file, &state.buf, state.end)?;
+        state.end += state.buf.len() as u64;
+        state.buf.clear();
+        Ok(())
+    }
+
+    /// Start over with an empty log. Only valid once every committed change is in the
+    /// backing file and nothing uncommitted was logged.
+    pub fn reset(&self) -> io::Result<()> {
+        let mut state = self.lock();
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&state.next_lsn.to_le_bytes());
+
+        fs::ftruncate(&self.file, 0)?;
+        fileio::pwrite_all(&self.file, &header, 0)?;
+        fs::fdatasync(&self.file)?;
+        state.buf.clear();
+        state.end = LOG_HEADER_SIZE;
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Byte range of `after` that differs from `before`, ignoring the page header
+pub(crate) fn changed(before: &[u8], after: &[u8]) -> Option<Range<usize>> {
+    let start = checksum::HEADER_SIZE
+        + before[checksum::HEADER_SIZE..]
+            .iter()
+            .zip(&after[checksum::HEADER_SIZE..])
+            .position(|(a, b)| a != b)?;
+    let end = before
+        .iter()
+        .zip(after)
+        .rposition(|(a, b)| a != b)
+        .expect("a byte differs")
+        + 1;
+    Some(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        path::{Path, PathBuf},
+        process::{Command, Stdio},
+    };
+
+    use rustix::fs::{openat, Mode, OFlags};
+
+    use super::*;
+    use crate::{bm::BufferManager, testutil::TempFile, OwnedExmapFd};
+
+    /// Where a crash child finds the log and the backing file of its parent
+    const CHILD_LOG: &str = "EXMAP_WAL_CHILD_LOG";
+    const CHILD_DATA: &str = "EXMAP_WAL_CHILD_DATA";
+
+    fn open(path: &Path, flags: OFlags) -> OwnedFd {
+        openat(
+            fs::cwd(),
+            path,
+            OFlags::RDWR | OFlags::CREATE | flags,
+            Mode::from_raw_mode(0o600),
+        )
+        .unwrap()
+    }
+
+    fn child_paths() -> Option<(PathBuf, PathBuf)> {
+        let log = std::env::var_os(CHILD_LOG)?;
+        let data = std::env::var_os(CHILD_DATA)?;
+        Some((log.into(), data.into()))
+    }
+
+    #[test]
+    fn changed_range() {
+        let before = [0u8; 64];
+        let mut after = before;
+        assert_eq!(changed(&before, &after), None);
+        after[PAGE_LSN.start] = 1;
+        assert_eq!(changed(&before, &after), None);
+        after[20] = 1;
+        after[30] = 1;
+        assert_eq!(changed(&before, &after), Some(20..31));
+    }
+
+    /// Run the ignored test `name` in a child process and kill it once it printed "ready"
+    fn crash(name: &str, log: &Path, data: &Path) {
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", name, "--ignored", "--nocapture"])
+            .env(CHILD_LOG, log)
+            .env(CHILD_DATA, data)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        // The harness prints the test name on the same line
+        assert!(stdout.lines().any(|line| line.unwrap().ends_with("ready")));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    fn wait_for_kill() -> ! {
+        println!("ready");
+        loop {
+            std::thread::park();
+        }
+    }
+
+    /// Run by `replays_after_kill` in a child process that is killed once it is ready
+    #[test]
+    #[ignore]
+    fn crash_child() {
+        let Some((log, data)) = child_paths() else {
+            return;
+        };
+        let (wal, _) =
+            Wal::open::<64>(open(&log, OFlags::empty()), open(&data, OFlags::empty())).unwrap();
+
+        wal.log_update(1, 16, b"committed");
+        // Past the end of the backing file
+        wal.log_update(5, 40, b"also");
+        wal.commit().unwrap();
+        wal.log_update(1, 16, b"torn away");
+        wal.flush().unwrap();
+        wait_for_kill();
+    }
+
+    #[test]
+    fn replays_after_kill() {
+        let (log, data) = (TempFile::new("wal.log"), TempFile::new("wal.data"));
+        let backing = open(&data, OFlags::TRUNC);
+        fs::ftruncate(&backing, 4 * 64).unwrap();
+        drop(open(&log, OFlags::TRUNC));
+
+        crash("wal::tests::crash_child", &log, &data);
+
+        let (wal, recovery) = Wal::open::<64>(open(&log, OFlags::empty()), &backing).unwrap();
+        assert_eq!(
+            recovery,
+            Recovery {
+                applied: 2,
+                skipped: 0,
+                discarded: 1
+            }
+        );
+
+        let mut page = [0; 64];
+        fileio::pread_exact(&backing, &mut page, 64).unwrap();
+        assert_eq!(&page[16..25], b"committed");
+        assert_eq!(page_lsn(&page), 1);
+        assert!(checksum::verify(&page).is_ok());
+        fileio::pread_exact(&backing, &mut page, 5 * 64).unwrap();
+        assert_eq!(&page[40..44], b"also");
+        assert!(checksum::verify(&page).is_ok());
+
+        // Replaying again is a no-op and LSNs keep growing
+        drop(wal);
+        let (wal, recovery) = Wal::open::<64>(open(&log, OFlags::empty()), &backing).unwrap();
+        assert_eq!(recovery, Recovery::default());
+        assert!(wal.log_update(1, 16, b"x") > 3);
+    }
+
+    /// Run by `buffer_manager_recovers` like `crash_child`, through a checksumming buffer manager
+    #[test]
+    #[ignore]
+    fn bm_crash_child() {
+        let Some((log, data)) = child_paths() else {
+            return;
+        };
+        let backing = open(&data, OFlags::empty());
+        let (wal, _) = Wal::open::<4096>(open(&log, OFlags::empty()), &backing).unwrap();
+        let exmap_fd = OwnedExmapFd::<4096>::open().unwrap();
+        let mem = exmap_fd
+            .create(16 * 4096, 1, 8, Some(backing.as_fd()))
+            .unwrap();
+        let bm = unsafe { BufferManager::new(&exmap_fd, mem, 1, 8) }
+            .unwrap()
+            .with_checksums()
+            .with_wal(wal);
+
+        for pid in 0..2 {
+            let mut page = bm.allocate_page().unwrap();
+            assert_eq!(page.pid(), pid);
+            page[100..109].copy_from_slice(b"written 1");
+        }
+        bm.wal().unwrap().commit().unwrap();
+        assert_eq!(bm.flush().unwrap(), 2);
+
+        // Committed but only in the log, one of them past the end of the backing file
+        bm.fix_x(0).unwrap()[100..109].copy_from_slice(b"written 2");
+        bm.allocate_page().unwrap()[200..203].copy_from_slice(b"new");
+        bm.wal().unwrap().commit().unwrap();
+
+        bm.fix_x(1).unwrap()[100..109].copy_from_slice(b"torn away");
+        bm.wal().unwrap().flush().unwrap();
+        wait_for_kill();
+    }
+
+    #[test]
+    fn buffer_manager_recovers() {
+        let (log, data) = (TempFile::new("wal-bm.log"), TempFile::new("wal-bm.data"));
+        let backing = open(&data, OFlags::TRUNC);
+        fs::ftruncate(&backing, 2 * 4096).unwrap();
+        drop(open(&log, OFlags::TRUNC));
+
+        crash("wal::tests::bm_crash_child", &log, &data);
+
+        let (wal, recovery) = Wal::open::<4096>(open(&log, OFlags::empty()), &backing).unwrap();
+        assert_eq!(recovery.discarded, 1);
+
+        // Faulting the pages in verifies their checksums
+        let exmap_fd = OwnedExmapFd::<4096>::open().unwrap();
+        let mem = exmap_fd
+            .create(16 * 4096, 1, 8, Some(backing.as_fd()))
+            .unwrap();
+        let bm = unsafe { BufferManager::new(&exmap_fd, mem, 1, 8) }
+            .unwrap()
+            .with_checksums()
+            .with_wal(wal);
+        assert_eq!(&bm.fix_s(0).unwrap()[100..109], b"written 2");
+        assert_eq!(&bm.fix_s(1).unwrap()[100..109], b"written 1");
+        assert_eq!(&bm.fix_s(2).unwrap()[200..203], b"new");
+        bm.close().unwrap();
+    }
+}
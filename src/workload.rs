@@ -0,0 +1,335 @@
+//! YCSB style workloads: operation mixes over a key space with a request distribution.
+//!
+//! A [`Workload`] says how often each [`Operation`] runs and how keys are picked. A
+//! [`Generator`] turns it into a seeded stream of [`Request`]s over record indices, which
+//! [`key`] maps to the keys actually stored, so inserts do not all land at the end of a tree.
+//! The generators are deterministic for a seed; running the requests is up to the caller.
+
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// SplitMix64
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// A seed from the clock for runs that did not ask for one
+    pub fn clock_seed() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..n`
+    pub fn below(&mut self, n: u64) -> u64 {
+        ((u128::from(self.next_u64()) * u128::from(n)) >> 64) as u64
+    }
+
+    /// Uniform in `[0, 1)`
+    pub fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Zipfian ranks in `0..n`, rank 0 the most popular, after Gray et al. "Quickly generating
+/// billion-record synthetic databases" as used by YCSB
+#[derive(Debug, Clone)]
+pub struct Zipf {
+    n: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipf {
+    /// `theta` in `(0, 1)`, YCSB uses 0.99. Setup is linear in `n`.
+    pub fn new(n: u64, theta: f64) -> Self {
+        assert!(n > 0 && theta > 0.0 && theta < 1.0);
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zetan = zeta(n);
+        Self {
+            n,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta(2) / zetan),
+        }
+    }
+
+    pub fn sample(&self, rng: &mut Rng) -> u64 {
+        let u = rng.unit();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(self.n - 1);
+        }
+        let rank = self.n as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha);
+        (rank as u64).min(self.n - 1)
+    }
+}
+
+/// How keys are picked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Distribution {
+    Uniform,
+    /// Popular records are spread over the key space
+    Zipfian,
+    /// Recently inserted records are the most popular
+    Latest,
+}
+
+impl FromStr for Distribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(Self::Uniform),
+            "zipfian" => Ok(Self::Zipfian),
+            "latest" => Ok(Self::Latest),
+            _ => Err("expected uniform, zipfian or latest".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Read,
+    Update,
+    Insert,
+    Scan,
+    ReadModifyWrite,
+}
+
+impl Operation {
+    pub const ALL: [Self; 5] = [
+        Self::Read,
+        Self::Update,
+        Self::Insert,
+        Self::Scan,
+        Self::ReadModifyWrite,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Update => "update",
+            Self::Insert => "insert",
+            Self::Scan => "scan",
+            Self::ReadModifyWrite => "read_modify_write",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workload {
+    pub name: &'static str,
+    /// Share of each operation, summing to 1
+    pub mix: Vec<(Operation, f64)>,
+    pub distribution: Distribution,
+    /// Scans cover a uniform number of records in `1..=max_scan`
+    pub max_scan: u64,
+}
+
+impl Workload {
+    /// The core YCSB workloads `A` to `F`
+    pub fn ycsb(name: &str) -> Option<Self> {
+        use Operation::*;
+
+        let (name, mix, distribution) = match name {
+            "a" | "A" => ("A", vec![(Read, 0.5), (Update, 0.5)], Distribution::Zipfian),
+            "b" | "B" => (
+                "B",
+                vec![(Read, 0.95), (Update, 0.05)],
+                Distribution::Zipfian,
+            ),
+            "c" | "C" => ("C", vec![(Read, 1.0)], Distribution::Zipfian),
+            "d" | "D" => (
+                "D",
+                vec![(Read, 0.95), (Insert, 0.05)],
+                Distribution::Latest,
+            ),
+            "e" | "E" => (
+                "E",
+                vec![(Scan, 0.95), (Insert, 0.05)],
+                Distribution::Zipfian,
+            ),
+            "f" | "F" => (
+                "F",
+                vec![(Read, 0.5), (ReadModifyWrite, 0.5)],
+                Distribution::Zipfian,
+            ),
+            _ => return None,
+        };
+        Some(Self {
+            name,
+            mix,
+            distribution,
+            max_scan: 100,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    Read(u64),
+    Update(u64),
+    /// Insert the next record
+    Insert(u64),
+    /// Up to `len` records in key order starting at the key of the record
+    Scan {
+        record: u64,
+        len: u64,
+    },
+    ReadModifyWrite(u64),
+}
+
+impl Request {
+    pub fn operation(&self) -> Operation {
+        match self {
+            Self::Read(_) => Operation::Read,
+            Self::Update(_) => Operation::Update,
+            Self::Insert(_) => Operation::Insert,
+            Self::Scan { .. } => Operation::Scan,
+            Self::ReadModifyWrite(_) => Operation::ReadModifyWrite,
+        }
+    }
+}
+
+/// Stored key of a record. A bijection, so distinct records never collide.
+pub fn key(record: u64) -> [u8; 8] {
+    let mut z = record;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    (z ^ (z >> 31)).to_be_bytes()
+}
+
+/// Requests of a workload for one thread. Threads share the record count so inserts get
+/// distinct records; a request may pick a record whose insert another thread has not finished.
+pub struct Generator<'r> {
+    workload: Workload,
+    records: &'r AtomicU64,
+    /// Over the records loaded initially, YCSB does not grow it with inserts either
+    zipf: Zipf,
+    rng: Rng,
+}
+
+impl<'r> Generator<'r> {
+    /// `records` holds the number of loaded records, which must be positive
+    pub fn new(workload: Workload, records: &'r AtomicU64, theta: f64, seed: u64) -> Self {
+        let loaded = records.load(Ordering::Relaxed);
+        Self {
+            zipf: Zipf::new(loaded, theta),
+            workload,
+            records,
+            rng: Rng::new(seed),
+        }
+    }
+
+    fn record(&mut self) -> u64 {
+        let count = self.records.load(Ordering::Relaxed);
+        match self.workload.distribution {
+            Distribution::Uniform => self.rng.below(count),
+            Distribution::Zipfian => {
+                let rank = self.zipf.sample(&mut self.rng);
+                u64::from_be_bytes(key(rank)) % count
+            }
+            Distribution::Latest => count - 1 - self.zipf.sample(&mut self.rng).min(count - 1),
+        }
+    }
+
+    pub fn next_request(&mut self) -> Request {
+        let mut pick = self.rng.unit();
+        let mut operation = self.workload.mix[0].0;
+        for &(op, share) in &self.workload.mix {
+            operation = op;
+            if pick < share {
+                break;
+            }
+            pick -= share;
+        }
+
+        match operation {
+            Operation::Read => Request::Read(self.record()),
+            Operation::Update => Request::Update(self.record()),
+            Operation::Insert => Request::Insert(self.records.fetch_add(1, Ordering::Relaxed)),
+            Operation::Scan => Request::Scan {
+                record: self.record(),
+                len: 1 + self.rng.below(self.workload.max_scan),
+            },
+            Operation::ReadModifyWrite => Request::ReadModifyWrite(self.record()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zipf_is_skewed() {
+        let zipf = Zipf::new(1000, 0.99);
+        let mut rng = Rng::new(7);
+        let mut counts = vec![0u32; 1000];
+        for _ in 0..100_000 {
+            counts[zipf.sample(&mut rng) as usize] += 1;
+        }
+        assert!(counts[0] > counts[1] && counts[1] > counts[10]);
+        // The top 1% of ranks get a large share
+        let top: u32 = counts[..10].iter().sum();
+        assert!(top > 30_000, "{top}");
+
+        let mut rng = Rng::new(7);
+        assert!((0..1000).all(|_| rng.below(10) < 10));
+    }
+
+    #[test]
+    fn ycsb_mixes() {
+        for name in ["A", "b", "C", "d", "E", "f"] {
+            let workload = Workload::ycsb(name).unwrap();
+            let total: f64 = workload.mix.iter().map(|&(_, share)| share).sum();
+            assert!((total - 1.0).abs() < 1e-9, "{name}");
+        }
+        assert_eq!(Workload::ycsb("G"), None);
+
+        let records = AtomicU64::new(1000);
+        let mut gen = Generator::new(Workload::ycsb("D").unwrap(), &records, 0.99, 3);
+        let mut inserts = 0;
+        for _ in 0..10_000 {
+            match gen.next_request() {
+                Request::Read(r) => assert!(r < records.load(Ordering::Relaxed)),
+                Request::Insert(r) => {
+                    assert_eq!(r, 1000 + inserts);
+                    inserts += 1;
+                }
+                r => panic!("{r:?} in workload D"),
+            }
+        }
+        assert!((300..700).contains(&inserts), "{inserts}");
+
+        // Same seed, same requests
+        let records = AtomicU64::new(1000);
+        let mut a = Generator::new(Workload::ycsb("E").unwrap(), &records, 0.99, 9);
+        let requests: Vec<_> = (0..100).map(|_| a.next_request()).collect();
+        let records = AtomicU64::new(1000);
+        let mut b = Generator::new(Workload::ycsb("E").unwrap(), &records, 0.99, 9);
+        assert!(requests.iter().all(|&r| r == b.next_request()));
+    }
+}